@@ -0,0 +1,424 @@
+//! How keys and values are written out, and read back in by `akv load` and
+//! `akv import`
+
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::*;
+use serde_json::{Map, Value};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// An encoding for printing keys and values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The bytes exactly as they are stored
+    Raw,
+    /// The bytes as text, with anything that isn't valid UTF-8 replaced
+    Utf8,
+    Hex,
+    Base64,
+    /// A JSON object per entry. Keys and values that are valid UTF-8 are
+    /// written as strings under `key` and `value`, and anything else in
+    /// base64 under `key_base64` and `value_base64`, so nothing is lost.
+    Json,
+    /// A row per entry of comma separated values: the key, the value and
+    /// how they are encoded. That is `utf8`, or `base64` if either of them
+    /// isn't valid UTF-8, in which case both are written in base64. Fields
+    /// holding commas, quotes or line breaks are quoted.
+    Csv,
+    /// Like CSV, but separated by tabs. TSV has no quoting, so keys and
+    /// values holding tabs or line breaks are written in base64 as well.
+    Tsv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "utf8" => Ok(Format::Utf8),
+            "hex" => Ok(Format::Hex),
+            "base64" => Ok(Format::Base64),
+            "json" | "jsonl" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+impl Format {
+    /// Writes a single value. Every format but raw ends it with a newline.
+    pub fn write_value<W: Write>(self, out: &mut W, value: &ByteStr) -> io::Result<()> {
+        match self {
+            Format::Raw => out.write_all(value),
+            Format::Json => {
+                let mut object = Map::new();
+                insert_bytes(&mut object, "value", value);
+                writeln!(out, "{}", Value::Object(object))
+            },
+            Format::Csv | Format::Tsv => writeln!(out, "{}", self.row(&[value])),
+            _ => writeln!(out, "{}", self.encode(value)),
+        }
+    }
+
+    /// Writes a key and its value on one line, separated by a tab, or as a
+    /// CSV or TSV row
+    pub fn write_pair<W: Write>(self, out: &mut W, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        match self {
+            Format::Raw => {
+                out.write_all(key)?;
+                out.write_all(b"\t")?;
+                out.write_all(value)?;
+                out.write_all(b"\n")
+            },
+            Format::Json => writeln!(out, "{}", pair_to_json(key, value)),
+            Format::Csv | Format::Tsv => writeln!(out, "{}", self.row(&[key, value])),
+            _ => writeln!(out, "{}\t{}", self.encode(key), self.encode(value)),
+        }
+    }
+
+    /// The row naming the columns of a CSV or TSV export
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Format::Csv => Some("key,value,encoding"),
+            Format::Tsv => Some("key\tvalue\tencoding"),
+            _ => None,
+        }
+    }
+
+    /// Reads back the keys and values written with `write_pair` in JSON,
+    /// CSV or TSV one at a time, skipping a CSV or TSV header. An entry that
+    /// can't be read fails with an `InvalidData` error naming its line.
+    pub fn pairs<R: BufRead>(self, input: R) -> Pairs<R> {
+        Pairs { format: self, input, line: 0, first: true }
+    }
+
+    /// The fields as a CSV or TSV row, followed by their encoding
+    fn row(self, fields: &[&ByteStr]) -> String {
+        let separator = match self {
+            Format::Tsv => "\t",
+            _ => ",",
+        };
+
+        let text: Option<Vec<&str>> = fields.iter().map(|field| std::str::from_utf8(field).ok()).collect();
+        let text = text.filter(|text| {
+            self != Format::Tsv || !text.iter().any(|field| field.contains(['\t', '\n', '\r']))
+        });
+
+        let (mut cells, encoding): (Vec<String>, _) = match text {
+            Some(text) if self == Format::Csv => (text.into_iter().map(csv_quote).collect(), "utf8"),
+            Some(text) => (text.into_iter().map(String::from).collect(), "utf8"),
+            None => (fields.iter().map(|field| BASE64_STANDARD.encode(field)).collect(), "base64"),
+        };
+        cells.push(encoding.to_string());
+        cells.join(separator)
+    }
+
+    fn encode(self, bytes: &ByteStr) -> String {
+        match self {
+            Format::Raw | Format::Utf8 | Format::Json | Format::Csv | Format::Tsv => {
+                String::from_utf8_lossy(bytes).into_owned()
+            },
+            Format::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            Format::Base64 => BASE64_STANDARD.encode(bytes),
+        }
+    }
+}
+
+/// The keys and values read by `Format::pairs`. Only the line, or for CSV
+/// the lines, of the entry being read are held in memory.
+pub struct Pairs<R> {
+    format: Format,
+    input: R,
+    /// How many lines have been read
+    line: usize,
+    /// Whether no row has been read yet, so the next may be a header
+    first: bool,
+}
+
+impl<R: BufRead> Pairs<R> {
+    /// Reads the next line, keeping its line break. `None` at the end.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut text = String::new();
+        match self.input.read_line(&mut text)? {
+            0 => Ok(None),
+            _ => {
+                self.line += 1;
+                Ok(Some(text))
+            },
+        }
+    }
+
+    /// Reads the fields of the next CSV or TSV row, along with the line it
+    /// starts on. A quoted CSV field can run over several lines.
+    fn next_row(&mut self) -> io::Result<Option<(usize, Vec<String>)>> {
+        loop {
+            let mut text = match self.next_line()? {
+                Some(text) => text,
+                None => return Ok(None),
+            };
+            let start = self.line;
+
+            let fields = match self.format {
+                Format::Csv => loop {
+                    match csv_rows(&text) {
+                        Ok(rows) => break rows.into_iter().next().map(|(_, fields)| fields),
+                        Err(_) => match self.next_line()? {
+                            Some(more) => text.push_str(&more),
+                            None => return Err(bad_data(format!("line {}: a quoted field is never closed", start))),
+                        },
+                    }
+                },
+                _ => Some(without_line_break(&text))
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.split('\t').map(String::from).collect()),
+            };
+
+            if let Some(fields) = fields {
+                return Ok(Some((start, fields)));
+            }
+        }
+    }
+
+    fn next_pair(&mut self) -> io::Result<Option<(ByteString, ByteString)>> {
+        if self.format == Format::Json {
+            while let Some(text) = self.next_line()? {
+                if text.trim().is_empty() {
+                    continue;
+                }
+
+                let pair = serde_json::from_str(&text).ok().and_then(|json| pair_from_json(&json));
+                let message = || bad_data(format!("line {}: expected a json key and value", self.line));
+                return pair.map(Some).ok_or_else(message);
+            }
+            return Ok(None);
+        }
+
+        let is_header = |fields: &[String]| fields.get(2).is_some_and(|encoding| encoding == "encoding");
+        while let Some((line, fields)) = self.next_row()? {
+            if std::mem::take(&mut self.first) && is_header(&fields) {
+                continue;
+            }
+            return pair_from_row(line, &fields).map(Some).map_err(bad_data);
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> Iterator for Pairs<R> {
+    type Item = io::Result<(ByteString, ByteString)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair().transpose()
+    }
+}
+
+fn bad_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A line without the `\n` or `\r\n` it ends with
+fn without_line_break(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// A key and value as a JSON object, in the form `Format::Json` uses
+pub fn pair_to_json(key: &ByteStr, value: &ByteStr) -> Value {
+    let mut object = Map::new();
+    insert_bytes(&mut object, "key", key);
+    insert_bytes(&mut object, "value", value);
+    Value::Object(object)
+}
+
+/// Reads back a key and value written by `pair_to_json`
+pub fn pair_from_json(object: &Value) -> Option<(ByteString, ByteString)> {
+    Some((get_bytes(object, "key")?, get_bytes(object, "value")?))
+}
+
+/// Quotes a CSV field if it has to be, doubling any quotes inside it
+fn csv_quote(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Splits CSV into rows of fields, along with the line each row starts on.
+/// A quoted field can run over several lines, and blank lines are skipped.
+fn csv_rows(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut rows = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+
+        loop {
+            match chars.next() {
+                None if quoted => return Err(format!("line {}: a quoted field is never closed", start)),
+                None => break,
+                Some('"') if quoted => match chars.peek() {
+                    Some('"') => {
+                        chars.next();
+                        field.push('"');
+                    },
+                    _ => quoted = false,
+                },
+                Some('"') if field.is_empty() => quoted = true,
+                Some(c) if quoted => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                },
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {},
+                Some('\n') => {
+                    line += 1;
+                    break;
+                },
+                Some(c) => field.push(c),
+            }
+        }
+
+        fields.push(field);
+        if fields != [""] {
+            rows.push((start, fields));
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Reads a key and value from a CSV or TSV row
+fn pair_from_row(line: usize, fields: &[String]) -> Result<(ByteString, ByteString), String> {
+    let decode = |field: &String| {
+        BASE64_STANDARD.decode(field).map_err(|_| format!("line {}: bad base64", line))
+    };
+
+    match fields {
+        [key, value] => Ok((key.as_bytes().to_vec(), value.as_bytes().to_vec())),
+        [key, value, encoding] if encoding == "utf8" => Ok((key.as_bytes().to_vec(), value.as_bytes().to_vec())),
+        [key, value, encoding] if encoding == "base64" => Ok((decode(key)?, decode(value)?)),
+        [_, _, encoding] => Err(format!("line {}: unknown encoding '{}'", line, encoding)),
+        _ => Err(format!("line {}: expected a key, a value and an encoding", line)),
+    }
+}
+
+/// Writes a time as a UTC timestamp, e.g. `2024-03-01T12:30:00.250Z`
+pub fn format_time(time: SystemTime) -> String {
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+    let (days, millis) = (millis / 86_400_000, millis % 86_400_000);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000,
+    )
+}
+
+/// Turns a count of days since 1970-01-01 into a (year, month, day) date,
+/// using Howard Hinnant's algorithm for the proleptic Gregorian calendar
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01, so leap days fall at the end of each year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn insert_bytes(object: &mut Map<String, Value>, name: &str, bytes: &ByteStr) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => object.insert(name.to_string(), Value::from(text)),
+        Err(_) => object.insert(format!("{}_base64", name), Value::from(BASE64_STANDARD.encode(bytes))),
+    };
+}
+
+fn get_bytes(object: &Value, name: &str) -> Option<ByteString> {
+    if let Some(text) = object.get(name) {
+        return Some(text.as_str()?.as_bytes().to_vec());
+    }
+
+    let encoded = object.get(format!("{}_base64", name))?.as_str()?;
+    BASE64_STANDARD.decode(encoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(format: Format, key: &ByteStr, value: &ByteStr) -> String {
+        let mut out = Vec::new();
+        format.write_pair(&mut out, key, value).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn read(format: Format, text: &str) -> Result<Vec<(ByteString, ByteString)>, String> {
+        format.pairs(text.as_bytes()).collect::<io::Result<_>>().map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn pairs_are_written_in_each_format() {
+        assert_eq!(pair(Format::Utf8, b"apple", b"r\xffd"), "apple\tr\u{fffd}d\n");
+        assert_eq!(pair(Format::Hex, b"ab", b"\x00\xff"), "6162\t00ff\n");
+        assert_eq!(pair(Format::Base64, b"a", b"bc"), "YQ==\tYmM=\n");
+        assert_eq!(pair(Format::Json, b"apple", b"\xff"), "{\"key\":\"apple\",\"value_base64\":\"/w==\"}\n");
+    }
+
+    #[test]
+    fn json_pairs_read_back_losslessly() {
+        for (key, value) in [(b"apple".as_ref(), b"red".as_ref()), (b"\xfe", b"\x00\xff")] {
+            let json = pair_to_json(key, value);
+            assert_eq!(pair_from_json(&json), Some((key.to_vec(), value.to_vec())));
+        }
+        assert_eq!(pair_from_json(&serde_json::json!({ "key": "apple" })), None);
+    }
+
+    #[test]
+    fn csv_and_tsv_rows_read_back_losslessly() {
+        assert_eq!(pair(Format::Csv, b"apple", b"red, \"ripe\""), "apple,\"red, \"\"ripe\"\"\",utf8\n");
+        assert_eq!(pair(Format::Tsv, b"a\tb", b"c"), "YQli\tYw==\tbase64\n");
+        assert_eq!(pair(Format::Tsv, b"a,b", b"\"c\""), "a,b\t\"c\"\tutf8\n");
+        assert_eq!(pair(Format::Csv, b"\xff", b"x"), "/w==,eA==,base64\n");
+
+        let pairs = [(b"apple".as_ref(), b"red".as_ref()), (b"line\nbreak", b"a,b\t\"c\""), (b"\xfe", b"\x00\xff"), (b"", b"")];
+        for format in [Format::Csv, Format::Tsv, Format::Json] {
+            let mut out = format.header().map(|header| format!("{}\n", header)).unwrap_or_default().into_bytes();
+            for (key, value) in pairs {
+                format.write_pair(&mut out, key, value).unwrap();
+            }
+
+            let read = read(format, std::str::from_utf8(&out).unwrap()).unwrap();
+            let expected: Vec<_> = pairs.iter().map(|(key, value)| (key.to_vec(), value.to_vec())).collect();
+            assert_eq!(read, expected, "{:?}", format);
+        }
+
+        assert_eq!(read(Format::Csv, "a,b\r\nc,d,utf8\r\n").unwrap(), vec![(b"a".to_vec(), b"b".to_vec()), (b"c".to_vec(), b"d".to_vec())]);
+        assert_eq!(read(Format::Csv, "a,b\n\"c,d\n").unwrap_err(), "line 2: a quoted field is never closed");
+        assert_eq!(read(Format::Tsv, "a\tb\tutf8\nc\n").unwrap_err(), "line 2: expected a key, a value and an encoding");
+        assert_eq!(read(Format::Json, "\n{\"key\": \"a\"}\n").unwrap_err(), "line 2: expected a json key and value");
+    }
+}
@@ -0,0 +1,570 @@
+//! The command line interface to ActionKV stores.
+//! This file compiles to the `akv` binary, which opens a store (a single
+//! file, or a directory of segment files) and runs one command against it.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use libactionkv::{ActionKV, Compression, EncryptionKey, Options, Stats};
+
+mod format;
+mod shell;
+
+use format::Format;
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv.exe get FILE KEY [--format FORMAT]
+    akv.exe put FILE KEY [VALUE | --file PATH] [--ttl SECONDS]
+    akv.exe delete FILE KEY
+    akv.exe scan FILE [PREFIX] [--format FORMAT]
+    akv.exe stats FILE [--format json]
+    akv.exe verify FILE
+    akv.exe compact FILE
+    akv.exe dump FILE [--format FORMAT] [--file PATH]
+    akv.exe load FILE [--file PATH]
+    akv.exe export FILE [--format jsonl|csv|tsv] [--file PATH]
+    akv.exe import FILE [--format jsonl|csv|tsv] [--file PATH]
+    akv.exe shell FILE [--batch SCRIPT] [--format FORMAT]
+    akv.exe upgrade FILE
+    akv.exe rekey FILE --new-key-file PATH [--key-file PATH]
+    akv.exe backup FILE BACKUP
+    akv.exe restore FILE BACKUP
+
+Options:
+    --format FORMAT       raw, utf8, hex, base64, json (or jsonl), csv or tsv
+    --file PATH           read the value (or import) from here, or write the dump (or export)
+                          here, instead of stdin (stdout)
+    --ttl SECONDS         expire the key after this many seconds
+    --batch SCRIPT        run the shell commands in SCRIPT instead of prompting
+    --persistent-index    keep the index in its own file, FILE.idx
+    --compression ALGO    compress large values with lz4 or zstd (or none)
+    --key-file PATH       encrypt the store with the key in PATH, as 64 hex digits
+    --new-key-file PATH   the key for rekey to encrypt the store with from now on
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv get FILE KEY [--format FORMAT]
+    akv put FILE KEY [VALUE | --file PATH] [--ttl SECONDS]
+    akv delete FILE KEY
+    akv scan FILE [PREFIX] [--format FORMAT]
+    akv stats FILE [--format json]
+    akv verify FILE
+    akv compact FILE
+    akv dump FILE [--format FORMAT] [--file PATH]
+    akv load FILE [--file PATH]
+    akv export FILE [--format jsonl|csv|tsv] [--file PATH]
+    akv import FILE [--format jsonl|csv|tsv] [--file PATH]
+    akv shell FILE [--batch SCRIPT] [--format FORMAT]
+    akv upgrade FILE
+    akv rekey FILE --new-key-file PATH [--key-file PATH]
+    akv backup FILE BACKUP
+    akv restore FILE BACKUP
+
+Options:
+    --format FORMAT       raw, utf8, hex, base64, json (or jsonl), csv or tsv
+    --file PATH           read the value (or import) from here, or write the dump (or export)
+                          here, instead of stdin (stdout)
+    --ttl SECONDS         expire the key after this many seconds
+    --batch SCRIPT        run the shell commands in SCRIPT instead of prompting
+    --persistent-index    keep the index in its own file, FILE.idx
+    --compression ALGO    compress large values with lz4 or zstd (or none)
+    --key-file PATH       encrypt the store with the key in PATH, as 64 hex digits
+    --new-key-file PATH   the key for rekey to encrypt the store with from now on
+";
+
+/// Why a command failed, which decides the exit code
+#[derive(Debug)]
+enum Failure {
+    /// The key asked for isn't in the store (exit code 1)
+    NotFound,
+    /// The command line didn't make sense (exit code 2)
+    Usage(String),
+    /// The store holds bad records, or the input to `load` or `import` is
+    /// malformed (exit code 3)
+    Data(String),
+    /// Reading or writing a file failed (exit code 4)
+    Io(io::Error),
+    /// Another process has the store open for writing, or for reading when
+    /// this command would write (exit code 5)
+    Locked(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::NotFound => 1,
+            Failure::Usage(_) => 2,
+            Failure::Data(_) => 3,
+            Failure::Io(_) => 4,
+            Failure::Locked(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::NotFound => write!(f, "key not found"),
+            Failure::Usage(message) => write!(f, "{}\n{}", message, USAGE),
+            Failure::Data(message) => write!(f, "{}", message),
+            Failure::Io(err) => write!(f, "{}", err),
+            Failure::Locked(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+impl From<libactionkv::Error> for Failure {
+    fn from(err: libactionkv::Error) -> Self {
+        match err {
+            libactionkv::Error::Io(err) => Failure::Io(err),
+            err @ libactionkv::Error::Locked { .. } => Failure::Locked(err.to_string()),
+            err => Failure::Data(err.to_string()),
+        }
+    }
+}
+
+/// The parsed command line
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    command: String,
+    path: PathBuf,
+    /// The arguments after FILE, other than options
+    positional: Vec<String>,
+    format: Option<Format>,
+    file: Option<PathBuf>,
+    ttl: Option<Duration>,
+    batch: Option<PathBuf>,
+    persistent_index: bool,
+    compression: Compression,
+    key_file: Option<PathBuf>,
+    new_key_file: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, Failure> {
+        let mut parsed = Args::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next().ok_or_else(|| Failure::Usage(format!("{} needs a value", name)))
+            };
+
+            match arg.as_str() {
+                "--format" => parsed.format = Some(value("--format")?.parse().map_err(Failure::Usage)?),
+                "--file" => parsed.file = Some(PathBuf::from(value("--file")?)),
+                "--ttl" => {
+                    let secs = value("--ttl")?
+                        .parse()
+                        .map_err(|_| Failure::Usage("--ttl takes a number of seconds".to_string()))?;
+                    parsed.ttl = Some(Duration::from_secs(secs));
+                },
+                "--batch" => parsed.batch = Some(PathBuf::from(value("--batch")?)),
+                "--persistent-index" => parsed.persistent_index = true,
+                "--compression" => {
+                    parsed.compression = match value("--compression")?.as_str() {
+                        "none" => Compression::None,
+                        "lz4" => Compression::Lz4,
+                        "zstd" => Compression::Zstd,
+                        other => return Err(Failure::Usage(format!("unknown compression '{}'", other))),
+                    };
+                },
+                "--key-file" => parsed.key_file = Some(PathBuf::from(value("--key-file")?)),
+                "--new-key-file" => parsed.new_key_file = Some(PathBuf::from(value("--new-key-file")?)),
+                _ if arg.starts_with("--") => return Err(Failure::Usage(format!("unknown option {}", arg))),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        parsed.command = positional.next().ok_or_else(|| Failure::Usage("no command given".to_string()))?;
+        parsed.path = positional.next().map(PathBuf::from).ok_or_else(|| Failure::Usage("no FILE given".to_string()))?;
+        parsed.positional = positional.collect();
+
+        Ok(parsed)
+    }
+
+    /// The positional arguments after FILE, checking that there are
+    /// between `min` and `max` of them
+    fn positional(&self, min: usize, max: usize) -> Result<&[String], Failure> {
+        match self.positional.len() {
+            n if n < min => Err(Failure::Usage(format!("{} needs more arguments", self.command))),
+            n if n > max => Err(Failure::Usage(format!("too many arguments for {}", self.command))),
+            _ => Ok(&self.positional),
+        }
+    }
+
+    fn format_or(&self, default: Format) -> Format {
+        self.format.unwrap_or(default)
+    }
+}
+
+fn main() -> ExitCode {
+    let result = Args::parse(std::env::args().skip(1)).and_then(|args| run(&args));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // Output piped into something like `head` that stopped reading
+        Err(Failure::Io(err)) if err.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("akv: {}", failure);
+            ExitCode::from(failure.exit_code())
+        },
+    }
+}
+
+/// Opens the store at `args.path`, which is a directory store if the path
+/// is a directory. Commands that only read open it read-only, so they can
+/// run alongside each other.
+fn open(args: &Args) -> Result<ActionKV, Failure> {
+    let read_only = matches!(args.command.as_str(), "get" | "scan" | "stats" | "verify" | "dump" | "export" | "backup");
+    let options = Options {
+        persistent_index: args.persistent_index,
+        compression: args.compression,
+        read_only,
+        encryption_key: args.key_file.as_deref().map(read_key).transpose()?,
+        ..Options::default()
+    };
+    let store = match args.path.is_dir() {
+        true    => ActionKV::open_dir(&args.path, options),
+        false   => ActionKV::open_with(&args.path, options),
+    }?;
+    Ok(store)
+}
+
+fn run(args: &Args) -> Result<(), Failure> {
+    // A store that needs upgrading can't be opened until it has been
+    if args.command == "upgrade" {
+        args.positional(0, 0)?;
+        match libactionkv::upgrade(&args.path)? {
+            0 => println!("already up to date"),
+            1 => println!("upgraded 1 segment"),
+            n => println!("upgraded {} segments", n),
+        }
+        return Ok(());
+    }
+
+    // Restoring replaces the store, so it is never opened
+    if args.command == "restore" {
+        let backup = &args.positional(1, 1)?[0];
+        libactionkv::restore(Path::new(backup), &args.path)?;
+        return Ok(());
+    }
+
+    let mut store = open(args)?;
+
+    // Verification has to happen before loading, which would stop at the
+    // first bad record
+    if args.command == "verify" {
+        args.positional(0, 0)?;
+        let bad_records = store.verify()?;
+        for err in &bad_records {
+            println!("{}", err);
+        }
+
+        return match bad_records.len() {
+            0 => Ok(()),
+            n => Err(Failure::Data(format!("{} bad records found", n))),
+        };
+    }
+
+    // A backup is a copy of the files, which doesn't need the index
+    if args.command == "backup" {
+        let backup = &args.positional(1, 1)?[0];
+        store.backup_to(Path::new(backup))?;
+        return Ok(());
+    }
+
+    store.load()?;
+
+    // The shell writes to the terminal itself, as it goes
+    if args.command == "shell" {
+        args.positional(0, 0)?;
+        shell::run(&mut store, args.format_or(Format::Utf8), args.batch.as_deref())?;
+        store.close()?;
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    match args.command.as_str() {
+        "get" => {
+            let key = &args.positional(1, 1)?[0];
+            let value = store.get(key.as_bytes())?.ok_or(Failure::NotFound)?;
+            args.format_or(Format::Raw).write_value(&mut out, &value)?;
+        },
+
+        "put" => {
+            let positional = args.positional(1, 2)?;
+            let key = positional[0].as_bytes();
+            let value = match (positional.get(1), &args.file) {
+                (Some(_), Some(_)) => return Err(Failure::Usage("give either VALUE or --file".to_string())),
+                (Some(value), None) => value.as_bytes().to_vec(),
+                (None, Some(path)) => std::fs::read(path)?,
+                (None, None) => {
+                    let mut value = Vec::new();
+                    io::stdin().lock().read_to_end(&mut value)?;
+                    value
+                },
+            };
+
+            match args.ttl {
+                Some(ttl) => store.insert_with_ttl(key, &value, ttl)?,
+                None => store.insert(key, &value)?,
+            }
+        },
+
+        "delete" => {
+            let key = args.positional(1, 1)?[0].as_bytes();
+            if !store.contains_key(key) {
+                return Err(Failure::NotFound);
+            }
+            store.delete(key)?;
+        },
+
+        "scan" => {
+            let prefix = args.positional(0, 1)?.first().map(String::as_bytes).unwrap_or_default();
+            let format = args.format_or(Format::Utf8);
+            for kv in store.scan_prefix(prefix) {
+                let (key, value) = kv?;
+                format.write_pair(&mut out, &key, &value)?;
+            }
+        },
+
+        "stats" => {
+            args.positional(0, 0)?;
+            write_stats(&mut out, &store.stats()?, args.format)?;
+        },
+
+        "compact" => {
+            args.positional(0, 0)?;
+            store.compact()?;
+        },
+
+        // Export is a dump in a format that import can read back
+        "dump" | "export" => {
+            args.positional(0, 0)?;
+            let format = match args.command.as_str() {
+                "export" => interchange_format(args)?,
+                _ => args.format_or(Format::Json),
+            };
+            match &args.file {
+                Some(path) => dump(&mut store, format, BufWriter::new(File::create(path)?))?,
+                None => dump(&mut store, format, &mut out)?,
+            }
+        },
+
+        // Load is an import of a JSON dump, unless --format says otherwise
+        "load" | "import" => {
+            args.positional(0, 0)?;
+            let format = interchange_format(args)?;
+            match &args.file {
+                Some(path) => import(&mut store, format, BufReader::new(File::open(path)?))?,
+                None => import(&mut store, format, io::stdin().lock())?,
+            };
+        },
+
+        "rekey" => {
+            args.positional(0, 0)?;
+            let path = args.new_key_file.as_deref()
+                .ok_or_else(|| Failure::Usage("rekey needs --new-key-file".to_string()))?;
+            store.rekey(read_key(path)?)?;
+        },
+
+        command => return Err(Failure::Usage(format!("unknown command '{}'", command))),
+    }
+
+    out.flush()?;
+    drop(out);
+
+    // Closing keeps the hint file up to date, so the next run loads quickly
+    store.close()?;
+    Ok(())
+}
+
+/// The format export writes and import reads, which is JSON Lines unless
+/// `--format` says otherwise
+fn interchange_format(args: &Args) -> Result<Format, Failure> {
+    match args.format_or(Format::Json) {
+        format @ (Format::Json | Format::Csv | Format::Tsv) => Ok(format),
+        _ => Err(Failure::Usage(format!("{} only works with jsonl, csv or tsv", args.command))),
+    }
+}
+
+/// Reads an encryption key from a file holding it as 64 hex digits, such as
+/// one made with `openssl rand -hex 32`
+fn read_key(path: &Path) -> Result<EncryptionKey, Failure> {
+    let text = std::fs::read_to_string(path)?;
+    let digits = text.trim().as_bytes();
+
+    let mut key = [0u8; 32];
+    let bad_key = || Failure::Usage(format!("{} doesn't hold a key of 64 hex digits", path.display()));
+    if digits.len() != key.len() * 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return Err(bad_key());
+    }
+    for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| bad_key())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| bad_key())?;
+    }
+
+    Ok(EncryptionKey::new(key))
+}
+
+/// Prints a store's stats, one per line, or as JSON
+fn write_stats<W: Write>(out: &mut W, stats: &Stats, format: Option<Format>) -> Result<(), Failure> {
+    match format {
+        None => {
+            writeln!(out, "keys:       {}", stats.keys)?;
+            writeln!(out, "segments:   {}", stats.segments)?;
+            writeln!(out, "disk bytes: {}", stats.disk_bytes)?;
+            writeln!(out, "value bytes: {} ({} stored)", stats.value_bytes, stats.stored_value_bytes)?;
+            writeln!(out, "compression ratio: {:.2}", stats.compression_ratio)?;
+            writeln!(out, "records:    {} ({} dead)", stats.records, stats.dead_records)?;
+            writeln!(out, "live bytes: {}", stats.live_bytes)?;
+            writeln!(
+                out,
+                "dead bytes: {} ({:.1}% fragmented, freed by compacting)",
+                stats.dead_bytes,
+                stats.fragmentation * 100.0,
+            )?;
+            writeln!(out, "overhead bytes: {}", stats.overhead_bytes)?;
+            writeln!(out, "largest key:   {} bytes", stats.largest_key)?;
+            writeln!(out, "largest value: {} bytes", stats.largest_value)?;
+
+            if !stats.value_sizes.is_empty() {
+                writeln!(out, "value sizes:")?;
+                let most = stats.value_sizes.iter().map(|bucket| bucket.values).max().unwrap_or(0);
+                for bucket in &stats.value_sizes {
+                    // Scale the bars so that the fullest bucket's is 40 long
+                    let bar = "#".repeat((bucket.values * 40).div_ceil(most.max(1)));
+                    writeln!(out, "{}", format!("  <= {:>9} {:>8} {}", size(bucket.up_to), bucket.values, bar).trim_end())?;
+                }
+            }
+        },
+        Some(Format::Json) => writeln!(out, "{}", serde_json::json!(stats))?,
+        Some(_) => return Err(Failure::Usage("stats can only be printed as json".to_string())),
+    }
+    Ok(())
+}
+
+/// A number of bytes in the biggest unit it is a whole number of
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024 && size.is_multiple_of(1024) && unit + 1 < UNITS.len() {
+        size /= 1024;
+        unit += 1;
+    }
+    format!("{} {}", size, UNITS[unit])
+}
+
+/// Writes every live key and value in `format`, after a header naming the
+/// columns if it has one
+fn dump<W: Write>(store: &mut ActionKV, format: Format, mut out: W) -> Result<(), Failure> {
+    if let Some(header) = format.header() {
+        writeln!(out, "{}", header)?;
+    }
+    for kv in store.scan(..) {
+        let (key, value) = kv?;
+        format.write_pair(&mut out, &key, &value)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Imports every key and value written by `dump` in `format`, reading them
+/// as they are written. An entry that can't be read stops the import, and
+/// those before it are kept.
+fn import<R: BufRead>(store: &mut ActionKV, format: Format, input: R) -> Result<usize, Failure> {
+    let mut failure = None;
+    let pairs = format.pairs(input).map_while(|pair| match pair {
+        Ok(pair) => Some(pair),
+        Err(err) => {
+            failure = Some(err);
+            None
+        },
+    });
+    let imported = store.import(pairs)?;
+
+    match failure {
+        Some(err) if err.kind() == io::ErrorKind::InvalidData => Err(Failure::Data(err.to_string())),
+        Some(err) => Err(Failure::Io(err)),
+        None => Ok(imported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, Failure> {
+        Args::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn options_can_go_anywhere() {
+        let args = parse("get --format hex store.akv apple").unwrap();
+        assert_eq!(args.command, "get");
+        assert_eq!(args.path, Path::new("store.akv"));
+        assert_eq!(args.positional, vec!["apple".to_string()]);
+        assert_eq!(args.format, Some(Format::Hex));
+
+        let args = parse("put store.akv apple --ttl 5 --file value.bin").unwrap();
+        assert_eq!(args.ttl, Some(Duration::from_secs(5)));
+        assert_eq!(args.file, Some(PathBuf::from("value.bin")));
+    }
+
+    #[test]
+    fn bad_command_lines_are_usage_errors() {
+        for args in ["", "get", "get store.akv --format", "get store.akv --format xml", "get store.akv --bogus"] {
+            assert_eq!(parse(args).unwrap_err().exit_code(), 2, "{:?}", args);
+        }
+
+        let args = parse("get store.akv").unwrap();
+        assert!(matches!(args.positional(1, 1), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn dumps_load_back_into_a_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("a.akv")).unwrap();
+        store.load().unwrap();
+        store.insert(b"apple", b"red").unwrap();
+        store.insert(b"\xff", b"\x00").unwrap();
+
+        let mut dump = Vec::new();
+        for kv in store.scan(..) {
+            let (key, value) = kv.unwrap();
+            Format::Json.write_pair(&mut dump, &key, &value).unwrap();
+        }
+
+        let mut copy = ActionKV::open(&dir.path().join("b.akv")).unwrap();
+        copy.load().unwrap();
+        import(&mut copy, Format::Json, dump.as_slice()).unwrap();
+        assert_eq!(copy.get(b"apple").unwrap(), Some(b"red".to_vec()));
+        assert_eq!(copy.get(b"\xff").unwrap(), Some(b"\x00".to_vec()));
+
+        let bad = import(&mut copy, Format::Json, b"{\"key\": \"apple\"}\n".as_ref()).unwrap_err();
+        assert_eq!(bad.exit_code(), 3);
+    }
+}
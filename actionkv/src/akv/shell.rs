@@ -0,0 +1,287 @@
+//! `akv shell`, which opens a store once and then runs command after
+//! command against it, either typed in at a prompt or read from a script
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use libactionkv::ActionKV;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::format::{self, Format};
+use crate::Failure;
+
+const HELP: &str = "\
+Commands:
+    get KEY [@SEQUENCE]
+    put KEY VALUE
+    del KEY
+    scan [PREFIX]
+    stats
+    history KEY
+    help
+    quit";
+
+const PROMPT: &str = "akv> ";
+
+/// Whether the shell should keep going after a command
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    Continue,
+    Quit,
+}
+
+/// Runs the commands in `script` if one is given, or else prompts for them
+pub fn run(store: &mut ActionKV, format: Format, script: Option<&Path>) -> Result<(), Failure> {
+    match script {
+        Some(path) => {
+            let stdout = io::stdout();
+            run_script(store, format, BufReader::new(File::open(path)?), &mut stdout.lock())
+        },
+        None => run_interactive(store, format),
+    }
+}
+
+/// Runs every command in a script, one per line, stopping at the first one
+/// that fails. Blank lines and lines starting with `#` are skipped.
+fn run_script<R: BufRead, W: Write>(
+    store: &mut ActionKV,
+    format: Format,
+    script: R,
+    out: &mut W,
+) -> Result<(), Failure> {
+    for (n, line) in script.lines().enumerate() {
+        let step = execute(store, format, &line?, out).map_err(|failure| match failure {
+            // A script with a mistake in it is bad input, like a bad dump
+            Failure::Usage(message) | Failure::Data(message) => {
+                Failure::Data(format!("line {}: {}", n + 1, message))
+            },
+            failure => failure,
+        })?;
+
+        if step == Step::Quit {
+            break;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Prompts for commands with line editing, until the user quits or
+/// sends end of file. Lines entered are remembered across sessions in
+/// `~/.akv_history`.
+fn run_interactive(store: &mut ActionKV, format: Format) -> Result<(), Failure> {
+    let mut editor = DefaultEditor::new().map_err(readline_failure)?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history the first time round
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // Ctrl-C abandons the line being typed, like in other shells
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_failure(err)),
+        };
+
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let step = execute(store, format, &line, &mut out);
+        out.flush()?;
+
+        match step {
+            Ok(Step::Continue) => {},
+            Ok(Step::Quit) => break,
+            Err(Failure::Usage(message)) => eprintln!("{} (try 'help')", message),
+            Err(failure) => eprintln!("error: {}", failure),
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path).map_err(readline_failure)?;
+    }
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".akv_history"))
+}
+
+fn readline_failure(err: ReadlineError) -> Failure {
+    match err {
+        ReadlineError::Io(err) => Failure::Io(err),
+        err => Failure::Io(io::Error::other(err)),
+    }
+}
+
+/// Runs a single command line. Keys are a single word, and the value given
+/// to `put` is the rest of the line.
+fn execute<W: Write>(store: &mut ActionKV, format: Format, line: &str, out: &mut W) -> Result<Step, Failure> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Step::Continue);
+    }
+
+    let (command, rest) = split_word(line);
+    let one_key = || match split_word(rest) {
+        ("", _) => Err(Failure::Usage(format!("{} needs a KEY", command))),
+        (key, "") => Ok(key.as_bytes()),
+        _ => Err(Failure::Usage(format!("too many arguments for {}", command))),
+    };
+
+    match command {
+        "get" => {
+            let value = match split_word(rest) {
+                ("", _) => return Err(Failure::Usage("get needs a KEY".to_string())),
+                (key, "") => store.get(key.as_bytes())?,
+                // Reads the key as it was just after an earlier write
+                (key, sequence) => match sequence.strip_prefix('@').map(str::parse) {
+                    Some(Ok(sequence)) => store.snapshot_at(sequence)?.get(key.as_bytes())?,
+                    _ => return Err(Failure::Usage(format!("bad sequence number '{}'", sequence))),
+                },
+            };
+
+            match value {
+                Some(value) => write_value(out, format, &value)?,
+                None => writeln!(out, "(not found)")?,
+            }
+        },
+
+        "put" => match split_word(rest) {
+            ("", _) => return Err(Failure::Usage("put needs a KEY and VALUE".to_string())),
+            (key, value) => store.insert(key.as_bytes(), value.as_bytes())?,
+        },
+
+        "del" => {
+            let key = one_key()?;
+            match store.contains_key(key) {
+                true => store.delete(key)?,
+                false => writeln!(out, "(not found)")?,
+            }
+        },
+
+        "scan" => {
+            for kv in store.scan_prefix(rest.as_bytes()) {
+                let (key, value) = kv?;
+                format.write_pair(out, &key, &value)?;
+            }
+        },
+
+        "stats" => crate::write_stats(out, &store.stats()?, None)?,
+
+        "history" => {
+            for version in store.history(one_key()?)? {
+                let written_at = version.written_at.map_or("-".to_string(), format::format_time);
+                write!(
+                    out,
+                    "@{}\t{}:{}\t{}\t",
+                    version.sequence,
+                    version.location.segment,
+                    version.location.offset,
+                    written_at,
+                )?;
+                match version.value {
+                    Some(value) => write_value(out, format, &value)?,
+                    None => writeln!(out, "(deleted)")?,
+                }
+            }
+        },
+
+        "help" => writeln!(out, "{}", HELP)?,
+        "quit" | "exit" => return Ok(Step::Quit),
+        _ => return Err(Failure::Usage(format!("unknown command '{}'", command))),
+    }
+
+    Ok(Step::Continue)
+}
+
+/// Splits the first word off a line, returning it and the rest of the line
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Writes a value on a line of its own, even in raw format
+fn write_value<W: Write>(out: &mut W, format: Format, value: &[u8]) -> io::Result<()> {
+    format.write_value(out, value)?;
+    if format == Format::Raw {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use libactionkv::{ManualClock, Options};
+
+    fn run(store: &mut ActionKV, script: &str) -> Result<String, Failure> {
+        let mut out = Vec::new();
+        run_script(store, Format::Utf8, script.as_bytes(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn scripts_run_each_command_in_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let options = Options { clock, ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+        store.load().unwrap();
+
+        let script = "
+            # set things up
+            put apple red and green
+            put apple green
+            put banana yellow
+            get apple
+            get cherry
+            del banana
+            scan
+            history apple
+            get apple @1
+            get banana @4
+            quit
+            put never reached
+        ";
+        let output = run(&mut store, script).unwrap();
+
+        assert_eq!(
+            output,
+            "green\n(not found)\napple\tgreen\n\
+             @1\t0:16\t2023-11-14T22:13:20.000Z\tred and green\n\
+             @2\t0:54\t2023-11-14T22:13:20.000Z\tgreen\n\
+             red and green\n(not found)\n",
+        );
+        assert!(!store.contains_key(b"never"));
+    }
+
+    #[test]
+    fn scripts_stop_at_the_first_mistake() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        store.load().unwrap();
+
+        let err = run(&mut store, "put apple 1\nget\nput banana 2").unwrap_err();
+        assert!(matches!(&err, Failure::Data(message) if message.starts_with("line 2:")));
+        assert_eq!(err.exit_code(), 3);
+        assert!(!store.contains_key(b"banana"));
+    }
+}
@@ -0,0 +1,419 @@
+//! Serves an ActionKV store over HTTP, so services written in any language
+//! can use it.
+//!
+//!     GET    /keys/KEY          the value of KEY
+//!     PUT    /keys/KEY          sets KEY to the request body
+//!     DELETE /keys/KEY          deletes KEY
+//!     GET    /keys?prefix=P     every key starting with P, with its value
+//!     GET    /stats             figures about the store
+//!
+//! Keys are percent-encoded in paths and queries. Values are raw bytes
+//! (`application/octet-stream`) unless JSON is asked for: a PUT with
+//! `Content-Type: application/json` takes `{"value": BASE64}`, and a GET
+//! with `Accept: application/json` answers with `{"key": BASE64, "value":
+//! BASE64}`. A PUT with `?ttl=SECONDS` expires after that many seconds,
+//! which must be at least 1.
+//! Key listings are always JSON. Keys and values in JSON are always base64,
+//! as either may be any bytes.
+
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::prelude::*;
+use libactionkv::{ActionKV, Options, SharedStore};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_http.exe FILE [ADDRESS]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_http FILE [ADDRESS]
+";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+/// How many requests are handled at once
+const WORKERS: usize = 4;
+
+/// The largest request body accepted
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).expect(USAGE);
+    let address = args.get(2).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+
+    // The index is kept in its own file, so a server that is killed rather
+    // than shut down still restarts quickly
+    let options = Options { persistent_index: true, ordered_index: true, ..Options::default() };
+    let path = Path::new(&fname);
+    let mut store = match path.is_dir() {
+        true    => ActionKV::open_dir(path, options),
+        false   => ActionKV::open_with(path, options),
+    }.expect("unable to open file");
+    store.load().expect("unable to load data");
+
+    let server = Server::http(address).expect("unable to listen on address");
+    eprintln!("listening on http://{}", server.server_addr());
+
+    serve(Arc::new(server), SharedStore::new(store));
+}
+
+/// Handles requests on `WORKERS` threads until the server is shut down
+fn serve(server: Arc<Server>, store: SharedStore) {
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let store = store.clone();
+
+            std::thread::spawn(move || {
+                while let Ok(request) = server.recv() {
+                    if let Err(err) = handle(&store, request) {
+                        eprintln!("unable to respond: {}", err);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+/// The status, content type and body of a response
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: ByteString,
+}
+
+impl Reply {
+    fn empty(status: u16) -> Reply {
+        Reply { status, content_type: "text/plain", body: Vec::new() }
+    }
+
+    fn raw(body: ByteString) -> Reply {
+        Reply { status: 200, content_type: "application/octet-stream", body }
+    }
+
+    fn json(status: u16, body: serde_json::Value) -> Reply {
+        Reply { status, content_type: "application/json", body: body.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, message: impl std::fmt::Display) -> Reply {
+        Reply::json(status, json!({ "error": message.to_string() }))
+    }
+}
+
+fn handle(store: &SharedStore, mut request: Request) -> std::io::Result<()> {
+    let reply = route(store, &mut request);
+
+    let content_type = Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes())
+        .expect("content types are valid headers");
+    let response = Response::from_data(reply.body)
+        .with_status_code(reply.status)
+        .with_header(content_type);
+
+    request.respond(response)
+}
+
+/// Works out what a request is asking for and carries it out
+fn route(store: &SharedStore, request: &mut Request) -> Reply {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    if path == "/stats" {
+        return match request.method() {
+            Method::Get => match store.stats() {
+                Ok(stats) => Reply::json(200, json!(stats)),
+                Err(err) => Reply::error(500, err),
+            },
+            _ => Reply::error(405, "method not allowed"),
+        };
+    }
+
+    if path == "/keys" {
+        return match request.method() {
+            Method::Get => list(store, query),
+            _ => Reply::error(405, "method not allowed"),
+        };
+    }
+
+    let key = match path.strip_prefix("/keys/").map(|key| percent_decode(key, false)) {
+        Some(Some(key)) if !key.is_empty() => key,
+        Some(_) => return Reply::error(400, "invalid key"),
+        None => return Reply::error(404, "not found"),
+    };
+
+    let result = match request.method() {
+        Method::Get => get(store, &key, wants_json(request)),
+        Method::Put => put(store, &key, query, request),
+        Method::Delete => store.delete(&key).map(|deleted| match deleted {
+            true => Reply::empty(204),
+            false => Reply::error(404, "key not found"),
+        }),
+        _ => Ok(Reply::error(405, "method not allowed")),
+    };
+
+    result.unwrap_or_else(|err| Reply::error(500, err))
+}
+
+fn get(store: &SharedStore, key: &ByteStr, as_json: bool) -> libactionkv::Result<Reply> {
+    let value = match store.get(key)? {
+        Some(value) => value,
+        None => return Ok(Reply::error(404, "key not found")),
+    };
+
+    Ok(match as_json {
+        true => Reply::json(200, json!({
+            "key": BASE64_STANDARD.encode(key),
+            "value": BASE64_STANDARD.encode(value),
+        })),
+        false => Reply::raw(value),
+    })
+}
+
+fn put(store: &SharedStore, key: &ByteStr, query: &str, request: &mut Request) -> libactionkv::Result<Reply> {
+    let ttl = match query_param(query, "ttl").map(|ttl| String::from_utf8(ttl).ok()?.parse().ok()) {
+        None => None,
+        // A ttl of 0 would write a value that has already expired
+        Some(Some(0)) | Some(None) => return Ok(Reply::error(400, "invalid ttl")),
+        Some(Some(secs)) => Some(Duration::from_secs(secs)),
+    };
+
+    let is_json = header_contains(request, "Content-Type", "application/json");
+
+    let mut body = ByteString::new();
+    request.as_reader().take(MAX_BODY_LEN + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_BODY_LEN {
+        return Ok(Reply::error(413, "value too large"));
+    }
+
+    let value = match is_json {
+        true => match decode_json_value(&body) {
+            Some(value) => value,
+            None => return Ok(Reply::error(400, "expected {\"value\": BASE64}")),
+        },
+        false => body,
+    };
+
+    match ttl {
+        Some(ttl) => store.insert_with_ttl(key, &value, ttl)?,
+        None => store.insert(key, &value)?,
+    }
+    Ok(Reply::empty(204))
+}
+
+/// Lists the keys that start with the `prefix` query parameter, up to
+/// `limit` of them if that is given
+fn list(store: &SharedStore, query: &str) -> Reply {
+    let prefix = query_param(query, "prefix").unwrap_or_default();
+    let limit = match query_param(query, "limit").map(|limit| String::from_utf8(limit).ok()?.parse().ok()) {
+        None => usize::MAX,
+        Some(Some(limit)) => limit,
+        Some(None) => return Reply::error(400, "invalid limit"),
+    };
+
+    let entries = store.with(|store| {
+        store.scan_prefix(&prefix)
+            .take(limit)
+            .map(|kv| kv.map(|(key, value)| json!({
+                "key": BASE64_STANDARD.encode(key),
+                "value": BASE64_STANDARD.encode(value),
+            })))
+            .collect::<libactionkv::Result<Vec<_>>>()
+    });
+
+    match entries {
+        Ok(entries) => Reply::json(200, json!({ "keys": entries })),
+        Err(err) => Reply::error(500, err),
+    }
+}
+
+fn decode_json_value(body: &ByteStr) -> Option<ByteString> {
+    let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+    BASE64_STANDARD.decode(body.get("value")?.as_str()?).ok()
+}
+
+fn header_contains(request: &Request, name: &str, needle: &str) -> bool {
+    request.headers().iter().any(|header| {
+        header.field.as_str().as_str().eq_ignore_ascii_case(name) && header.value.as_str().to_ascii_lowercase().contains(needle)
+    })
+}
+
+fn wants_json(request: &Request) -> bool {
+    header_contains(request, "Accept", "application/json")
+}
+
+/// The decoded value of a query parameter, if it was given
+fn query_param(query: &str, name: &str) -> Option<ByteString> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value, true))
+}
+
+/// Decodes `%XX` escapes, and in query strings `+` for a space. Returns
+/// `None` if an escape is malformed.
+fn percent_decode(s: &str, is_query: bool) -> Option<ByteString> {
+    let mut decoded = ByteString::with_capacity(s.len());
+    let mut bytes = s.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                // from_str_radix would let a sign through, as in `%+1`
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            },
+            b'+' if is_query => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpStream};
+
+    /// Starts a server on a free localhost port, returning its address
+    fn start_server(dir: &tempfile::TempDir) -> SocketAddr {
+        let options = Options { ordered_index: true, ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+        store.load().unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let store = SharedStore::new(store);
+        std::thread::spawn(move || serve(Arc::new(server), store));
+
+        address
+    }
+
+    /// Makes a request and returns the status code and body of the response
+    fn request(
+        address: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: &[u8],
+    ) -> (u16, ByteString) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method, path, body.len(),
+        );
+        for header in headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = ByteString::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&response[9..12]).unwrap().parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn json_body(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[test]
+    fn keys_can_be_written_read_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = start_server(&dir);
+
+        assert_eq!(request(address, "PUT", "/keys/apple", &[], b"\x00raw").0, 204);
+        assert_eq!(request(address, "GET", "/keys/apple", &[], b""), (200, b"\x00raw".to_vec()));
+
+        let (status, body) = request(address, "GET", "/keys/apple", &["Accept: application/json"], b"");
+        assert_eq!(status, 200);
+        assert_eq!(json_body(&body), json!({ "key": "YXBwbGU=", "value": "AHJhdw==" }));
+
+        // JSON bodies carry the value in base64, and keys can be escaped
+        let put = request(
+            address, "PUT", "/keys/green%20apple",
+            &["Content-Type: application/json"], br#"{"value": "Z3JlZW4="}"#,
+        );
+        assert_eq!(put.0, 204);
+        assert_eq!(request(address, "GET", "/keys/green%20apple", &[], b"").1, b"green");
+
+        let bad = request(address, "PUT", "/keys/pear", &["Content-Type: application/json"], b"{}");
+        assert_eq!(bad.0, 400);
+
+        // A value that would already have expired isn't written
+        assert_eq!(request(address, "PUT", "/keys/pear?ttl=0", &[], b"ripe").0, 400);
+        assert_eq!(request(address, "PUT", "/keys/pear?ttl=x", &[], b"ripe").0, 400);
+        assert_eq!(request(address, "PUT", "/keys/pear?ttl=60", &[], b"ripe").0, 204);
+        assert_eq!(request(address, "GET", "/keys/pear", &[], b"").1, b"ripe");
+
+        assert_eq!(request(address, "DELETE", "/keys/apple", &[], b"").0, 204);
+        assert_eq!(request(address, "GET", "/keys/apple", &[], b"").0, 404);
+        assert_eq!(request(address, "DELETE", "/keys/apple", &[], b"").0, 404);
+        assert_eq!(request(address, "POST", "/keys/apple", &[], b"").0, 405);
+    }
+
+    #[test]
+    fn keys_can_be_listed_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = start_server(&dir);
+
+        for key in ["apple", "apricot", "banana"] {
+            request(address, "PUT", &format!("/keys/{}", key), &[], key.as_bytes());
+        }
+
+        let (status, body) = request(address, "GET", "/keys?prefix=ap", &[], b"");
+        assert_eq!(status, 200);
+        let keys: Vec<_> = json_body(&body)["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| BASE64_STANDARD.decode(entry["key"].as_str().unwrap()).unwrap())
+            .collect();
+        assert_eq!(keys, vec![b"apple".to_vec(), b"apricot".to_vec()]);
+
+        let (_, body) = request(address, "GET", "/keys?limit=1", &[], b"");
+        assert_eq!(json_body(&body)["keys"].as_array().unwrap().len(), 1);
+
+        let (status, body) = request(address, "GET", "/stats", &[], b"");
+        assert_eq!(status, 200);
+        assert_eq!(json_body(&body)["keys"], 3);
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("a%2Fb+c", false), Some(b"a/b+c".to_vec()));
+        assert_eq!(percent_decode("a%2Fb+c", true), Some(b"a/b c".to_vec()));
+        assert_eq!(percent_decode("%zz", false), None);
+        assert_eq!(percent_decode("%+1", false), None);
+        assert_eq!(percent_decode("%-1", false), None);
+        assert_eq!(query_param("x=1&prefix=%ff", "prefix"), Some(vec![0xff]));
+    }
+}
@@ -0,0 +1,740 @@
+//! Serves an ActionKV store over TCP using the Redis protocol (RESP), so
+//! that other processes, and existing Redis clients, can use it without
+//! reopening the store on every call.
+//!
+//! Supported commands: PING, GET, SET (with EX or PX), DEL, EXISTS, SCAN
+//! (with MATCH and COUNT), INFO and QUIT. Each connection is served by its
+//! own thread, all sharing the one store.
+//!
+//! With `--replicate ADDRESS`, the server also ships its log to followers
+//! that connect there. With `--follow ADDRESS`, it is a follower itself: it
+//! keeps its store up to date with the leader whose log is served at that
+//! address, answers reads, and refuses writes. `INFO replication` reports
+//! how far behind the leader it is. A follower won't empty a store that
+//! already holds records of its own unless `--reset` is given as well.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use libactionkv::{ActionKV, Follower, Options, SharedStore};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_server.exe FILE [ADDRESS] [--replicate ADDRESS | --follow ADDRESS [--reset]]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_server FILE [ADDRESS] [--replicate ADDRESS | --follow ADDRESS [--reset]]
+";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6379";
+
+/// How many keys SCAN looks at when the client doesn't give a COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// The longest line a client may send, and the largest bulk string. Lengths
+/// come from the client, so they aren't trusted to size buffers with.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let replicate = take_option(&mut args, "--replicate");
+    let follow = take_option(&mut args, "--follow");
+    let reset = take_flag(&mut args, "--reset");
+    if replicate.is_some() && follow.is_some() || reset && follow.is_none() {
+        panic!("{}", USAGE);
+    }
+
+    let fname = args.get(1).expect(USAGE);
+    let address = args.get(2).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+
+    // The index is kept in its own file, so a server that is killed rather
+    // than shut down still restarts quickly
+    let options = Options { persistent_index: true, ordered_index: true, ..Options::default() };
+    let path = Path::new(&fname);
+    let mut store = match path.is_dir() {
+        true    => ActionKV::open_dir(path, options),
+        false   => ActionKV::open_with(path, options),
+    }.expect("unable to open file");
+    store.load().expect("unable to load data");
+
+    let listener = TcpListener::bind(address).expect("unable to listen on address");
+    eprintln!("listening on {}", listener.local_addr().expect("unable to get address"));
+
+    let store = SharedStore::new(store);
+    if let Some(address) = replicate {
+        let replication = TcpListener::bind(&address).expect("unable to listen on replication address");
+        eprintln!("shipping the log on {}", address);
+
+        let store = store.clone();
+        std::thread::spawn(move || libactionkv::serve_log(replication, store));
+    }
+
+    let follower = follow.map(|leader| {
+        let follower = match reset {
+            true => Follower::new(store.clone(), &leader).resetting(),
+            false => Follower::new(store.clone(), &leader),
+        };
+        let follower = Arc::new(follower);
+        eprintln!("following {}", leader);
+
+        let running = follower.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = running.run() {
+                eprintln!("replication stopped: {}", err);
+            }
+        });
+        follower
+    });
+
+    serve(listener, store, follower).expect("unable to accept connections");
+}
+
+/// Removes `--name VALUE` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let at = args.iter().position(|arg| arg == name)?;
+    args.remove(at);
+    match at < args.len() {
+        true => Some(args.remove(at)),
+        false => panic!("{} needs a value\n{}", name, USAGE),
+    }
+}
+
+/// Removes `--name` from the arguments, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let at = args.iter().position(|arg| arg == name);
+    if let Some(at) = at {
+        args.remove(at);
+    }
+    at.is_some()
+}
+
+/// Accepts connections until the listener fails, serving each one on its
+/// own thread. A follower's connections are only allowed to read.
+fn serve(listener: TcpListener, store: SharedStore, follower: Option<Arc<Follower>>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        let follower = follower.clone();
+
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(err) = handle_connection(stream, &store, follower.as_deref()) {
+                eprintln!("connection {:?}: {}", peer, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Reads commands from a client and answers each one, until the client
+/// hangs up or quits
+fn handle_connection(stream: TcpStream, store: &SharedStore, follower: Option<&Follower>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR Protocol error: {}", err)).write_to(&mut writer)?;
+                return writer.flush();
+            },
+            Err(err) => return Err(err),
+        };
+
+        if command.is_empty() {
+            continue;
+        }
+
+        let quit = command[0].eq_ignore_ascii_case(b"quit");
+        execute(store, follower, &command).write_to(&mut writer)?;
+
+        // Pipelined commands are answered together, once the client has
+        // nothing more waiting to be read
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// A reply to a command, in any of the forms RESP allows
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string for `None`
+    Bulk(Option<ByteString>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(f, "+{}\r\n", s),
+            Reply::Error(e) => write!(f, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(f, ":{}\r\n", n),
+            Reply::Bulk(None) => f.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(f, "${}\r\n", bytes.len())?;
+                f.write_all(bytes)?;
+                f.write_all(b"\r\n")
+            },
+            Reply::Array(items) => {
+                write!(f, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(f))
+            },
+        }
+    }
+}
+
+fn wrong_args(name: &ByteStr) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        String::from_utf8_lossy(name).to_lowercase(),
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+/// Runs a single command against the store
+fn execute(store: &SharedStore, follower: Option<&Follower>, command: &[ByteString]) -> Reply {
+    let name = command[0].to_ascii_uppercase();
+    let args = &command[1..];
+
+    // Only the leader's writes may reach a follower's store
+    if follower.is_some() && matches!(name.as_slice(), b"SET" | b"DEL") {
+        return Reply::Error("READONLY You can't write against a read only replica.".to_string());
+    }
+
+    let result = match (name.as_slice(), args.len()) {
+        (b"PING", 0) => Ok(Reply::Simple("PONG")),
+        (b"PING", 1) => Ok(Reply::Bulk(Some(args[0].clone()))),
+        (b"QUIT", _) => Ok(Reply::Simple("OK")),
+        // Sent by redis-cli when it connects; an empty answer is enough
+        (b"COMMAND", _) => Ok(Reply::Array(Vec::new())),
+
+        (b"GET", 1) => store.get(&args[0]).map(Reply::Bulk),
+        (b"SET", n) if n >= 2 => return set(store, args),
+        (b"DEL", n) if n >= 1 => delete(store, args),
+        (b"EXISTS", n) if n >= 1 => {
+            let found = args.iter().filter(|key| store.contains_key(key)).count();
+            Ok(Reply::Integer(found as i64))
+        },
+        (b"SCAN", n) if n >= 1 => return scan(store, args),
+        (b"INFO", 0 | 1) => Ok(Reply::Bulk(Some(info(follower).into_bytes()))),
+
+        (b"PING" | b"GET" | b"SET" | b"DEL" | b"EXISTS" | b"SCAN" | b"INFO", _) => return wrong_args(&command[0]),
+        _ => return Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&command[0]),
+        )),
+    };
+
+    result.unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+}
+
+/// SET key value [EX seconds | PX milliseconds]
+fn set(store: &SharedStore, args: &[ByteString]) -> Reply {
+    let (key, value) = (&args[0], &args[1]);
+
+    let ttl = match &args[2..] {
+        [] => None,
+        [unit, amount] => {
+            let amount = match parse_int(amount) {
+                Some(amount) if amount > 0 => amount as u64,
+                _ => return Reply::Error("ERR invalid expire time in 'set' command".to_string()),
+            };
+
+            match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => Some(Duration::from_secs(amount)),
+                b"PX" => Some(Duration::from_millis(amount)),
+                _ => return syntax_error(),
+            }
+        },
+        _ => return syntax_error(),
+    };
+
+    let result = match ttl {
+        Some(ttl) => store.insert_with_ttl(key, value, ttl),
+        None => store.insert(key, value),
+    };
+
+    match result {
+        Ok(()) => Reply::Simple("OK"),
+        Err(err) => Reply::Error(format!("ERR {}", err)),
+    }
+}
+
+/// DEL key [key ...], answering with how many of the keys existed
+fn delete(store: &SharedStore, keys: &[ByteString]) -> libactionkv::Result<Reply> {
+    let mut deleted = 0;
+    for key in keys {
+        if store.delete(key)? {
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count]
+///
+/// The cursor is the last key an earlier call returned, and each call picks
+/// up from the key after it, in key order. As with Redis, keys that exist
+/// for the whole scan are always returned, but keys added or removed during
+/// it may be missed.
+fn scan(store: &SharedStore, args: &[ByteString]) -> Reply {
+    let after = match parse_cursor(&args[0]) {
+        Some(after) => after,
+        None => return Reply::Error("ERR invalid cursor".to_string()),
+    };
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = Some(value.clone()),
+            [name, value] if name.eq_ignore_ascii_case(b"count") => match parse_int(value) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return syntax_error(),
+            },
+            _ => return syntax_error(),
+        }
+    }
+
+    let lower = match &after {
+        Some(key) => Bound::Excluded(key.as_slice()),
+        None => Bound::Unbounded,
+    };
+    let mut keys: Vec<ByteString> = store.with(|store| {
+        store.index
+            .range(lower, Bound::Unbounded)
+            .filter(|(key, _)| store.contains_key(key))
+            .take(count + 1)
+            .map(|(key, _)| key.clone())
+            .collect()
+    });
+
+    // One more key than asked for was taken, to tell whether any are left
+    let next = match keys.len() > count {
+        true => {
+            keys.truncate(count);
+            cursor_after(&keys[count - 1])
+        },
+        false => b"0".to_vec(),
+    };
+
+    if let Some(pattern) = pattern {
+        keys.retain(|key| glob_match(&pattern, key));
+    }
+
+    Reply::Array(vec![
+        Reply::Bulk(Some(next)),
+        Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect()),
+    ])
+}
+
+/// The cursor that carries a scan on from just after `key`. Clients expect
+/// cursors to be numbers, so it is a 1 followed by each byte of the key as
+/// three decimal digits.
+fn cursor_after(key: &ByteStr) -> ByteString {
+    let mut cursor = b"1".to_vec();
+    for b in key {
+        cursor.extend_from_slice(format!("{:03}", b).as_bytes());
+    }
+    cursor
+}
+
+/// The key a cursor carries on from, or `None` for 0, which starts a scan
+fn parse_cursor(cursor: &ByteStr) -> Option<Option<ByteString>> {
+    match cursor {
+        b"0" => Some(None),
+        [b'1', digits @ ..] if digits.len() % 3 == 0 => digits
+            .chunks(3)
+            .map(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok())
+            .collect::<Option<ByteString>>()
+            .map(Some),
+        _ => None,
+    }
+}
+
+/// INFO [section], of which only the replication section is kept, in the
+/// form Redis gives it
+fn info(follower: Option<&Follower>) -> String {
+    let follower = match follower {
+        Some(follower) => follower,
+        None => return "# Replication\r\nrole:master\r\n".to_string(),
+    };
+
+    let status = follower.status();
+    let position = status.position.map_or("none".to_string(), |p| format!("{}:{}", p.segment, p.offset));
+    let last_io = status.last_contact
+        .and_then(|at| SystemTime::now().duration_since(at).ok())
+        .map_or(-1, |since| since.as_secs() as i64);
+
+    format!(
+        "# Replication\r\nrole:slave\r\nmaster_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\n\
+         slave_repl_position:{}\r\nslave_repl_behind_bytes:{}\r\nslave_repl_applied:{}\r\n",
+        if status.connected { "up" } else { "down" },
+        last_io,
+        position,
+        status.behind_bytes,
+        status.applied,
+    )
+}
+
+/// Matches a key against a Redis style glob pattern, where `*` matches any
+/// run of bytes, `?` any single byte, `[...]` any byte in the set and `\`
+/// escapes the byte after it.
+///
+/// Only the last `*` seen is ever backtracked to, as anything an earlier one
+/// could match, the last one can too. That keeps the work for each `*` to
+/// one pass over the key, however many a client puts in the pattern.
+fn glob_match(pattern: &ByteStr, key: &ByteStr) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Just past the last `*`, and where in the key its run ends so far
+    let mut star = None;
+
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+
+        if let Some(len) = match_one(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+            continue;
+        }
+
+        // Let the last `*` match one more byte, and try again from there
+        match star {
+            Some((after_star, run_end)) => {
+                p = after_star;
+                k = run_end + 1;
+                star = Some((after_star, k));
+            },
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Whether the start of `pattern`, other than a `*`, matches the byte `b`.
+/// Returns how much of the pattern it took up.
+fn match_one(pattern: &ByteStr, b: u8) -> Option<usize> {
+    let len = match pattern {
+        [] => return None,
+        [b'?', ..] => 1,
+        [b'[', rest @ ..] => match rest.iter().position(|&c| c == b']') {
+            Some(close) => {
+                let (negate, set) = match &rest[..close] {
+                    [b'^', set @ ..] => (true, set),
+                    set => (false, set),
+                };
+                match in_set(set, b) != negate {
+                    true => close + 2,
+                    false => return None,
+                }
+            },
+            None if b == b'[' => 1,
+            None => return None,
+        },
+        [b'\\', escaped, ..] if *escaped == b => 2,
+        [b'\\', _, ..] => return None,
+        [c, ..] if *c == b => 1,
+        _ => return None,
+    };
+    Some(len)
+}
+
+/// Whether `b` is in a glob character set such as `abc` or `a-z`
+fn in_set(set: &ByteStr, b: u8) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            if (set[i]..=set[i + 2]).contains(&b) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == b {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Reads one command: either a RESP array of bulk strings, as sent by
+/// Redis clients, or an inline command of words separated by spaces, as
+/// typed into telnet. Returns `None` once the client has hung up.
+fn read_command<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<ByteString>>> {
+    let line = match read_line(r)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let words = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(words));
+    }
+
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let header = read_line(r)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+
+        let len = parse_len(&header[1..])?;
+        if len > MAX_BULK_LEN {
+            return Err(protocol_error("invalid bulk length"));
+        }
+
+        let mut arg = ByteString::new();
+        r.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF after bulk string"));
+        }
+
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Reads a line ending in CRLF (or just LF), without the line ending.
+/// Returns `None` if the stream ends before the line starts.
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<ByteString>> {
+    let mut line = ByteString::new();
+    r.by_ref().take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return match line.len() as u64 + 1 >= MAX_LINE_LEN {
+            true => Err(protocol_error("line too long")),
+            false => Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &ByteStr) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn parse_len(bytes: &ByteStr) -> io::Result<usize> {
+    match parse_int(bytes) {
+        Some(len) if len >= 0 => Ok(len as usize),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Starts a server on a free localhost port, returning its address
+    fn start_server(dir: &tempfile::TempDir) -> SocketAddr {
+        let options = Options { ordered_index: true, ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+        store.load().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let store = SharedStore::new(store);
+        std::thread::spawn(move || serve(listener, store, None));
+
+        address
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            let writer = TcpStream::connect(address).unwrap();
+            let reader = BufReader::new(writer.try_clone().unwrap());
+            Client { reader, writer }
+        }
+
+        /// Sends a command the way Redis clients do, and returns the raw reply
+        fn send(&mut self, args: &[&str]) -> String {
+            let mut request = format!("*{}\r\n", args.len());
+            for arg in args {
+                request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+            self.writer.write_all(request.as_bytes()).unwrap();
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+
+            match line.as_bytes()[0] {
+                b'$' if !line.starts_with("$-1") => {
+                    let mut bulk = String::new();
+                    self.reader.read_line(&mut bulk).unwrap();
+                    line + &bulk
+                },
+                b'*' => {
+                    let count: usize = line[1..].trim().parse().unwrap();
+                    (0..count).fold(line, |reply, _| reply + &self.read_reply())
+                },
+                _ => line,
+            }
+        }
+    }
+
+    #[test]
+    fn answers_redis_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::connect(start_server(&dir));
+
+        assert_eq!(client.send(&["PING"]), "+PONG\r\n");
+        assert_eq!(client.send(&["SET", "apple", "1"]), "+OK\r\n");
+        assert_eq!(client.send(&["set", "apricot", "2", "EX", "100"]), "+OK\r\n");
+        assert_eq!(client.send(&["SET", "banana", "3"]), "+OK\r\n");
+        assert_eq!(client.send(&["GET", "apple"]), "$1\r\n1\r\n");
+        assert_eq!(client.send(&["GET", "cherry"]), "$-1\r\n");
+        assert_eq!(client.send(&["EXISTS", "apple", "banana", "cherry"]), ":2\r\n");
+        assert_eq!(client.send(&["DEL", "banana", "cherry"]), ":1\r\n");
+        assert_eq!(client.send(&["EXISTS", "banana"]), ":0\r\n");
+
+        assert_eq!(
+            client.send(&["SCAN", "0", "MATCH", "ap*"]),
+            "*2\r\n$1\r\n0\r\n*2\r\n$5\r\napple\r\n$7\r\napricot\r\n",
+        );
+        assert_eq!(
+            client.send(&["SCAN", "0", "COUNT", "1"]),
+            "*2\r\n$16\r\n1097112112108101\r\n*1\r\n$5\r\napple\r\n",
+        );
+        assert_eq!(
+            client.send(&["SCAN", "1097112112108101", "COUNT", "1"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$7\r\napricot\r\n",
+        );
+        assert_eq!(client.send(&["SCAN", "12"]), "-ERR invalid cursor\r\n");
+
+        assert!(client.send(&["GET"]).starts_with("-ERR wrong number of arguments"));
+        assert!(client.send(&["FLUSHALL"]).starts_with("-ERR unknown command"));
+
+        // Inline commands work too, e.g. from telnet
+        client.writer.write_all(b"GET apple\r\n").unwrap();
+        assert_eq!(client.read_reply(), "$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn serves_many_connections_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = start_server(&dir);
+
+        let clients: Vec<_> = (0..8)
+            .map(|n| {
+                std::thread::spawn(move || {
+                    let mut client = Client::connect(address);
+                    for i in 0..20 {
+                        let key = format!("{}-{}", n, i);
+                        assert_eq!(client.send(&["SET", &key, &key]), "+OK\r\n");
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        let mut client = Client::connect(address);
+        assert_eq!(client.send(&["GET", "7-19"]), "$4\r\n7-19\r\n");
+        assert_eq!(client.send(&["SCAN", "0", "COUNT", "1000"]).matches("\r\n$").count(), 161);
+    }
+
+    #[test]
+    fn followers_only_answer_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        store.load().unwrap();
+        let store = SharedStore::new(store);
+        let follower = Follower::new(store.clone(), "127.0.0.1:1");
+
+        let run = |args: &[&str]| {
+            let command: Vec<ByteString> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            execute(&store, Some(&follower), &command)
+        };
+
+        assert!(matches!(run(&["SET", "apple", "1"]), Reply::Error(e) if e.starts_with("READONLY")));
+        assert!(matches!(run(&["DEL", "apple"]), Reply::Error(e) if e.starts_with("READONLY")));
+        assert_eq!(run(&["GET", "apple"]), Reply::Bulk(None));
+
+        let Reply::Bulk(Some(info)) = run(&["INFO", "replication"]) else { panic!("INFO isn't a bulk string") };
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("role:slave\r\n"));
+        assert!(info.contains("master_link_status:down\r\n"));
+        assert!(info.contains("slave_repl_position:none\r\n"));
+    }
+
+    #[test]
+    fn glob_patterns_match_like_redis() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"[a-c]*", b"banana"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*a*b", b"xaxxab"));
+        assert!(!glob_match(b"*a*b", b"xaxxa"));
+        assert!(glob_match(b"*[", b"a["));
+
+        // Each `*` only takes one pass over the key, however many there are
+        let key = vec![b'a'; 10_000];
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*b", &key));
+    }
+}
@@ -0,0 +1,234 @@
+//! Backing a store up while it is in use, and restoring it from the backup.
+//!
+//! A backup holds the segment files as they stood at one moment. With the
+//! store locked, its writes are flushed, each segment file is opened again
+//! and its length is noted. Records are only ever appended, so the bytes up
+//! to those lengths won't change, and they are copied once the store is
+//! free to be written to again. Compaction and emptying the store replace
+//! segment files rather than changing them, so the copy carries on reading
+//! the files it opened even if they are replaced part way through.
+//!
+//! A backup of a single file store is a single file, and a backup of a
+//! directory store is a directory of the same segment files. The backup is
+//! put together next to where it is going (`PATH.partial`), and every
+//! record in it has its checksum checked before it is renamed into place.
+//! Encrypted records have their tag checked instead, with the store's keys.
+//! `restore` isn't given a key, so it can only check that an encrypted
+//! record is laid out as it should be, and leaves its tag to be checked as
+//! it is read.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::crypto::EncryptionKey;
+use crate::header::FileHeader;
+use crate::{hint, index_file, segment, store_file_path, sync_parent_dir};
+use crate::{Error, Result};
+use crate::{FLAG_ENCRYPTED, HEADER_LEN, KEY_LEN_MASK};
+
+/// A segment file to copy, and how much of it
+#[derive(Debug)]
+pub(crate) struct Source {
+    pub id: u32,
+    pub f: File,
+    pub len: u64,
+}
+
+/// Copies the segments to a new backup at `path`, as a directory if the
+/// store is kept in one. `keys` are the store's, to check encrypted
+/// records with.
+pub(crate) fn copy(sources: Vec<Source>, keys: &[EncryptionKey], directory: bool, path: &Path) -> Result<()> {
+    if path.exists() {
+        let message = format!("{} already exists", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+    }
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    let partial = PathBuf::from(name);
+    remove_any(&partial)?;
+    if directory {
+        fs::create_dir(&partial)?;
+    }
+
+    if let Err(err) = write_backup(sources, keys, directory, &partial) {
+        remove_any(&partial)?;
+        return Err(err);
+    }
+
+    fs::rename(&partial, path)?;
+    sync_parent_dir(path)?;
+    Ok(())
+}
+
+/// Copies and checks each segment in turn
+fn write_backup(sources: Vec<Source>, keys: &[EncryptionKey], directory: bool, partial: &Path) -> Result<()> {
+    for source in sources {
+        let id = source.id;
+        let to = match directory {
+            true => segment::segment_path(partial, id),
+            false => partial.to_path_buf(),
+        };
+
+        copy_segment(source, &to)?;
+        check_segment(id, &to, keys)?;
+        if directory {
+            sync_parent_dir(&to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the store at `path` with the backup at `backup`, which is
+/// checked first. The store mustn't be open anywhere else while it is
+/// restored.
+///
+/// Every segment file is copied next to where it belongs first
+/// (`PATH.restore`, or `DIR/00000000.akv.restore` and so on). A single
+/// file store is then swapped for its copy with one rename. A directory
+/// store takes a rename for each segment, and any of its segments the
+/// backup doesn't have are removed, so it lists what it is restoring in a
+/// marker (`DIR/store.restore`) first: a restore that is interrupted after
+/// that is finished the next time the store is opened, and one interrupted
+/// before leaves the store as it was. The hint and index files go too, and
+/// are rebuilt on the next load.
+pub fn restore(backup: &Path, path: &Path) -> Result<()> {
+    let directory = backup.is_dir();
+    let segments = match directory {
+        true => segment::list(backup)?
+            .into_iter()
+            .map(|id| (id, segment::segment_path(backup, id)))
+            .collect(),
+        false => vec![(0, backup.to_path_buf())],
+    };
+
+    if segments.is_empty() {
+        let message = format!("{} holds no segment files", backup.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+    }
+    for (id, segment_path) in &segments {
+        check_segment(*id, segment_path, &[])?;
+    }
+
+    if path.exists() && path.is_dir() != directory {
+        let message = match directory {
+            true => format!("{} is a directory store backup, but {} is a file", backup.display(), path.display()),
+            false => format!("{} is a single file backup, but {} is a directory", backup.display(), path.display()),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+    }
+    if directory {
+        fs::create_dir_all(path)?;
+    }
+
+    let _lock = crate::lock::acquire(&store_file_path(path, directory, "lock"), false)?;
+
+    for (id, from) in &segments {
+        let to = match directory {
+            true => segment::segment_path(path, *id),
+            false => path.to_path_buf(),
+        };
+
+        let f = File::open(from)?;
+        let len = f.metadata()?.len();
+        copy_segment(Source { id: *id, f, len }, &crate::staged_path(&to, "restore"))?;
+    }
+
+    if directory {
+        let ids: Vec<u32> = segments.iter().map(|(id, _)| *id).collect();
+        crate::begin_replacing(path, "restore", &ids)?;
+        return crate::finish_replacing(path, "restore", false);
+    }
+
+    hint::remove(&store_file_path(path, directory, "hint"))?;
+    index_file::remove(&store_file_path(path, directory, "idx"))?;
+    fs::rename(crate::staged_path(path, "restore"), path)?;
+    sync_parent_dir(path)?;
+    Ok(())
+}
+
+/// Copies the first `source.len` bytes of a segment file to a new file at
+/// `to`, and syncs it
+fn copy_segment(source: Source, to: &Path) -> Result<()> {
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+
+    let copied = {
+        let mut writer = BufWriter::new(&mut out);
+        let copied = io::copy(&mut BufReader::new(source.f).take(source.len), &mut writer)?;
+        writer.flush()?;
+        copied
+    };
+
+    // Something other than the store cut the file short as it was copied
+    if copied < source.len {
+        let message = format!("segment {} shrank while it was being copied", source.id);
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message).into());
+    }
+
+    out.sync_all()?;
+    Ok(())
+}
+
+/// Checks the header of the segment file at `path`, and the checksum of
+/// every record after it. A batch frame's checksum covers the records in
+/// it, so they aren't checked one by one. Encrypted records are checked
+/// by their tag if there are `keys` to check it with.
+fn check_segment(id: u32, path: &Path, keys: &[EncryptionKey]) -> Result<()> {
+    let mut f = BufReader::new(File::open(path)?);
+    FileHeader::read(&mut f, id)?;
+
+    let mut offset = crate::header::LEN;
+    let mut data = Vec::new();
+    loop {
+        let mut header = [0u8; HEADER_LEN as usize];
+        match crate::read_up_to(&mut f, &mut header)? {
+            0 => return Ok(()),
+            n if n == header.len() => {},
+            _ => return Err(Error::TruncatedRecord { segment: id, offset }),
+        }
+
+        let mut fields = &header[..];
+        let saved_checksum = fields.read_u32::<LittleEndian>()?;
+        let flags_and_key_len = fields.read_u32::<LittleEndian>()?;
+        let val_len = fields.read_u32::<LittleEndian>()?;
+        let flags = (flags_and_key_len >> 24) as u8;
+        let data_len = crate::data_len(flags, (flags_and_key_len & KEY_LEN_MASK) as u64, val_len as u64);
+
+        data.clear();
+        f.by_ref().take(data_len).read_to_end(&mut data)?;
+        if (data.len() as u64) < data_len {
+            return Err(Error::TruncatedRecord { segment: id, offset });
+        }
+        if flags & FLAG_ENCRYPTED == 0 {
+            if crate::record_checksum(flags, &data) != saved_checksum {
+                return Err(Error::ChecksumMismatch { segment: id, offset });
+            }
+        } else if !keys.is_empty() && crate::open_record(&header, &data, keys).is_none() {
+            return Err(Error::Decryption { segment: id, offset });
+        }
+
+        offset += HEADER_LEN + data_len;
+    }
+}
+
+/// Removes whatever is at `path`, be it a file or a directory
+fn remove_any(path: &Path) -> io::Result<()> {
+    let removed = match path.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
+    };
+
+    match removed {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,67 @@
+//! Groups of writes that are applied to a store all at once
+
+use std::time::Duration;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// A set of puts and deletes to be written to a store together with
+/// `ActionKV::write_batch`.
+///
+/// The batch is stored as a single checksummed frame, so after a crash
+/// either every write in it is there or none of them are. Writes are
+/// applied in the order they were added, so a later write to a key in the
+/// same batch wins.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A key with its new value, or `None` for a delete, and how long the new
+/// value lives for if it has a time to live
+#[derive(Debug, Clone)]
+pub(crate) struct BatchOp {
+    pub key: ByteString,
+    pub value: Option<ByteString>,
+    pub ttl: Option<Duration>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a write of `value` to `key`
+    pub fn put(&mut self, key: &ByteStr, value: &ByteStr) -> &mut Self {
+        self.push(key, Some(value), None)
+    }
+
+    /// Adds a write of `value` to `key` that expires once `ttl` has passed.
+    /// The time to live starts from when the batch is written.
+    pub fn put_with_ttl(&mut self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> &mut Self {
+        self.push(key, Some(value), Some(ttl))
+    }
+
+    /// Adds a delete of `key`
+    pub fn delete(&mut self, key: &ByteStr) -> &mut Self {
+        self.push(key, None, None)
+    }
+
+    fn push(&mut self, key: &ByteStr, value: Option<&ByteStr>, ttl: Option<Duration>) -> &mut Self {
+        self.ops.push(BatchOp { key: key.to_vec(), value: value.map(<[u8]>::to_vec), ttl });
+        self
+    }
+
+    /// How many writes are in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
@@ -0,0 +1,95 @@
+//! Compressing large values before they are written.
+//!
+//! A compressed record has FLAG_LZ4 or FLAG_ZSTD set, saying which
+//! algorithm its value was compressed with. The stored value starts with
+//! the original length as 4 bytes, followed by the compressed bytes, so the
+//! original size is known without decompressing it. The checksum covers
+//! the value as it is stored.
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{FLAG_LZ4, FLAG_ZSTD};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Every flag that marks a record's value as compressed
+pub(crate) const FLAGS: u8 = FLAG_LZ4 | FLAG_ZSTD;
+
+/// The zstd level values are compressed at, zstd's own default
+const ZSTD_LEVEL: i32 = 3;
+
+/// How values are compressed before they are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Quick to compress and decompress, but saves less space
+    Lz4,
+    /// Slower, but saves more space
+    Zstd,
+}
+
+impl Compression {
+    /// How the algorithm is recorded in a file header
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compresses `value`, returning what to store along with the flag to
+    /// set on the record. Values that compression doesn't make smaller are
+    /// left as they are.
+    pub(crate) fn compress(self, value: &ByteStr) -> io::Result<Option<(ByteString, u8)>> {
+        let (stored, flag) = match self {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => (lz4_flex::compress_prepend_size(value), FLAG_LZ4),
+            Compression::Zstd => {
+                let mut stored = (value.len() as u32).to_le_bytes().to_vec();
+                stored.extend(zstd::bulk::compress(value, ZSTD_LEVEL)?);
+                (stored, FLAG_ZSTD)
+            },
+        };
+
+        match stored.len() < value.len() {
+            true => Ok(Some((stored, flag))),
+            false => Ok(None),
+        }
+    }
+}
+
+/// Turns the stored value of a record with the given flags back into the
+/// value that was written
+pub(crate) fn decompress(flags: u8, stored: ByteString) -> io::Result<ByteString> {
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+
+    match flags & FLAGS {
+        0 => Ok(stored),
+        FLAG_LZ4 => lz4_flex::decompress_size_prepended(&stored).map_err(|err| invalid(err.to_string())),
+        FLAG_ZSTD => {
+            let len = original_len(&stored).ok_or_else(|| invalid("compressed value is too short".to_string()))?;
+            zstd::bulk::decompress(&stored[4..], len as usize)
+        },
+        _ => Err(invalid("value is marked with more than one compression".to_string())),
+    }
+}
+
+/// The length a compressed value had before it was compressed, from the
+/// start of its stored value
+pub(crate) fn original_len(stored: &ByteStr) -> Option<u64> {
+    (stored.len() >= 4).then(|| LittleEndian::read_u32(stored) as u64)
+}
@@ -0,0 +1,112 @@
+//! Encrypting records at rest with ChaCha20-Poly1305.
+//!
+//! An encrypted record has FLAG_ENCRYPTED set. Its key and value are
+//! encrypted together after a random 12 byte nonce, and the 16 byte
+//! authentication tag takes the place of the CRC32 other records carry:
+//! its first 4 bytes are kept where the checksum would be, and the rest
+//! after the ciphertext.
+//!
+//! ```text
+//! tag[..4]  key_len  val_len  [expiry] [written]  nonce     key and value  tag[4..]
+//! [ | | ]   [ | | ]  [ | | ]  [......] [.......]  [......]  [...........]  [......]
+//! ```
+//!
+//! key_len and val_len are the lengths before encryption. The tag covers
+//! the lengths, flags and times as well as the key and value, so none of
+//! them can be changed without the record failing to decrypt.
+//!
+//! With no CRC32, a damaged record can't be told apart from one sealed
+//! with a different key: either fails with `Error::Decryption`, which
+//! recovery leaves alone rather than skipping or truncating the record, in
+//! case it is the key that is wrong. Only the key can check the tag, so
+//! without it just the layout of encrypted records can be checked.
+
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// How many bytes encryption adds to a record: the nonce and the part of
+/// the tag that doesn't fit in the checksum field
+pub(crate) const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN - HEAD_LEN) as u64;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// How much of the tag goes in the record's checksum field
+const HEAD_LEN: usize = 4;
+
+/// A 256 bit key that records are encrypted with. It is never written to
+/// the store, so it has to be kept somewhere safe and given to every open.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Makes a new key from the operating system's random number generator
+    pub fn generate() -> Self {
+        EncryptionKey(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.0.into())
+    }
+
+    /// Encrypts `plaintext` under a fresh nonce, returning the first 4
+    /// bytes of the tag, for the checksum field, along with the nonce,
+    /// ciphertext and the rest of the tag one after another
+    pub(crate) fn seal(&self, plaintext: &ByteStr, aad: &ByteStr) -> (u32, ByteString) {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = self.cipher()
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .expect("encrypting into memory can't fail");
+
+        let tag_at = sealed.len() - TAG_LEN;
+        let head: [u8; HEAD_LEN] = sealed[tag_at..tag_at + HEAD_LEN].try_into().unwrap();
+        sealed.drain(tag_at..tag_at + HEAD_LEN);
+
+        let mut out = ByteString::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend(sealed);
+        (u32::from_le_bytes(head), out)
+    }
+}
+
+/// Keys are secret, so they are left out of debug output
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Decrypts what `seal` produced, given the head of the tag it returned,
+/// with the first of `keys` that it was sealed with. Returns `None` if none
+/// of them were, or it has been tampered with.
+pub(crate) fn open(keys: &[EncryptionKey], head: u32, sealed: &ByteStr, aad: &ByteStr) -> Option<ByteString> {
+    if sealed.len() < OVERHEAD as usize {
+        return None;
+    }
+
+    // Put the tag back together after the ciphertext
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let tag_at = rest.len() - (TAG_LEN - HEAD_LEN);
+    let mut ciphertext = ByteString::with_capacity(rest.len() + HEAD_LEN);
+    ciphertext.extend_from_slice(&rest[..tag_at]);
+    ciphertext.extend_from_slice(&head.to_le_bytes());
+    ciphertext.extend_from_slice(&rest[tag_at..]);
+
+    let nonce = Nonce::from_slice(nonce);
+    keys.iter().find_map(|key| {
+        key.cipher().decrypt(nonce, Payload { msg: &ciphertext, aad }).ok()
+    })
+}
@@ -0,0 +1,139 @@
+//! Errors that can occur while reading from or writing to an ActionKV store
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::NodeId;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The record starting at `offset` in segment `segment` doesn't match
+    /// its saved checksum
+    ChecksumMismatch { segment: u32, offset: u64 },
+
+    /// The segment ends part way through the record starting at `offset`,
+    /// typically because a write was torn by a crash
+    TruncatedRecord { segment: u32, offset: u64 },
+
+    /// Segment `segment` doesn't start with an ActionKV file header. Either
+    /// it isn't a store at all, or it was written before there were headers
+    /// and has to be upgraded first.
+    MissingHeader { segment: u32 },
+
+    /// The file header of segment `segment` asks for something this version
+    /// of ActionKV can't read, such as a newer format version
+    UnsupportedFormat { segment: u32, reason: String },
+
+    /// Another process has the store open in a way that rules out opening
+    /// it like this: it is writing to it, or it is reading from it and this
+    /// open would write. `path` is the store's lock file.
+    Locked { path: PathBuf },
+
+    /// The store was opened read-only, so it can't be changed
+    ReadOnly,
+
+    /// The store is encrypted, but it was opened without a key
+    KeyRequired,
+
+    /// The record starting at `offset` in segment `segment` is encrypted
+    /// and couldn't be decrypted with any of the keys the store was opened
+    /// with. Its tag is all that vouches for it, so either the key is wrong
+    /// or the record has been damaged.
+    Decryption { segment: u32, offset: u64 },
+
+    /// A write was proposed to a Raft node that isn't the leader. `leader`
+    /// is the node it believes is, if it knows of one.
+    NotLeader { leader: Option<NodeId> },
+
+    /// The store was asked how it was as of write `sequence`, but compaction
+    /// has thrown away values it held then. It can only be viewed as of
+    /// write `whole_from` or later.
+    HistoryCompacted { sequence: u64, whole_from: u64 },
+
+    /// A follower was started on a store that already holds records, with
+    /// no saved position to show they came from its leader. Following
+    /// starts by emptying the store, so it only does that to a store that
+    /// isn't already in use when told to with `Follower::resetting`.
+    NotAReplica,
+
+    /// Any other failure reading or writing the underlying file
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The offset of the bad record, if the error is about the data
+    /// in the file rather than the file itself
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::ChecksumMismatch { offset, .. } => Some(*offset),
+            Error::TruncatedRecord { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ChecksumMismatch { segment, offset } => {
+                write!(f, "checksum mismatch in record at offset {} of segment {}", offset, segment)
+            },
+            Error::TruncatedRecord { segment, offset } => {
+                write!(f, "truncated record at offset {} of segment {}", offset, segment)
+            },
+            Error::MissingHeader { segment } => {
+                write!(
+                    f,
+                    "segment {} has no file header: it is not an ActionKV store, \
+                     or was written by an older version and needs upgrading (akv upgrade)",
+                    segment,
+                )
+            },
+            Error::UnsupportedFormat { segment, reason } => {
+                write!(f, "segment {} can't be read: {}", segment, reason)
+            },
+            Error::Locked { path } => {
+                write!(f, "store is locked by another process (lock file {})", path.display())
+            },
+            Error::ReadOnly => write!(f, "store was opened read-only"),
+            Error::KeyRequired => write!(f, "store is encrypted, and no key was given"),
+            Error::Decryption { segment, offset } => {
+                write!(f, "record at offset {} of segment {} couldn't be decrypted with the key given", offset, segment)
+            },
+            Error::NotLeader { leader: Some(leader) } => {
+                write!(f, "not the leader: writes go to node {}", leader)
+            },
+            Error::NotLeader { leader: None } => write!(f, "not the leader, and no leader is known"),
+            Error::HistoryCompacted { sequence, whole_from } => {
+                write!(
+                    f,
+                    "the store can't be viewed as of write {}: compaction has thrown away \
+                     history before write {}",
+                    sequence, whole_from,
+                )
+            },
+            Error::NotAReplica => {
+                write!(f, "the store already holds records that didn't come from the leader, and would be emptied")
+            },
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
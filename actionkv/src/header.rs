@@ -0,0 +1,223 @@
+//! The header at the start of every segment file.
+//!
+//! The header says that the file belongs to an ActionKV store, which
+//! version of the format its records are in, and how the store is set up.
+//! It is 16 bytes long, and the first record follows straight after it:
+//!
+//! ```text
+//! magic    version  checksum  compression  flags    crc
+//! [AKVS]   [ | ]    [ ]       [ ]          [ | | ]  [ | | ]
+//! 4 bytes  2 bytes  1 byte    1 byte       4 bytes  4 bytes
+//! ```
+//!
+//! The crc covers the 12 bytes before it. Files written before there were
+//! headers start straight away with a record, and have to be upgraded with
+//! `upgrade` (or `akv upgrade`) before they can be opened.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::compression::Compression;
+use crate::{hint, index_file, segment, store_file_path, sync_parent_dir};
+use crate::{ActionKV, Error, Location, Result};
+
+const MAGIC: &[u8; 4] = b"AKVS";
+
+/// The version of the record format written by this version of ActionKV
+pub const VERSION: u16 = 1;
+
+/// How many bytes the header takes up at the start of a segment file
+pub const LEN: u64 = 16;
+
+/// Set in the flags of a segment started by an encrypted store, so that it
+/// isn't opened without a key
+pub const ENCRYPTED: u32 = 0x1;
+
+/// How each record's checksum is worked out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    #[default]
+    Crc32,
+}
+
+/// The store-level settings recorded in every segment file's header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileHeader {
+    pub version: u16,
+    pub checksum: Checksum,
+    /// How the store compresses values. Each record says for itself whether
+    /// and how its value is compressed, so this is only the setting the
+    /// store had when the segment was started.
+    pub compression: Compression,
+    /// Store-wide flags, such as `ENCRYPTED`. A file with any others set was
+    /// written by a newer version and can't be read safely.
+    pub flags: u32,
+}
+
+impl FileHeader {
+    /// The header that new segment files of a store with the given
+    /// compression are written with
+    pub fn current(compression: Compression) -> Self {
+        FileHeader { version: VERSION, compression, ..FileHeader::default() }
+    }
+
+    pub(crate) fn write<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(LEN as usize);
+        buf.extend_from_slice(MAGIC);
+        buf.write_u16::<LittleEndian>(self.version)?;
+        buf.push(match self.checksum {
+            Checksum::Crc32 => 1,
+        });
+        buf.push(self.compression.id());
+        buf.write_u32::<LittleEndian>(self.flags)?;
+        buf.write_u32::<LittleEndian>(crc32::checksum_ieee(&buf))?;
+
+        f.write_all(&buf)
+    }
+
+    /// Whether the store was encrypted when the segment was started
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & ENCRYPTED != 0
+    }
+
+    /// Reads the header at the start of segment `segment`, checking that
+    /// this version of ActionKV can read the records after it
+    pub(crate) fn read<R: Read>(f: &mut R, segment: u32) -> Result<Self> {
+        let mut buf = [0u8; LEN as usize];
+        match crate::read_up_to(f, &mut buf)? {
+            n if n == buf.len() && &buf[..4] == MAGIC => {},
+            _ => return Err(Error::MissingHeader { segment }),
+        }
+
+        let unsupported = |reason: String| Error::UnsupportedFormat { segment, reason };
+
+        let mut fields = &buf[4..];
+        let version = fields.read_u16::<LittleEndian>()?;
+        let checksum = fields.read_u8()?;
+        let compression = fields.read_u8()?;
+        let flags = fields.read_u32::<LittleEndian>()?;
+        let saved_crc = fields.read_u32::<LittleEndian>()?;
+
+        if crc32::checksum_ieee(&buf[..12]) != saved_crc {
+            return Err(unsupported("the file header is corrupt".to_string()));
+        }
+        if version != VERSION {
+            return Err(unsupported(format!("format version {} is not supported", version)));
+        }
+
+        let checksum = match checksum {
+            1 => Checksum::Crc32,
+            other => return Err(unsupported(format!("checksum algorithm {} is not supported", other))),
+        };
+        let compression = match Compression::from_id(compression) {
+            Some(compression) => compression,
+            None => return Err(unsupported(format!("compression {} is not supported", compression))),
+        };
+        if flags & !ENCRYPTED != 0 {
+            return Err(unsupported(format!("store flags {:#x} are not supported", flags)));
+        }
+
+        Ok(FileHeader { version, checksum, compression, flags })
+    }
+}
+
+/// Whether `f` is shorter than a header and starts the way one does, as a
+/// file does when it was cut short while its header was being written.
+/// Leaves `f` back at the start.
+pub(crate) fn is_torn<F: Read + Seek>(f: &mut F) -> io::Result<bool> {
+    let mut buf = [0u8; LEN as usize];
+    f.rewind()?;
+    let n = crate::read_up_to(f, &mut buf)?;
+    f.rewind()?;
+
+    let magic = n.min(MAGIC.len());
+    Ok(n < buf.len() && buf[..magic] == MAGIC[..magic])
+}
+
+/// Gives every segment file of the store at `path` that was written before
+/// there were headers a header, returning how many needed one.
+///
+/// The records are checked first and then copied across unchanged after the
+/// header, into a new file that is renamed over the old one, so a file that
+/// doesn't hold valid records is left as it was. Hint and index files point
+/// at the old offsets, so they are removed and rebuilt on the next load.
+pub fn upgrade(path: &Path) -> Result<usize> {
+    let directory = path.is_dir();
+
+    // Upgrading is a write like any other, so it needs the store to itself
+    let _lock = crate::lock::acquire(&store_file_path(path, directory, "lock"), false)?;
+
+    let segments = match directory {
+        true => segment::list(path)?
+            .into_iter()
+            .map(|id| (id, segment::segment_path(path, id)))
+            .collect(),
+        false => vec![(0, path.to_path_buf())],
+    };
+
+    let mut upgraded = 0;
+    for (id, segment_path) in segments {
+        if upgrade_segment(id, &segment_path)? {
+            upgraded += 1;
+        }
+    }
+
+    if upgraded > 0 {
+        hint::remove(&store_file_path(path, directory, "hint"))?;
+        index_file::remove(&store_file_path(path, directory, "idx"))?;
+    }
+
+    Ok(upgraded)
+}
+
+/// Upgrades a single segment file, returning whether it needed it
+fn upgrade_segment(id: u32, path: &Path) -> Result<bool> {
+    let mut f = BufReader::new(File::open(path)?);
+    if f.get_ref().metadata()?.len() == 0 || is_torn(&mut f)? {
+        return Ok(false);
+    }
+    match FileHeader::read(&mut f, id) {
+        Ok(_) => return Ok(false),
+        Err(Error::MissingHeader { .. }) => f.rewind()?,
+        Err(err) => return Err(err),
+    }
+
+    // Only a file made up entirely of records is worth keeping. Files this
+    // old were never encrypted, so no keys are needed to read them.
+    loop {
+        let at = Location { segment: id, offset: f.stream_position()? };
+        if ActionKV::read_entry(&mut f, at, &[])?.is_none() {
+            break;
+        }
+    }
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(".upgrade");
+    let tmp_path = PathBuf::from(name);
+
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    {
+        let mut out = BufWriter::new(&mut tmp);
+        FileHeader::current(Compression::None).write(&mut out)?;
+        f.rewind()?;
+        io::copy(&mut f, &mut out)?;
+        out.flush()?;
+    }
+
+    tmp.sync_all()?;
+    drop(tmp);
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)?;
+
+    Ok(true)
+}
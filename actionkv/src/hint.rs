@@ -0,0 +1,183 @@
+//! Hint files let a store rebuild its index without reading every value.
+//!
+//! A hint file sits alongside the data file (`FILE.hint`), or inside a store
+//! directory (`DIR/store.hint`), and lists the location and length of the
+//! live record for each key as of the moment it was written. Records appended
+//! after that point are picked up by scanning just the tail of the store.
+//!
+//! Hint file format
+//!  magic   version  segment   end     count
+//! [ | | ]  [ | | ]  [ | | ]  [ .... ]  [ .... ]
+//! 4 bytes  4 bytes  4 bytes  8 bytes   8 bytes
+//!
+//! where `segment` and `end` are the location the hints cover up to,
+//! followed by `count` entries
+//! segment   offset   rec_len  expires   key_len    key
+//! [ | | ]  [ .... ]  [ | | ]  [ .... ]  [ | | ]  [........]
+//! 4 bytes  8 bytes   4 bytes  8 bytes   4 bytes  ..variable..
+//!
+//! where `expires` is when the key expires in milliseconds since the Unix
+//! epoch, or 0 if it never does, and a trailing CRC32 of everything before it.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::clock::{from_millis, to_millis};
+use crate::Location;
+
+const MAGIC: &[u8; 4] = b"AKVH";
+const VERSION: u32 = 3;
+
+/// Where one key's live record is in the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hint {
+    pub key: Vec<u8>,
+    pub location: Location,
+    pub record_len: u32,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// The contents of a valid hint file
+#[derive(Debug)]
+pub struct Hints {
+    /// How much of the store the hints describe
+    pub end: Location,
+    pub hints: Vec<Hint>,
+}
+
+impl Hints {
+    /// Checks the hints against the current size of each segment. They only
+    /// fit if everything they describe is still there.
+    pub fn fit(&self, sizes: &HashMap<u32, u64>) -> bool {
+        let fits_before_end = |location: Location| {
+            location <= self.end
+                && sizes.get(&location.segment).is_some_and(|size| location.offset <= *size)
+        };
+
+        fits_before_end(self.end)
+            && self.hints.iter().all(|h| {
+                let mut record_end = h.location;
+                record_end.offset += h.record_len as u64;
+                fits_before_end(record_end)
+            })
+    }
+}
+
+/// Writes a hint file covering the store up to `end`. The hints are written
+/// to a temporary file first and renamed into place, so a reader never sees
+/// a half-written hint file.
+pub fn write(path: &Path, end: Location, hints: &[Hint]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    buf.write_all(MAGIC)?;
+    buf.write_u32::<LittleEndian>(VERSION)?;
+    buf.write_u32::<LittleEndian>(end.segment)?;
+    buf.write_u64::<LittleEndian>(end.offset)?;
+    buf.write_u64::<LittleEndian>(hints.len() as u64)?;
+
+    for hint in hints {
+        buf.write_u32::<LittleEndian>(hint.location.segment)?;
+        buf.write_u64::<LittleEndian>(hint.location.offset)?;
+        buf.write_u32::<LittleEndian>(hint.record_len)?;
+        buf.write_u64::<LittleEndian>(hint.expires_at.map_or(0, to_millis))?;
+        buf.write_u32::<LittleEndian>(hint.key.len() as u32)?;
+        buf.write_all(&hint.key)?;
+    }
+
+    let checksum = crc32::checksum_ieee(&buf);
+    buf.write_u32::<LittleEndian>(checksum)?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    {
+        let mut f = BufWriter::new(&mut tmp);
+        f.write_all(&buf)?;
+        f.flush()?;
+    }
+    tmp.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Reads a hint file. Returns `None` if there is no hint file, or if it is
+/// damaged or was written in an older format.
+pub fn read(path: &Path) -> io::Result<Option<Hints>> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    Ok(parse(&buf))
+}
+
+/// Deletes a hint file, if there is one
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn parse(buf: &[u8]) -> Option<Hints> {
+    if buf.len() < 4 {
+        return None;
+    }
+
+    let (body, mut footer) = buf.split_at(buf.len() - 4);
+    let saved_checksum = footer.read_u32::<LittleEndian>().ok()?;
+    if crc32::checksum_ieee(body) != saved_checksum {
+        return None;
+    }
+
+    let mut f = body;
+    let mut magic = [0u8; 4];
+    f.read_exact(&mut magic).ok()?;
+    if &magic != MAGIC || f.read_u32::<LittleEndian>().ok()? != VERSION {
+        return None;
+    }
+
+    let end = Location {
+        segment: f.read_u32::<LittleEndian>().ok()?,
+        offset: f.read_u64::<LittleEndian>().ok()?,
+    };
+    let count = f.read_u64::<LittleEndian>().ok()?;
+
+    let mut hints = Vec::new();
+    for _ in 0..count {
+        let location = Location {
+            segment: f.read_u32::<LittleEndian>().ok()?,
+            offset: f.read_u64::<LittleEndian>().ok()?,
+        };
+        let record_len = f.read_u32::<LittleEndian>().ok()?;
+        let expires_at = match f.read_u64::<LittleEndian>().ok()? {
+            0 => None,
+            millis => Some(from_millis(millis)),
+        };
+        let key_len = f.read_u32::<LittleEndian>().ok()? as usize;
+
+        if f.len() < key_len {
+            return None;
+        }
+        let (key, rest) = f.split_at(key_len);
+        f = rest;
+
+        hints.push(Hint { key: key.to_vec(), location, record_len, expires_at });
+    }
+
+    Some(Hints { end, hints })
+}
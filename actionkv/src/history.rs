@@ -0,0 +1,112 @@
+//! Looking back at the values keys used to have
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::{ActionKV, Error, Location, Result};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// One of the values a key has had, from `ActionKV::history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Where the write stands in the log: the first write to the store is
+    /// 1, the next 2, and so on. The records of a batch share one sequence
+    /// number, since they were written together.
+    ///
+    /// Compaction throws old writes away, but the ones it keeps carry their
+    /// numbers with them, and later writes carry on counting from the last
+    /// write before it. Emptying a store, as a follower does when it starts
+    /// over from its leader, counts from 1 again.
+    pub sequence: u64,
+    pub location: Location,
+    /// The value the key was given, or `None` where it was deleted
+    pub value: Option<ByteString>,
+    pub expires_at: Option<SystemTime>,
+    /// When the write happened, for records stamped with the time
+    pub written_at: Option<SystemTime>,
+}
+
+/// The store as it was just after a given write, from `ActionKV::snapshot_at`.
+///
+/// The view reads values from the store's files, so it borrows the store
+/// for as long as it is in use.
+pub struct PointInTime<'a> {
+    store: &'a mut ActionKV,
+    sequence: u64,
+    /// When the last write in the view happened, if it was stamped
+    as_of: Option<SystemTime>,
+    /// Where the value each key had is, and when it expires
+    index: HashMap<ByteString, (Location, Option<SystemTime>)>,
+}
+
+impl<'a> PointInTime<'a> {
+    /// Replays the store's log up to and including write `sequence`, so
+    /// long as compaction hasn't thrown any of the values it had away
+    pub(crate) fn new(store: &'a mut ActionKV, sequence: u64) -> Result<Self> {
+        let mut index = HashMap::new();
+        let mut as_of = None;
+
+        let whole_from = store.for_each_record(|seq, location, kv| {
+            if seq > sequence {
+                return;
+            }
+
+            as_of = kv.written_at.or(as_of);
+            match kv.tombstone {
+                true => index.remove(&kv.key),
+                false => index.insert(kv.key, (location, kv.expires_at)),
+            };
+        })?;
+
+        if sequence < whole_from {
+            return Err(Error::HistoryCompacted { sequence, whole_from });
+        }
+        Ok(PointInTime { store, sequence, as_of, index })
+    }
+
+    /// The last write the view includes
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// When the last write the view includes happened, if it is known
+    pub fn as_of(&self) -> Option<SystemTime> {
+        self.as_of
+    }
+
+    /// Gets the value `key` had at that point. A key with a time to live
+    /// counts as absent once it had run out by then, which can only be told
+    /// when the writes were stamped with the time.
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        if !self.contains_key(key) {
+            return Ok(None);
+        }
+
+        let (location, _) = self.index[key];
+        Ok(Some(self.store.get_at(location)?.value))
+    }
+
+    /// Whether `key` had a live value at that point
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        match self.index.get(key) {
+            None => false,
+            Some((_, expires_at)) => match (expires_at, self.as_of) {
+                (Some(expires_at), Some(as_of)) => *expires_at > as_of,
+                _ => true,
+            },
+        }
+    }
+
+    /// The keys that had live values at that point, in key order
+    pub fn keys(&self) -> Vec<ByteString> {
+        let mut keys: Vec<ByteString> = self.index
+            .keys()
+            .filter(|key| self.contains_key(key))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+}
@@ -0,0 +1,230 @@
+//! The in-memory index from each live key to the location of its record
+
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+use crate::Location;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Maps each live key to where its latest record is stored.
+///
+/// A hashed index is the quickest for looking up single keys. An ordered
+/// index keeps the keys sorted, so range and prefix scans can walk through
+/// them without sorting every key first.
+#[derive(Debug, Clone)]
+pub enum KeyIndex {
+    Hashed(HashMap<ByteString, Location>),
+    Ordered(BTreeMap<ByteString, Location>),
+}
+
+impl KeyIndex {
+    pub fn new(ordered: bool) -> Self {
+        match ordered {
+            true => KeyIndex::Ordered(BTreeMap::new()),
+            false => KeyIndex::Hashed(HashMap::new()),
+        }
+    }
+
+    pub fn is_ordered(&self) -> bool {
+        matches!(self, KeyIndex::Ordered(_))
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Option<&Location> {
+        match self {
+            KeyIndex::Hashed(map) => map.get(key),
+            KeyIndex::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: ByteString, location: Location) -> Option<Location> {
+        match self {
+            KeyIndex::Hashed(map) => map.insert(key, location),
+            KeyIndex::Ordered(map) => map.insert(key, location),
+        }
+    }
+
+    pub fn remove(&mut self, key: &ByteStr) -> Option<Location> {
+        match self {
+            KeyIndex::Hashed(map) => map.remove(key),
+            KeyIndex::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            KeyIndex::Hashed(map) => map.len(),
+            KeyIndex::Ordered(map) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            KeyIndex::Hashed(map) => map.clear(),
+            KeyIndex::Ordered(map) => map.clear(),
+        }
+    }
+
+    /// Iterates over every key and its location. Only an ordered index
+    /// yields them in key order.
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            KeyIndex::Hashed(map) => Iter::Hashed(map.iter()),
+            KeyIndex::Ordered(map) => Iter::Ordered(map.iter()),
+        }
+    }
+
+    /// The keys within `(lower, upper)` and their locations, in key order.
+    /// An ordered index seeks straight to `lower`, whereas a hashed index
+    /// has to gather and sort the matching keys up front.
+    pub fn range(&self, lower: Bound<&ByteStr>, upper: Bound<&ByteStr>) -> Range<'_> {
+        let bounds = (lower.map(<[u8]>::to_vec), upper.map(<[u8]>::to_vec));
+        if is_empty_range(&bounds.0, &bounds.1) {
+            return Range::Sorted(Vec::new().into_iter());
+        }
+
+        match self {
+            KeyIndex::Ordered(map) => Range::Ordered(map.range::<ByteStr, _>((lower, upper))),
+            KeyIndex::Hashed(map) => {
+                let mut keys: Vec<_> = map.iter().filter(|(key, _)| bounds.contains(*key)).collect();
+                keys.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                Range::Sorted(keys.into_iter())
+            },
+        }
+    }
+
+    /// The first key within `(lower, upper)`, along with its location
+    pub(crate) fn first_in(
+        &self,
+        lower: &Bound<ByteString>,
+        upper: &Bound<ByteString>,
+    ) -> Option<(ByteString, Location)> {
+        if is_empty_range(lower, upper) {
+            return None;
+        }
+
+        match self {
+            KeyIndex::Ordered(map) => map
+                .range::<ByteString, _>((lower.clone(), upper.clone()))
+                .next()
+                .map(|(key, location)| (key.clone(), *location)),
+            KeyIndex::Hashed(map) => map
+                .iter()
+                .filter(|(key, _)| (lower.as_ref(), upper.as_ref()).contains(*key))
+                .min_by(|a, b| a.0.cmp(b.0))
+                .map(|(key, location)| (key.clone(), *location)),
+        }
+    }
+
+    /// Every key within `(lower, upper)` with its location, in key order
+    pub(crate) fn sorted_in(
+        &self,
+        lower: &Bound<ByteString>,
+        upper: &Bound<ByteString>,
+    ) -> Vec<(ByteString, Location)> {
+        if is_empty_range(lower, upper) {
+            return Vec::new();
+        }
+
+        let range = (lower.as_ref(), upper.as_ref());
+        let mut keys: Vec<(ByteString, Location)> = self
+            .iter()
+            .filter(|(key, _)| range.contains(*key))
+            .map(|(key, location)| (key.clone(), *location))
+            .collect();
+
+        if !self.is_ordered() {
+            keys.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        }
+        keys
+    }
+}
+
+impl Default for KeyIndex {
+    fn default() -> Self {
+        KeyIndex::new(false)
+    }
+}
+
+impl std::ops::Index<&ByteStr> for KeyIndex {
+    type Output = Location;
+
+    fn index(&self, key: &ByteStr) -> &Location {
+        self.get(key).expect("key not in index")
+    }
+}
+
+impl Extend<(ByteString, Location)> for KeyIndex {
+    fn extend<T: IntoIterator<Item = (ByteString, Location)>>(&mut self, iter: T) {
+        match self {
+            KeyIndex::Hashed(map) => map.extend(iter),
+            KeyIndex::Ordered(map) => map.extend(iter),
+        }
+    }
+}
+
+pub enum Iter<'a> {
+    Hashed(hash_map::Iter<'a, ByteString, Location>),
+    Ordered(btree_map::Iter<'a, ByteString, Location>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a ByteString, &'a Location);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Hashed(iter) => iter.next(),
+            Iter::Ordered(iter) => iter.next(),
+        }
+    }
+}
+
+pub enum Range<'a> {
+    Ordered(btree_map::Range<'a, ByteString, Location>),
+    Sorted(std::vec::IntoIter<(&'a ByteString, &'a Location)>),
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (&'a ByteString, &'a Location);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Range::Ordered(range) => range.next(),
+            Range::Sorted(keys) => keys.next(),
+        }
+    }
+}
+
+/// The smallest key greater than every key that starts with `prefix`, or
+/// `None` if there isn't one (e.g. the prefix is empty or all 0xff bytes)
+pub(crate) fn prefix_end(prefix: &ByteStr) -> Option<ByteString> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Whether no key could fall within `(lower, upper)`. BTreeMap panics if
+/// asked for a range like that, rather than returning nothing.
+fn is_empty_range(lower: &Bound<ByteString>, upper: &Bound<ByteString>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l), Bound::Excluded(u))
+        | (Bound::Excluded(l), Bound::Included(u))
+        | (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+        _ => false,
+    }
+}
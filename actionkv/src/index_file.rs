@@ -0,0 +1,293 @@
+//! A persistent copy of the index, kept in its own file next to the data
+//! file (`FILE.idx`), or inside a store directory (`DIR/store.idx`).
+//!
+//! The index file is a log of its own: every write to the data file that
+//! changes the index appends a small entry here, so keeping it up to date
+//! costs the same no matter how many keys there are. Opening a store replays
+//! the entries instead of reading the data file.
+//!
+//! Index file format
+//!  magic   version    base
+//! [ | | ]  [ | | ]  [ ...... ]
+//! 4 bytes  4 bytes   12 bytes
+//!
+//! followed by one entry per write
+//! checksum  op   key_len  location   expires     end        key
+//! [ | | ]  [ ]  [ | | ]  [ ...... ]  [ .... ]  [ ...... ]  [........]
+//! 4 bytes 1 byte 4 bytes  12 bytes   8 bytes    12 bytes  ..variable..
+//!
+//! Locations are written as a 4 byte segment id followed by an 8 byte
+//! offset. `expires` is when a key with a time to live expires, in
+//! milliseconds since the Unix epoch, or 0 if it never does. `end` is where the store ended once the write had finished, and
+//! `base` is where it ended when the index file was last rewritten from
+//! scratch. Together they say how much of the store the index describes.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::clock::{from_millis, to_millis};
+use crate::{KeyIndex, Location};
+
+const MAGIC: &[u8; 4] = b"AKVI";
+const VERSION: u32 = 3;
+const HEADER_LEN: u64 = 20;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// The index as it was rebuilt from the entries in the index file
+#[derive(Debug)]
+pub struct Replay {
+    pub index: HashMap<Vec<u8>, Location>,
+    /// When each key that has a time to live expires
+    pub expiries: HashMap<Vec<u8>, SystemTime>,
+    /// How much of the store the index describes
+    pub end: Location,
+}
+
+#[derive(Debug)]
+pub struct IndexFile {
+    f: File,
+    path: PathBuf,
+}
+
+/// Deletes an index file, if there is one
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Replays the index file at `path` without changing it, for a store that
+/// is only being read. The replay is `None` if there is no usable index file.
+pub fn read(path: &Path) -> io::Result<Option<Replay>> {
+    match fs::read(path) {
+        Ok(buf) => Ok(replay(&buf).map(|(replay, _)| replay)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl IndexFile {
+    /// Opens the index file at `path` and replays it. The replay is `None`
+    /// if there was no usable index file, in which case the caller has to
+    /// rebuild the index and `rewrite` it.
+    pub fn open(path: &Path) -> io::Result<(IndexFile, Option<Replay>)> {
+        let path = path.to_path_buf();
+        let mut f = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&path)?;
+
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+
+        let replay = match replay(&buf) {
+            Some((replay, good_len)) => {
+                // Drop a torn entry from the end, so new entries
+                // are appended after the last good one
+                if good_len < buf.len() as u64 {
+                    f.set_len(good_len)?;
+                }
+                Some(replay)
+            },
+            None => None,
+        };
+
+        Ok((IndexFile { f, path }, replay))
+    }
+
+    /// Records that the live value for `key` is now at `location`, and when
+    /// it expires if it has a time to live
+    pub fn append_put(
+        &mut self,
+        key: &[u8],
+        location: Location,
+        expires_at: Option<SystemTime>,
+        end: Location,
+    ) -> io::Result<()> {
+        self.append(OP_PUT, key, location, expires_at, end)
+    }
+
+    /// Records that `key` has been deleted
+    pub fn append_delete(&mut self, key: &[u8], end: Location) -> io::Result<()> {
+        self.append(OP_DELETE, key, Location::default(), None, end)
+    }
+
+    fn append(
+        &mut self,
+        op: u8,
+        key: &[u8],
+        location: Location,
+        expires_at: Option<SystemTime>,
+        end: Location,
+    ) -> io::Result<()> {
+        let entry = encode_entry(op, key, location, expires_at, end)?;
+        self.f.write_all(&entry)
+    }
+
+    /// Hands any buffered entries over to the operating system
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()
+    }
+
+    /// A separate handle to the index file, e.g. for syncing it to disk
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        self.f.try_clone()
+    }
+
+    /// Replaces the whole index file with one describing `index` and the
+    /// expiry times in `expiries`, which covers the store up to `end`
+    pub fn rewrite(
+        &mut self,
+        index: &KeyIndex,
+        expiries: &HashMap<Vec<u8>, SystemTime>,
+        end: Location,
+    ) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        {
+            let mut f = BufWriter::new(&mut tmp);
+            f.write_all(MAGIC)?;
+            f.write_u32::<LittleEndian>(VERSION)?;
+            write_location(&mut f, end)?;
+
+            for (key, location) in index.iter() {
+                let expires_at = expiries.get(key).copied();
+                f.write_all(&encode_entry(OP_PUT, key, *location, expires_at, end)?)?;
+            }
+            f.flush()?;
+        }
+        tmp.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+        self.f = OpenOptions::new().read(true).append(true).open(&self.path)?;
+
+        Ok(())
+    }
+}
+
+fn write_location<W: Write>(f: &mut W, location: Location) -> io::Result<()> {
+    f.write_u32::<LittleEndian>(location.segment)?;
+    f.write_u64::<LittleEndian>(location.offset)
+}
+
+fn read_location<R: Read>(f: &mut R) -> io::Result<Location> {
+    let segment = f.read_u32::<LittleEndian>()?;
+    let offset = f.read_u64::<LittleEndian>()?;
+    Ok(Location { segment, offset })
+}
+
+fn encode_entry(
+    op: u8,
+    key: &[u8],
+    location: Location,
+    expires_at: Option<SystemTime>,
+    end: Location,
+) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(37 + key.len());
+    body.write_u8(op)?;
+    body.write_u32::<LittleEndian>(key.len() as u32)?;
+    write_location(&mut body, location)?;
+    body.write_u64::<LittleEndian>(expires_at.map_or(0, to_millis))?;
+    write_location(&mut body, end)?;
+    body.write_all(key)?;
+
+    let mut entry = Vec::with_capacity(4 + body.len());
+    entry.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+    entry.write_all(&body)?;
+
+    Ok(entry)
+}
+
+/// Rebuilds the index from the contents of an index file, returning it
+/// along with how many bytes of the file held good entries. Reading stops
+/// at the first entry that is torn or damaged.
+fn replay(buf: &[u8]) -> Option<(Replay, u64)> {
+    let mut f = buf;
+    let mut magic = [0u8; 4];
+    f.read_exact(&mut magic).ok()?;
+    if &magic != MAGIC || f.read_u32::<LittleEndian>().ok()? != VERSION {
+        return None;
+    }
+
+    let mut end = read_location(&mut f).ok()?;
+    let mut index = HashMap::new();
+    let mut expiries = HashMap::new();
+    let mut good_len = HEADER_LEN;
+
+    while let Some((entry, rest)) = next_entry(f) {
+        match entry.op {
+            OP_PUT => {
+                match entry.expires_at {
+                    Some(expires_at) => expiries.insert(entry.key.to_vec(), expires_at),
+                    None => expiries.remove(entry.key),
+                };
+                index.insert(entry.key.to_vec(), entry.location);
+            },
+            OP_DELETE => {
+                expiries.remove(entry.key);
+                index.remove(entry.key);
+            },
+            _ => break,
+        }
+
+        end = end.max(entry.end);
+        good_len += (f.len() - rest.len()) as u64;
+        f = rest;
+    }
+
+    Some((Replay { index, expiries, end }, good_len))
+}
+
+struct Entry<'a> {
+    op: u8,
+    key: &'a [u8],
+    location: Location,
+    expires_at: Option<SystemTime>,
+    end: Location,
+}
+
+/// Splits the next whole, undamaged entry off the front of `buf`
+fn next_entry(buf: &[u8]) -> Option<(Entry<'_>, &[u8])> {
+    let mut f = buf;
+    let saved_checksum = f.read_u32::<LittleEndian>().ok()?;
+    let body = f;
+
+    let op = f.read_u8().ok()?;
+    let key_len = f.read_u32::<LittleEndian>().ok()? as usize;
+    let location = read_location(&mut f).ok()?;
+    let expires_at = match f.read_u64::<LittleEndian>().ok()? {
+        0 => None,
+        millis => Some(from_millis(millis)),
+    };
+    let end = read_location(&mut f).ok()?;
+
+    if f.len() < key_len {
+        return None;
+    }
+    let (key, rest) = f.split_at(key_len);
+
+    let body = &body[..body.len() - rest.len()];
+    if crc32::checksum_ieee(body) != saved_checksum {
+        return None;
+    }
+
+    Some((Entry { op, key, location, expires_at, end }, rest))
+}
@@ -1,258 +1,4099 @@
-/// A recreation of a key-value database store.
-/// This library file denotes the writing of the data to files
+//! A recreation of a key-value database store.
+//! This library file denotes the writing of the data to files
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, SeekFrom };
-use std::path::Path;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32;
 use serde::{Deserialize, Serialize};
 
+use index_file::IndexFile;
+use segment::Segment;
+use sync::SyncState;
+
+mod backup;
+mod batch;
+mod clock;
+mod compression;
+mod crypto;
+mod error;
+mod header;
+mod hint;
+mod history;
+mod index;
+mod index_file;
+mod lock;
+mod raft;
+mod replication;
+mod scan;
+mod segment;
+mod shared;
+mod snapshot;
+mod stats;
+mod sync;
+
+pub use backup::restore;
+pub use batch::WriteBatch;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use error::{Error, Result};
+pub use compression::Compression;
+pub use crypto::EncryptionKey;
+pub use header::{upgrade, Checksum, FileHeader};
+pub use history::{PointInTime, Version};
+pub use index::KeyIndex;
+pub use raft::{Command, Envelope, LogEntry, Message, Network, NodeId, RaftConfig, RaftNode, Role};
+pub use replication::{serve_log, Follower, ReplicationStatus};
+pub use scan::Scan;
+pub use shared::SharedStore;
+pub use snapshot::Snapshot;
+pub use stats::{SizeBucket, Stats};
+pub use sync::SyncPolicy;
+
 type ByteString = Vec<u8>;
 type ByteStr = [u8];
 
+/// Marks a record as a deletion of its key rather than a new value
+pub const FLAG_TOMBSTONE: u8 = 0x01;
+
+/// Marks a record as a batch frame: it has no key, and its value is the
+/// records of a `WriteBatch` one after another. The frame's checksum covers
+/// all of them, so they are applied together or not at all.
+pub const FLAG_BATCH: u8 = 0x02;
+
+/// Marks a record whose key expires. The header is followed by an 8 byte
+/// expiry time, in milliseconds since the Unix epoch, which the checksum
+/// covers along with the key and value.
+pub const FLAG_EXPIRES: u8 = 0x04;
+
+/// Marks a record that carries the time it was written. The header is
+/// followed by an 8 byte timestamp, in milliseconds since the Unix epoch,
+/// after the expiry time if there is one. Every record is stamped now, but
+/// records written before timestamps existed read back without one.
+pub const FLAG_TIMESTAMP: u8 = 0x08;
+
+/// Marks a record whose value was compressed with LZ4
+pub const FLAG_LZ4: u8 = 0x10;
+
+/// Marks a record whose value was compressed with zstd
+pub const FLAG_ZSTD: u8 = 0x20;
+
+/// Marks a record whose key and value are encrypted. They are stored after
+/// the expiry and write times and a nonce, and the record is checked by its
+/// authentication tag rather than a CRC32.
+pub const FLAG_ENCRYPTED: u8 = 0x40;
+
+/// Marks an entry that carries its sequence number (see `Version`) in 8
+/// bytes after its expiry and write times. An entry without one is the
+/// write after the entry before it. Compaction numbers the records it keeps
+/// like this where there are gaps between them, and ends each segment it
+/// rewrites with a mark: an empty batch frame that carries a number.
+pub const FLAG_SEQUENCE: u8 = 0x80;
+
+/// The size of the expiry time that follows the header of a FLAG_EXPIRES record
+const EXPIRY_LEN: u64 = 8;
+
+/// The size of the write time that follows the header of a FLAG_TIMESTAMP record
+const TIMESTAMP_LEN: u64 = 8;
+
+/// The size of the sequence number that follows the header of a FLAG_SEQUENCE entry
+const SEQUENCE_LEN: u64 = 8;
+
+/// The key length shares its 4 bytes with the record flags, which live in
+/// the most significant byte
+const KEY_LEN_MASK: u32 = 0x00ff_ffff;
+
+/// The size of the fixed part of every record: checksum, key_len and val_len
+pub(crate) const HEADER_LEN: u64 = 12;
+
+/// How many bytes of keys and values `import` sorts at a time
+pub const IMPORT_RUN_LEN: usize = 64 * 1024 * 1024;
+
+/// The records in an entry, along with where each one starts
+type EntryRecords = Vec<(Location, KeyValuePair)>;
+
 /// BitCask file format
 /// checksum  key_len  val_len    key       val
 ///  [ | | ]  [ | | ]  [ | | ]  [........][.........]
-///  3 bytes  3 bytes  3 bytes   ..variable bytes..  
-
+///  3 bytes  3 bytes  3 bytes   ..variable bytes..
+///
+/// The top byte of key_len holds the record flags (e.g. FLAG_TOMBSTONE),
+/// so files written before flags existed read back with no flags set.
+/// Records with FLAG_EXPIRES have their expiry time between the header
+/// and the key, followed by their write time if they have FLAG_TIMESTAMP,
+/// and their sequence number if they have FLAG_SEQUENCE.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
+    /// Set when the record deletes the key. Tombstones are dead records:
+    /// they only exist to hide the older values of their key.
+    #[serde(default)]
+    pub tombstone: bool,
+    /// When the value stops being live, if it was written with a time to
+    /// live. Once expired, the record hides older values like a tombstone.
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+    /// When the record was written, if it was stamped with the time
+    #[serde(default)]
+    pub written_at: Option<SystemTime>,
+}
+
+impl KeyValuePair {
+    /// Whether the record only deletes its key as of `now`, either because
+    /// it is a tombstone or because its value has expired
+    pub fn is_dead_at(&self, now: SystemTime) -> bool {
+        self.tombstone || self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Where a record is stored: the segment file it is in, and how far into
+/// that file it starts. A store kept in a single file only has segment 0.
+///
+/// Locations order the same way the records were written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Location {
+    pub segment: u32,
+    pub offset: u64,
+}
+
+/// What `load` does when it comes across a record that is corrupt or
+/// was only partly written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Recovery {
+    /// Stop loading and return the error
+    #[default]
+    Fail,
+    /// Leave the bad record where it is and carry on with the next one.
+    /// A truncated record can only be the last one in its segment and has
+    /// to be cut off, since anything appended after it would be swallowed
+    /// up as part of it.
+    Skip,
+    /// Cut the segment off at the first bad record, losing everything from
+    /// that record onwards. For a store kept in a single file, that is
+    /// everything after it in the file.
+    Truncate,
+}
+
+/// Settings that control how a store is opened and loaded
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub recovery: Recovery,
+    /// Keep the index in a separate file that is updated on every write,
+    /// rather than rebuilding it from the data file or hints on load
+    pub persistent_index: bool,
+    /// How large the active segment of a directory store may grow before
+    /// a new one is started. Stores kept in a single file never rotate.
+    pub segment_size: u64,
+    /// Keep the index sorted by key, which makes `scan` and `scan_prefix`
+    /// cheaper at the cost of slightly slower lookups
+    pub ordered_index: bool,
+    /// Where the current time comes from when deciding whether keys have
+    /// expired
+    pub clock: Arc<dyn Clock>,
+    /// How often writes are synced to disk
+    pub sync: SyncPolicy,
+    /// How values larger than `compression_threshold` are compressed. Values
+    /// are decompressed whatever this is set to, so it can be changed freely.
+    pub compression: Compression,
+    /// How large a value has to be, in bytes, before it is compressed
+    pub compression_threshold: usize,
+    /// Open the store only to read it. Nothing in the store is ever written,
+    /// so `load` leaves bad records, stale hints and index files as they are,
+    /// and every write fails with `Error::ReadOnly`.
+    pub read_only: bool,
+    /// Encrypt the key and value of every record written from now on with
+    /// this key. Records already in the store are read whatever this is, as
+    /// long as they were written with this key or `previous_encryption_key`,
+    /// so `rekey` is what encrypts a store that was written in the clear.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Another key to try for records that don't decrypt with
+    /// `encryption_key`, such as those left behind by a `rekey` that
+    /// didn't finish
+    pub previous_encryption_key: Option<EncryptionKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            recovery: Recovery::default(),
+            persistent_index: false,
+            segment_size: 64 * 1024 * 1024,
+            ordered_index: false,
+            clock: Arc::new(SystemClock),
+            sync: SyncPolicy::default(),
+            compression: Compression::default(),
+            compression_threshold: 1024,
+            read_only: false,
+            encryption_key: None,
+            previous_encryption_key: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ActionKV {
-    f: File,
-    pub index: HashMap<ByteString, u64>
+    /// The data file, or the directory holding the segment files
+    path: PathBuf,
+    /// Whether the store is a directory of segment files
+    directory: bool,
+    /// The segment files, oldest first. The last one is the active segment
+    /// that records are appended to.
+    segments: Vec<Segment>,
+    options: Options,
+    /// How much of the store the hint file on disk describes, once the
+    /// index has been loaded
+    hinted: Option<Location>,
+    /// The persistent copy of the index, once it has been loaded
+    index_file: Option<IndexFile>,
+    /// When each key in the index that has a time to live expires
+    expiries: HashMap<ByteString, SystemTime>,
+    /// The writes made since the last sync
+    sync_state: SyncState,
+    /// The store's lock file, which holds the lock for as long as it is open
+    _lock: File,
+    /// The keys encrypted records are decrypted with, in the order they are
+    /// tried
+    keys: Vec<EncryptionKey>,
+    /// How many times records have been moved about by compaction, or the
+    /// store emptied, so that anyone holding on to a location can tell
+    /// that it might not be valid any more
+    pub(crate) generation: u64,
+    pub index: KeyIndex
 }
 
 impl ActionKV {
     /// Opens (or creates) the file at the specified path to be read from, and
     /// initializes the index
-    pub fn open (path: &Path) -> io::Result<Self> {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)?;
-        let index = HashMap::new();
-        Ok(ActionKV { f, index })
+    pub fn open (path: &Path) -> Result<Self> {
+        ActionKV::open_with(path, Options::default())
+    }
+
+    /// Opens (or creates) the file at the specified path with the given
+    /// options, and initializes the index.
+    ///
+    /// Fails with `Error::Locked` if another process is writing to the
+    /// store, or if it is reading from it and this open is for writing, and
+    /// with `Error::KeyRequired` if the store is encrypted but no key was
+    /// given.
+    pub fn open_with(path: &Path, options: Options) -> Result<Self> {
+        // Nothing is written, not even a new file's header, until the lock is held
+        let lock = lock::acquire(&store_file_path(path, false, "lock"), options.read_only)?;
+        let segment = match options.read_only {
+            true => Segment::open_read_only(0, path.to_path_buf())?,
+            false => Segment::open(0, path.to_path_buf(), new_file_header(&options))?,
+        };
+        check_encryption(std::slice::from_ref(&segment), &options)?;
+        Ok(ActionKV::new(path, false, vec![segment], options, lock))
+    }
+
+    /// Opens an existing file only to read it. Other processes can read it
+    /// at the same time, but none can write to it while it is open.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let options = Options { read_only: true, ..Options::default() };
+        ActionKV::open_with(path, options)
+    }
+
+    /// Opens (or creates) the file at the specified path, keeping the index
+    /// in its own index file alongside it (`FILE.idx`)
+    pub fn open_with_persistent_index(path: &Path) -> Result<Self> {
+        let options = Options { persistent_index: true, ..Options::default() };
+        ActionKV::open_with(path, options)
+    }
+
+    /// Opens (or creates) a store kept in a directory of segment files.
+    /// Records are appended to the newest segment until it reaches
+    /// `options.segment_size`, when a new segment is started.
+    pub fn open_dir(path: &Path, options: Options) -> Result<Self> {
+        if !options.read_only {
+            fs::create_dir_all(path)?;
+        }
+        let lock = lock::acquire(&store_file_path(path, true, "lock"), options.read_only)?;
+        for purpose in ["clear", "restore"] {
+            finish_replacing(path, purpose, options.read_only)?;
+        }
+
+        let mut ids = segment::list(path)?;
+        if ids.is_empty() {
+            ids.push(0);
+        }
+
+        let mut segments = Vec::with_capacity(ids.len());
+        for id in ids {
+            let segment_path = segment::segment_path(path, id);
+            segments.push(match options.read_only {
+                true => Segment::open_read_only(id, segment_path)?,
+                false => Segment::open(id, segment_path, new_file_header(&options))?,
+            });
+        }
+        check_encryption(&segments, &options)?;
+
+        Ok(ActionKV::new(path, true, segments, options, lock))
+    }
+
+    fn new(path: &Path, directory: bool, segments: Vec<Segment>, options: Options, lock: File) -> Self {
+        let keys = options.encryption_key
+            .iter()
+            .chain(&options.previous_encryption_key)
+            .cloned()
+            .collect();
+
+        ActionKV {
+            path: path.to_path_buf(),
+            directory,
+            segments,
+            index: KeyIndex::new(options.ordered_index),
+            sync_state: SyncState::new(options.sync),
+            options,
+            hinted: None,
+            index_file: None,
+            expiries: HashMap::new(),
+            _lock: lock,
+            keys,
+            generation: 0,
+        }
+    }
+
+    pub(crate) fn store_file_path(&self, extension: &str) -> PathBuf {
+        store_file_path(&self.path, self.directory, extension)
+    }
+
+    /// Fails with `Error::ReadOnly` if the store was opened read-only
+    fn check_writable(&self) -> Result<()> {
+        match self.options.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    fn hint_path(&self) -> PathBuf {
+        self.store_file_path("hint")
+    }
+
+    fn index_file_path(&self) -> PathBuf {
+        self.store_file_path("idx")
+    }
+
+    /// The segment that records are currently appended to
+    fn active_segment(&mut self) -> &mut Segment {
+        self.segments.last_mut().expect("a store always has an active segment")
+    }
+
+    /// Where in `segments` the segment with the given id is
+    fn segment_position(&self, id: u32) -> Result<usize> {
+        self.segments
+            .binary_search_by_key(&id, |segment| segment.id)
+            .map_err(|_| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("segment {} does not exist", id),
+            ).into())
+    }
+
+    /// The current size of every segment, by id
+    fn segment_sizes(&mut self) -> Result<HashMap<u32, u64>> {
+        let mut sizes = HashMap::with_capacity(self.segments.len());
+        for segment in &mut self.segments {
+            sizes.insert(segment.id, segment.size()?);
+        }
+        Ok(sizes)
+    }
+
+    /// The location just past the last record in the store
+    fn end(&mut self) -> Result<Location> {
+        let segment = self.active_segment();
+        Ok(Location { segment: segment.id, offset: segment.size()? })
+    }
+
+    /// The location of the first record in the store, just after the
+    /// oldest segment's header
+    fn start(&self) -> Location {
+        Location { segment: self.segments[0].id, offset: header::LEN }
     }
 
     /// Loads an entry from the file wherever it happens to be reading
-    /// from the file at that point in time.
-    fn process_record<R: Read>( f: &mut R ) -> io::Result<KeyValuePair> {
+    /// from the file at that point in time. `at` is the location the
+    /// record starts at, which is reported back in any errors, and `keys`
+    /// are the keys to try if the record is encrypted.
+    /// Returns `None` if the reader is already at the end of the file.
+    fn process_record<R: Read>(
+        f: &mut R,
+        at: Location,
+        keys: &[EncryptionKey]
+    ) -> Result<Option<KeyValuePair>> {
+        Ok(ActionKV::read_record(f, at, keys)?.map(|(_, _, kv)| kv))
+    }
+
+    /// Reads the next entry in the file, which is either a single record or
+    /// a batch frame, and returns the records in it along with where each
+    /// one starts. Returns `None` if the reader is already at the end of the
+    /// file.
+    fn read_entry<R: Read>(
+        f: &mut R,
+        at: Location,
+        keys: &[EncryptionKey]
+    ) -> Result<Option<EntryRecords>> {
+        Ok(ActionKV::read_numbered_entry(f, at, keys)?.map(|(_, records)| records))
+    }
+
+    /// Reads the next entry in the file as `read_entry` does, along with
+    /// its sequence number if it carries one
+    fn read_numbered_entry<R: Read>(
+        f: &mut R,
+        at: Location,
+        keys: &[EncryptionKey]
+    ) -> Result<Option<(Option<u64>, EntryRecords)>> {
+        let (flags, sequence, kv) = match ActionKV::read_record(f, at, keys)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        if flags & FLAG_BATCH == 0 {
+            return Ok(Some((sequence, vec![(at, kv)])));
+        }
+
+        // The records in a frame start right after the frame's own header,
+        // and its sequence number if it has one
+        let start = at.offset + HEADER_LEN + extras_len(flags);
+        let mut records = Vec::new();
+        let mut payload = kv.value.as_slice();
+        loop {
+            let consumed = kv.value.len() - payload.len();
+            let inner_at = Location {
+                segment: at.segment,
+                offset: start + consumed as u64,
+            };
+
+            match ActionKV::process_record(&mut payload, inner_at, keys)? {
+                Some(inner) => records.push((inner_at, inner)),
+                None => break,
+            }
+        }
+
+        Ok(Some((sequence, records)))
+    }
+
+    /// Reads a single record, returning its flags and sequence number along
+    /// with it
+    fn read_record<R: Read>(
+        f: &mut R,
+        at: Location,
+        keys: &[EncryptionKey]
+    ) -> Result<Option<(u8, Option<u64>, KeyValuePair)>> {
+        let Location { segment, offset } = at;
+
         // Key / Value entry starts with Checksum
         // Then 4 bytes defining the length of the key
         // Then 4 bytes defining the length of the value
-        let saved_checksum = f.read_u32::<LittleEndian>()?;
-        let key_len = f.read_u32::<LittleEndian>()?;
-        let val_len = f.read_u32::<LittleEndian>()?;
+        let mut raw_header = [0u8; HEADER_LEN as usize];
+        match read_up_to(f, &mut raw_header)? {
+            0 => return Ok(None),
+            n if n == raw_header.len() => {},
+            _ => return Err(Error::TruncatedRecord { segment, offset }),
+        }
+
+        let mut header = &raw_header[..];
+        let saved_checksum = header.read_u32::<LittleEndian>()?;
+        let flags_and_key_len = header.read_u32::<LittleEndian>()?;
+        let val_len = header.read_u32::<LittleEndian>()?;
 
-        // Therefore, the key/value path has the length key_len + val_len
-        let data_len = key_len + val_len;
+        let flags = (flags_and_key_len >> 24) as u8;
+        let key_len = flags_and_key_len & KEY_LEN_MASK;
 
-        // Read that much data from the file
-        let mut data = ByteString::with_capacity(data_len as usize);
+        // Therefore, the key/value path has the length key_len + val_len,
+        // after the expiry and write times if the record has them
+        let extras_len = extras_len(flags);
+        let data_len = data_len(flags, key_len as u64, val_len as u64);
+
+        // Read that much data from the file. The lengths come from the file
+        // itself, so they aren't trusted to preallocate the buffer
+        let mut data = ByteString::new();
 
         // This section is put in a block because .take(..) creates
         // a new Read insteance
         {
-            // Using the opened file stream, 
+            // Using the opened file stream,
             f.by_ref()
-                .take(data_len as u64)
+                .take(data_len)
                 .read_to_end(&mut data)?;
         }
 
-        debug_assert_eq!(data.len(), data_len as usize);
-
-        // Check that the data isn't corrupted
-        let checksum = crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!(
-                "Data corrupted: {:08x} != {:08x}",
-                checksum, saved_checksum
-            )
+        if (data.len() as u64) < data_len {
+            return Err(Error::TruncatedRecord { segment, offset });
         }
 
         // vec.split_off removes a subslice of the given range (key_len)
-        // from the vector and returns it. 
-        let value = data.split_off(key_len as usize);
-        let key = data;
+        // from the vector and returns it.
+        let (key, value) = match flags & FLAG_ENCRYPTED {
+            0 => {
+                // Check that the data isn't corrupted
+                let checksum = record_checksum(flags, &data);
+                if checksum != saved_checksum {
+                    return Err(Error::ChecksumMismatch { segment, offset });
+                }
+
+                let value = data.split_off(extras_len as usize + key_len as usize);
+                let key = data.split_off(extras_len as usize);
+                (key, value)
+            },
+            // An encrypted record is checked by its tag as it is decrypted
+            _ => {
+                let mut key = open_record(&raw_header, &data, keys)
+                    .ok_or(Error::Decryption { segment, offset })?;
+                data.truncate(extras_len as usize);
+                let value = key.split_off(key_len as usize);
+                (key, value)
+            },
+        };
+        let value = compression::decompress(flags, value)?;
+
+        let mut extras = data.as_slice();
+        let expires_at = match flags & FLAG_EXPIRES {
+            0 => None,
+            _ => Some(clock::from_millis(extras.read_u64::<LittleEndian>()?)),
+        };
+        let written_at = match flags & FLAG_TIMESTAMP {
+            0 => None,
+            _ => Some(clock::from_millis(extras.read_u64::<LittleEndian>()?)),
+        };
+        let sequence = match flags & FLAG_SEQUENCE {
+            0 => None,
+            _ => Some(extras.read_u64::<LittleEndian>()?),
+        };
+        let tombstone = flags & FLAG_TOMBSTONE != 0;
 
-        Ok(KeyValuePair { key, value })
+        Ok(Some((flags, sequence, KeyValuePair { key, value, tombstone, expires_at, written_at })))
     }
 
-    /// Set the file cursor to be at the end of the file
-    pub fn seek_to_end(&mut self) -> io::Result<u64> {
+    /// Set the file cursor to be at the end of the active segment
+    pub fn seek_to_end(&mut self) -> Result<u64> {
         // SeekFrom::End(0) starts the "seeking" 0 bytes from the end of the file
-        self.f.seek(SeekFrom::End(0))
+        Ok(self.active_segment().f.seek(SeekFrom::End(0))?)
     }
 
-    /// Load the file into the HashMap
-    pub fn load(&mut self) -> io::Result<()> {
-        let mut f = BufReader::new(&mut self.f);
+    /// Load the file into the HashMap, dealing with bad records according
+    /// to the store's recovery policy.
+    ///
+    /// If there is a valid persistent index or hint file, the index is read
+    /// from it and only the records written after it need to be scanned.
+    pub fn load(&mut self) -> Result<()> {
+        if self.options.persistent_index {
+            return self.load_persistent_index();
+        }
+
+        let hint_path = self.hint_path();
+        let sizes = self.segment_sizes()?;
 
-        loop {
-            // SeekFrom::Current(0) moves the cursor 0 bytes from it's current location
-            // So it doesn't move it at all and allows us to get the current position
-            let current_position = f.seek(SeekFrom::Current(0))?;
+        // An encrypted store has no use for hints, and any left over from
+        // before it was encrypted name its keys in the clear
+        let hints = match self.options.encryption_key {
+            Some(_) => None,
+            None => hint::read(&hint_path)?,
+        };
 
-            let maybe_kv = ActionKV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
+        let start = match hints {
+            Some(hints) if hints.fit(&sizes) => {
+                for h in hints.hints {
+                    if let Some(expires_at) = h.expires_at {
+                        self.expiries.insert(h.key.clone(), expires_at);
+                    }
+                    self.index.insert(h.key, h.location);
+                }
+                hints.end
+            },
+            // A hint file that doesn't fit the data file can't be trusted
+            // again later, even once the data file has grown past it
+            _ => {
+                if !self.options.read_only {
+                    hint::remove(&hint_path)?;
+                }
+                self.start()
+            },
+        };
+
+        self.hinted = Some(start);
+        self.load_from(start)
+    }
+
+    /// Loads the index from the index file, falling back to scanning the
+    /// data files if the index file is missing or describes more data than
+    /// they hold
+    fn load_persistent_index(&mut self) -> Result<()> {
+        // A read-only store reads the index file but never brings it up to date
+        let (index_file, replay) = match self.options.read_only {
+            true => (None, index_file::read(&self.index_file_path())?),
+            false => {
+                let (index_file, replay) = IndexFile::open(&self.index_file_path())?;
+                (Some(index_file), replay)
+            },
+        };
+        let sizes = self.segment_sizes()?;
+
+        let start = match replay {
+            Some(replay) if sizes.get(&replay.end.segment).is_some_and(|size| replay.end.offset <= *size) => {
+                self.index.clear();
+                self.index.extend(replay.index);
+                self.expiries = replay.expiries;
+                Some(replay.end)
+            },
+            _ => None,
+        };
+
+        self.load_from(start.unwrap_or(self.start()))?;
+
+        if let Some(mut index_file) = index_file {
+            // Records found by scanning aren't in the index file yet
+            let end = self.end()?;
+            if start != Some(end) {
+                index_file.rewrite(&self.index, &self.expiries, end)?;
+            }
+
+            self.index_file = Some(index_file);
+        }
+        Ok(())
+    }
+
+    /// Reads every record from `start` to the end of the store into the
+    /// index, then drops any keys that have expired
+    fn load_from(&mut self, start: Location) -> Result<()> {
+        let recovery = self.options.recovery;
+        let read_only = self.options.read_only;
+        let now = self.options.clock.now();
+        let mut truncated = false;
+
+        for segment in self.segments.iter_mut().filter(|s| s.id >= start.segment) {
+            let id = segment.id;
+            let mut truncate_at = None;
+
+            {
+                let mut f = BufReader::new(&mut segment.f);
+                if id == start.segment {
+                    f.seek(SeekFrom::Start(start.offset))?;
+                } else {
+                    f.seek(SeekFrom::Start(header::LEN))?;
+                }
+
+                loop {
+                    // stream_position() is the same as seeking 0 bytes from the current location,
+                    // so it doesn't move the cursor and gives us the current position
+                    let current_position = f.stream_position()?;
+                    let at = Location { segment: id, offset: current_position };
+
+                    let maybe_entry = ActionKV::read_entry(&mut f, at, &self.keys);
+                    let records = match (maybe_entry, recovery) {
+                        (Ok(Some(records)), _) => records,
+                        (Ok(None), _) => break,
+                        (Err(Error::ChecksumMismatch { .. }), Recovery::Skip) => continue,
+                        (Err(Error::TruncatedRecord { .. }), Recovery::Skip)
+                        | (Err(Error::ChecksumMismatch { .. }), Recovery::Truncate)
+                        | (Err(Error::TruncatedRecord { .. }), Recovery::Truncate) => {
+                            truncate_at = Some(current_position);
                             break;
                         },
-                        _ => return Err(err)
+                        (Err(err), _) => return Err(err),
+                    };
+
+                    for (at, kv) in records {
+                        if kv.is_dead_at(now) {
+                            self.index.remove(&kv.key);
+                            self.expiries.remove(&kv.key);
+                            continue;
+                        }
+
+                        match kv.expires_at {
+                            Some(expires_at) => self.expiries.insert(kv.key.clone(), expires_at),
+                            None => self.expiries.remove(&kv.key),
+                        };
+                        self.index.insert(kv.key, at);
                     }
                 }
-            };
+            }
+
+            // A read-only store just stops reading at the bad record
+            if let Some(len) = truncate_at
+                && !read_only
+            {
+                segment.f.set_len(len)?;
+                segment.f.sync_all()?;
+                truncated = true;
+            }
+        }
+
+        // Hints written before the truncation could otherwise look
+        // valid again once the file grows back past them
+        if truncated {
+            hint::remove(&self.hint_path())?;
+        }
+
+        // Keys read from hints or the index file may have expired since
+        self.drop_expired();
+
+        Ok(())
+    }
+
+    /// Removes every key whose time to live has run out from the index
+    fn drop_expired(&mut self) {
+        let now = self.options.clock.now();
+        let index = &mut self.index;
+
+        self.expiries.retain(|key, expires_at| {
+            if *expires_at <= now {
+                index.remove(key);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Whether `key` has a time to live that has run out
+    pub(crate) fn is_expired(&self, key: &ByteStr) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= self.options.clock.now())
+    }
+
+    /// Writes a hint file listing where the live record for every key in
+    /// the index is, so that the next `load` can skip reading the values.
+    ///
+    /// Hints would give away an encrypted store's keys, so an encrypted
+    /// store removes its hint file instead.
+    pub fn write_hints(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.options.encryption_key.is_some() {
+            hint::remove(&self.hint_path())?;
+            return Ok(());
+        }
+        let end = self.end()?;
+
+        let mut locations: Vec<(ByteString, Location)> = self.index
+            .iter()
+            .map(|(key, location)| (key.clone(), *location))
+            .collect();
+        locations.sort_unstable_by_key(|(_, location)| *location);
+
+        let mut hints = Vec::with_capacity(locations.len());
+        for (key, location) in locations {
+            let record_len = self.record_len_at(location)?;
+            let expires_at = self.expiries.get(&key).copied();
+            hints.push(hint::Hint { key, location, record_len, expires_at });
+        }
+
+        hint::write(&self.hint_path(), end, &hints)?;
+        self.hinted = Some(end);
+
+        Ok(())
+    }
+
+    /// Reads the header of the record at `location` to work out how many
+    /// bytes the whole record takes up
+    fn record_len_at(&mut self, location: Location) -> Result<u32> {
+        let position = self.segment_position(location.segment)?;
+        let f = &mut self.segments[position].f;
 
-            self.index.insert(kv.key, current_position);
+        let mut header = [0u8; HEADER_LEN as usize];
+        f.seek(SeekFrom::Start(location.offset))?;
+        f.read_exact(&mut header)?;
+
+        Ok(record_len(&header) as u32)
+    }
+
+    /// Closes the store. If the index was loaded from hints and the store has changed
+    /// since the hint file was last written, the hints are brought up to
+    /// date first so the next `load` is quick. Unless the sync policy is
+    /// `SyncPolicy::Never`, any writes not yet synced are synced.
+    pub fn close(mut self) -> Result<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+
+        if self.sync_state.policy() != SyncPolicy::Never && self.sync_state.unsynced() > 0 {
+            self.sync()?;
+        }
+
+        // A persistent index is always up to date, so it doesn't need hints
+        if self.index_file.is_none()
+            && let Some(hinted) = self.hinted
+            && self.end()? != hinted
+        {
+            self.write_hints()?;
         }
 
         Ok(())
     }
 
-    /// Gets the specified key from the HashMap index
+    /// Checks every record in the store, returning an error for each bad
+    /// record found rather than stopping at the first one
+    pub fn verify(&mut self) -> Result<Vec<Error>> {
+        let mut bad_records = Vec::new();
+
+        for segment in &mut self.segments {
+            let mut f = BufReader::new(&mut segment.f);
+            f.seek(SeekFrom::Start(header::LEN))?;
+
+            loop {
+                let at = Location { segment: segment.id, offset: f.stream_position()? };
+
+                match ActionKV::read_entry(&mut f, at, &self.keys) {
+                    Ok(Some(_)) => {},
+                    Ok(None) => break,
+                    // A record that fails its tag is as good as damaged
+                    Err(err @ (Error::ChecksumMismatch { .. } | Error::Decryption { .. })) => bad_records.push(err),
+                    // Nothing can follow a truncated record in its segment
+                    Err(err @ Error::TruncatedRecord { .. }) => {
+                        bad_records.push(err);
+                        break;
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(bad_records)
+    }
+
+    /// Gets the specified key from the HashMap index. Keys whose time to
+    /// live has run out are treated as absent.
     pub fn get(
         &mut self,
         key: &ByteStr
-    ) -> io::Result<Option<ByteString>> {
-        let position = match self.index.get(key) {
+    ) -> Result<Option<ByteString>> {
+        if self.is_expired(key) {
+            return Ok(None);
+        }
+
+        let location = match self.index.get(key) {
             None => return Ok(None),
-            Some(position) => *position
+            Some(location) => *location
         };
 
-        let kv = self.get_at(position)?;
+        let kv = self.get_at(location)?;
+        if kv.tombstone {
+            return Ok(None);
+        }
 
         Ok(Some(kv.value))
     }
 
-    /// Gets the data from the specified position in the database
-    pub fn get_at(
-        &mut self,
-        position: u64
-    ) -> io::Result<KeyValuePair> {
-        let mut f = BufReader::new(&mut self.f);
-        // Set the cursor to be a the position argument and start the database read
-        f.seek(SeekFrom::Start(position))?;
-        let kv = ActionKV::process_record(&mut f)?;
+    /// Whether `key` has a live value, without reading the value itself
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.index.contains_key(key) && !self.is_expired(key)
+    }
+
+    /// Counts the live keys and measures the files the store is made of,
+    /// including how much of them is taken up by dead records that
+    /// compaction would free. Every record's header is read, and the start
+    /// of every live record's value to work out how well it compresses, but
+    /// only encrypted records are read in full.
+    pub fn stats(&mut self) -> Result<Stats> {
+        let live: HashSet<Location> = self.index
+            .iter()
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(_, location)| *location)
+            .collect();
+
+        let mut records = 0;
+        let mut live_bytes = 0;
+        let mut largest_key = 0;
+        // The length of each batch frame's header, and the frames still
+        // holding a live record
+        let mut frames = HashMap::new();
+        let mut live_frames = HashSet::new();
+        let mut marks = 0;
+        self.for_each_header(|location, header, frame| {
+            if header[7] & FLAG_BATCH != 0 {
+                // A mark holds no records, and has to stay
+                let len = HEADER_LEN + extras_len(header[7]);
+                if record_len(header) == len {
+                    marks += len;
+                } else {
+                    frames.insert(location, len);
+                }
+                return;
+            }
+
+            records += 1;
+            if live.contains(&location) {
+                live_bytes += record_len(header);
+                largest_key = largest_key.max((LittleEndian::read_u32(&header[4..]) & KEY_LEN_MASK) as u64);
+                live_frames.extend(frame);
+            }
+        })?;
+
+        let mut value_bytes = 0;
+        let mut stored_value_bytes = 0;
+        let mut value_sizes = Vec::with_capacity(live.len());
+        for location in &live {
+            let (stored, original) = self.value_sizes_at(*location)?;
+            stored_value_bytes += stored;
+            value_bytes += original;
+            value_sizes.push(original);
+        }
+
+        let compression_ratio = match stored_value_bytes {
+            0 => 1.0,
+            stored => value_bytes as f64 / stored as f64,
+        };
+
+        // A frame's header has to stay for as long as any record in it does
+        let overhead_bytes = self.segments.len() as u64 * header::LEN + marks + frames
+            .iter()
+            .filter(|(location, _)| live_frames.contains(*location))
+            .map(|(_, len)| len)
+            .sum::<u64>();
+
+        let disk_bytes: u64 = self.segment_sizes()?.values().sum();
+        let dead_bytes = disk_bytes.saturating_sub(overhead_bytes + live_bytes);
+        let fragmentation = match live_bytes + dead_bytes {
+            0 => 0.0,
+            total => dead_bytes as f64 / total as f64,
+        };
 
-        Ok(kv)
+        Ok(Stats {
+            keys: live.len(),
+            segments: self.segments.len(),
+            disk_bytes,
+            value_bytes,
+            stored_value_bytes,
+            compression_ratio,
+            records,
+            dead_records: records - live.len() as u64,
+            live_bytes,
+            dead_bytes,
+            overhead_bytes,
+            fragmentation,
+            largest_key,
+            largest_value: value_sizes.iter().copied().max().unwrap_or(0),
+            value_sizes: stats::histogram(value_sizes),
+        })
     }
 
-    /// Find the specified key "target" in the database
-    /// Returns the position of the key and it's value
-    pub fn find(
-        &mut self,
-        target: &ByteStr
-    ) -> io::Result<Option<(u64, ByteString)>> {
-        let mut f = BufReader::new(&mut self.f);
+    /// Calls `f` with the location and header of every whole record and
+    /// batch frame, without reading any keys or values. The records inside a
+    /// frame come straight after it, along with where the frame is.
+    fn for_each_header<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Location, &[u8; HEADER_LEN as usize], Option<Location>),
+    {
+        for segment in &mut self.segments {
+            let size = segment.size()?;
+            let mut reader = BufReader::new(&mut segment.f);
+            let mut offset = reader.seek(SeekFrom::Start(header::LEN))?;
 
-        let mut found: Option<(u64, ByteString)> = None;
+            // The frame being read through, and where it ends
+            let mut frame: Option<(Location, u64)> = None;
+            let mut header = [0u8; HEADER_LEN as usize];
+            while offset + HEADER_LEN <= size {
+                reader.read_exact(&mut header)?;
+                let location = Location { segment: segment.id, offset };
+                let len = record_len(&header);
+                if offset + len > size {
+                    break;
+                }
 
-        loop {
-            let position = f.seek(SeekFrom::Current(0))?;
+                if frame.is_some_and(|(_, end)| offset >= end) {
+                    frame = None;
+                }
 
-            let maybe_kv = ActionKV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        },
-                        _ => return Err(err),
-                    }
+                // A frame's records follow straight after its header and
+                // anything the header carries
+                if header[7] & FLAG_BATCH != 0 {
+                    f(location, &header, None);
+                    let extras = extras_len(header[7]);
+                    frame = Some((location, offset + len));
+                    offset += HEADER_LEN + extras;
+                    reader.seek_relative(extras as i64)?;
+                    continue;
                 }
-            };
-            
-            if kv.key == target {
-                found = Some((position, kv.value));
+
+                f(location, &header, frame.map(|(location, _)| location));
+                offset += len;
+                reader.seek_relative((len - HEADER_LEN) as i64)?;
             }
+        }
 
-            // We keep looping through to the end of the file because
-            // if the key has been overwritten, it will be recorded as
-            // such later in the file
-        };
+        Ok(())
+    }
 
-        Ok(found)
+    /// How many bytes the value of the record at `location` takes up as it
+    /// is stored, and how many it took up before it was compressed. Only an
+    /// encrypted record's value has to be read to find out.
+    fn value_sizes_at(&mut self, location: Location) -> Result<(u64, u64)> {
+        let position = self.segment_position(location.segment)?;
+        let f = &mut self.segments[position].f;
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        f.seek(SeekFrom::Start(location.offset))?;
+        f.read_exact(&mut header)?;
+
+        let flags = header[7];
+        let key_len = u32::from_le_bytes([header[4], header[5], header[6], 0]) as u64;
+        let val_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as u64;
+        if flags & compression::FLAGS == 0 {
+            return Ok((val_len, val_len));
+        }
+        if flags & FLAG_ENCRYPTED != 0 {
+            let value = self.get_at(location)?.value;
+            return Ok((val_len, value.len() as u64));
+        }
+
+        // Compressed values start with their original length
+        let mut original = [0u8; 4];
+        f.seek(SeekFrom::Start(location.offset + HEADER_LEN + extras_len(flags) + key_len))?;
+        f.read_exact(&mut original)?;
+        Ok((val_len, u32::from_le_bytes(original) as u64))
     }
 
-    /// Inserts a key/value pair into the database
-    /// Also inserts it into the index hashmap
-    pub fn insert(
+    /// Gets the data from the specified location in the database
+    pub fn get_at(
         &mut self,
-        key: &ByteStr,
-        value: &ByteStr
-    ) -> io::Result<()> {
-        let position = self.insert_but_ignore_index(key, value)?;
+        location: Location
+    ) -> Result<KeyValuePair> {
+        let position = self.segment_position(location.segment)?;
+        let mut f = BufReader::new(&mut self.segments[position].f);
+        // Set the cursor to be a the location argument and start the database read
+        f.seek(SeekFrom::Start(location.offset))?;
 
-        self.index.insert(key.to_vec(), position);
-        Ok(())
+        match ActionKV::process_record(&mut f, location, &self.keys)? {
+            Some(kv) => Ok(kv),
+            None => Err(Error::TruncatedRecord {
+                segment: location.segment,
+                offset: location.offset,
+            }),
+        }
     }
 
-    /// Inserts a key/value pair into the database
-    pub fn insert_but_ignore_index(
+    /// Find the specified key "target" in the database
+    /// Returns the location of the key and it's value
+    pub fn find(
         &mut self,
-        key: &ByteStr,
-        value: &ByteStr
-    ) -> io::Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
+        target: &ByteStr
+    ) -> Result<Option<(Location, ByteString)>> {
+        let now = self.options.clock.now();
+        let mut found: Option<(Location, ByteString)> = None;
 
-        let key_len = key.len();
-        let val_len = value.len();
+        // We keep looping through to the end of the store because if the
+        // key has been overwritten, it will be recorded as such later on
+        self.for_each_record(|_, at, kv| {
+            if kv.key == target {
+                found = match kv.is_dead_at(now) {
+                    true => None,
+                    false => Some((at, kv.value)),
+                };
+            }
+        })?;
 
-        // Push bytes from key/value in temporary [u8] buffer
-        let mut tmp = ByteString::with_capacity(key_len + val_len);
-        for byte in key {
-            tmp.push(*byte);
-        }
+        Ok(found)
+    }
 
-        for byte in value {
-            tmp.push(*byte);
-        }
+    /// Every version of `key` still in the store, oldest first: each value
+    /// it was given, and each time it was deleted, along with when. Compacting
+    /// a segment only keeps the latest version, so older ones are lost with it,
+    /// but the versions kept keep their sequence numbers.
+    pub fn history(&mut self, key: &ByteStr) -> Result<Vec<Version>> {
+        let mut versions = Vec::new();
 
-        // Calculate checksum
-        let checksum = crc32::checksum_ieee(&tmp);
+        self.for_each_record(|sequence, location, kv| {
+            if kv.key == key {
+                versions.push(Version {
+                    sequence,
+                    location,
+                    value: (!kv.tombstone).then_some(kv.value),
+                    expires_at: kv.expires_at,
+                    written_at: kv.written_at,
+                });
+            }
+        })?;
 
-        // Starting from end of file, write the bytes in the BitCask format
-        let next_byte = SeekFrom::End(0);
-        let current_position = f.seek(SeekFrom::Current(0))?;
-        f.seek(next_byte)?;
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(val_len as u32)?;
-        f.write_all(&tmp)?;
+        Ok(versions)
+    }
 
-        Ok(current_position)
+    /// Freezes the index and how long each segment file is into a handle
+    /// that other threads can read from while this store keeps on writing.
+    /// Copying the index makes this O(keys).
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        Snapshot::new(self)
     }
 
-    #[inline]
-    pub fn update(
-        &mut self,
-        key: &ByteStr,
-        value: &ByteStr
-    ) -> io::Result<()> {
-        self.insert(key, value)
+    /// A view of the store as it was just after write `sequence`, as
+    /// numbered by `history`. Writes made since are left out of it.
+    ///
+    /// Compaction throws away the values it finds overwritten, so once it
+    /// has run the store can only be viewed as of the last write before it
+    /// or later. Asking for an earlier write fails with
+    /// `Error::HistoryCompacted`.
+    pub fn snapshot_at(&mut self, sequence: u64) -> Result<PointInTime<'_>> {
+        PointInTime::new(self, sequence)
     }
 
-    #[inline]
-        pub fn delete(
-            &mut self,
-            key: &ByteStr
-        ) -> io::Result<()> {
-            self.insert(key, b"")
-        }
-}
\ No newline at end of file
+    /// Reads every record in the store in the order they were written,
+    /// passing each one to `f` along with its sequence number and where it
+    /// is. The records of a batch share the sequence number of the batch.
+    ///
+    /// Returns the oldest write the store's history is whole from. Compaction
+    /// throws away values that had been overwritten by the time it ran, so
+    /// the store can't be told as it was before then.
+    pub(crate) fn for_each_record<F: FnMut(u64, Location, KeyValuePair)>(&mut self, mut f: F) -> Result<u64> {
+        let mut whole_from = 0;
+
+        self.for_each_entry(|sequence, numbered, _, records| {
+            // An empty numbered frame marks where a compaction ran
+            if numbered && records.is_empty() {
+                whole_from = whole_from.max(sequence);
+            }
+
+            for (at, kv) in records {
+                f(sequence, at, kv);
+            }
+        })?;
+
+        Ok(whole_from)
+    }
+
+    /// Reads every entry in the store in the order they were written,
+    /// passing each one to `f` along with its sequence number, whether the
+    /// entry carries that number itself, where it is and the records in it
+    fn for_each_entry<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, bool, Location, EntryRecords),
+    {
+        let mut sequence = 0;
+
+        for segment in &mut self.segments {
+            let mut reader = BufReader::new(&mut segment.f);
+            reader.seek(SeekFrom::Start(header::LEN))?;
+
+            loop {
+                let at = Location { segment: segment.id, offset: reader.stream_position()? };
+
+                let (numbered, records) = match ActionKV::read_numbered_entry(&mut reader, at, &self.keys)? {
+                    Some(entry) => entry,
+                    None => break,
+                };
+
+                sequence = numbered.unwrap_or(sequence + 1);
+                f(sequence, numbered.is_some(), at, records);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Iterates over the keys within `range` and their values, in key order
+    pub fn scan<R: RangeBounds<ByteString>>(&mut self, range: R) -> Scan<'_> {
+        let lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+        Scan::new(self, lower, upper)
+    }
+
+    /// Iterates over the keys that start with `prefix` and their values,
+    /// in key order
+    pub fn scan_prefix(&mut self, prefix: &ByteStr) -> Scan<'_> {
+        let upper = match index::prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        Scan::new(self, Bound::Included(prefix.to_vec()), upper)
+    }
+
+    /// Inserts a key/value pair into the database
+    /// Also inserts it into the index hashmap
+    pub fn insert(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr
+    ) -> Result<()> {
+        self.put(key, value, None)
+    }
+
+    /// Inserts a key/value pair that expires once `ttl` has passed, after
+    /// which the key reads as absent
+    pub fn insert_with_ttl(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        ttl: Duration
+    ) -> Result<()> {
+        let expires_at = self.options.clock.now() + ttl;
+        self.put(key, value, Some(expires_at))
+    }
+
+    fn put(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<SystemTime>
+    ) -> Result<()> {
+        let (stored, flags) = self.encode_value(value)?;
+        let (location, end) = self.append_record(key, &stored, flags, expires_at)?;
+
+        if let Some(index_file) = &mut self.index_file {
+            index_file.append_put(key, location, expires_at, end)?;
+        }
+
+        self.set_expiry(key, expires_at);
+        self.index.insert(key.to_vec(), location);
+        self.after_write()
+    }
+
+    /// Compresses `value` if it is large enough for the store to compress,
+    /// returning the value to store along with the flags to mark it with
+    fn encode_value<'a>(&self, value: &'a ByteStr) -> Result<(Cow<'a, ByteStr>, u8)> {
+        if value.len() > self.options.compression_threshold
+            && let Some((stored, flag)) = self.options.compression.compress(value)?
+        {
+            return Ok((Cow::Owned(stored), flag));
+        }
+
+        Ok((Cow::Borrowed(value), 0))
+    }
+
+    /// Notes when `key` expires, or that it never does
+    fn set_expiry(&mut self, key: &ByteStr, expires_at: Option<SystemTime>) {
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key.to_vec(), expires_at),
+            None => self.expiries.remove(key),
+        };
+    }
+
+    /// Inserts a key/value pair into the database
+    pub fn insert_but_ignore_index(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr
+    ) -> Result<Location> {
+        let (stored, flags) = self.encode_value(value)?;
+        let (location, _) = self.append_record(key, &stored, flags, None)?;
+        self.after_write()?;
+        Ok(location)
+    }
+
+    /// Appends a record with the given flags and expiry time to the end of
+    /// the active segment, returning the location it was written at and the
+    /// new end of the store. The record is stamped with the current time,
+    /// and encrypted if the store has a key, unless it is a batch frame, whose
+    /// records carry their own times and encryption.
+    fn append_record(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        flags: u8,
+        expires_at: Option<SystemTime>
+    ) -> Result<(Location, Location)> {
+        self.check_writable()?;
+
+        // A batch frame's records are stamped and encrypted one by one
+        let (written_at, cipher) = match flags & FLAG_BATCH {
+            0 => (Some(self.options.clock.now()), self.options.encryption_key.clone()),
+            _ => (None, None),
+        };
+        let extras_len = expires_at.map_or(0, |_| EXPIRY_LEN)
+            + written_at.map_or(0, |_| TIMESTAMP_LEN)
+            + cipher.as_ref().map_or(0, |_| crypto::OVERHEAD);
+        let record_len = HEADER_LEN + extras_len + key.len() as u64 + value.len() as u64;
+        if self.directory {
+            let size = self.active_segment().size()?;
+            if size > header::LEN && size + record_len > self.options.segment_size {
+                self.rotate()?;
+            }
+        }
+
+        let segment = self.active_segment();
+        let id = segment.id;
+        let mut f = BufWriter::new(&mut segment.f);
+
+        // Starting from end of file, write the bytes in the BitCask format
+        let current_position = f.seek(SeekFrom::End(0))?;
+        let written = ActionKV::write_record(&mut f, key, value, flags, expires_at, written_at, cipher.as_ref())?;
+
+        // Dropping the writer would flush it too, but would swallow any error
+        f.flush()?;
+
+        Ok((
+            Location { segment: id, offset: current_position },
+            Location { segment: id, offset: current_position + written },
+        ))
+    }
+
+    /// Seals the active segment and starts appending to a new, empty one
+    fn rotate(&mut self) -> Result<()> {
+        let sealed = self.active_segment();
+
+        // A sealed segment never changes again, so it only needs to be
+        // flushed to disk once
+        sealed.f.sync_data()?;
+
+        let id = sealed.id + 1;
+        let segment = Segment::open(id, segment::segment_path(&self.path, id), new_file_header(&self.options))?;
+        self.segments.push(segment);
+
+        Ok(())
+    }
+
+    /// Writes a single key/value record in the BitCask format, returning
+    /// the number of bytes written. A record with an expiry time is written
+    /// with FLAG_EXPIRES set, one with a write time with FLAG_TIMESTAMP, and
+    /// one encrypted with `cipher` with FLAG_ENCRYPTED.
+    fn write_record<W: Write>(
+        f: &mut W,
+        key: &ByteStr,
+        value: &ByteStr,
+        flags: u8,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+        cipher: Option<&EncryptionKey>
+    ) -> io::Result<u64> {
+        let key_len = key.len();
+        let val_len = value.len();
+
+        if key_len as u64 > KEY_LEN_MASK as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key is longer than 16 MiB",
+            ));
+        }
+        if val_len as u64 > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "value is 4 GiB or longer",
+            ));
+        }
+
+        // Push bytes from key/value in temporary [u8] buffer, after the
+        // expiry and write times if there are any
+        let extras_len = (EXPIRY_LEN + TIMESTAMP_LEN) as usize;
+        let mut tmp = ByteString::with_capacity(extras_len + key_len + val_len);
+        let mut flags = flags;
+        if let Some(expires_at) = expires_at {
+            flags |= FLAG_EXPIRES;
+            tmp.write_u64::<LittleEndian>(clock::to_millis(expires_at))?;
+        }
+        if let Some(written_at) = written_at {
+            flags |= FLAG_TIMESTAMP;
+            tmp.write_u64::<LittleEndian>(clock::to_millis(written_at))?;
+        }
+
+        if cipher.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        let mut header = [0u8; HEADER_LEN as usize];
+        LittleEndian::write_u32(&mut header[4..8], (flags as u32) << 24 | key_len as u32);
+        LittleEndian::write_u32(&mut header[8..12], val_len as u32);
+
+        let checksum = match cipher {
+            // The key and value are sealed together, bound to the header and
+            // times so that none of them can be swapped for another record's.
+            // The tag stands in for the checksum.
+            Some(cipher) => {
+                let mut plaintext = ByteString::with_capacity(key_len + val_len);
+                plaintext.extend_from_slice(key);
+                plaintext.extend_from_slice(value);
+                let (tag_head, sealed) = cipher.seal(&plaintext, &record_aad(&header, &tmp));
+                tmp.extend(sealed);
+                tag_head
+            },
+            None => {
+                for byte in key {
+                    tmp.push(*byte);
+                }
+
+                for byte in value {
+                    tmp.push(*byte);
+                }
+
+                // Calculate checksum
+                record_checksum(flags, &tmp)
+            },
+        };
+        LittleEndian::write_u32(&mut header[..4], checksum);
+
+        f.write_all(&header)?;
+        f.write_all(&tmp)?;
+
+        Ok(HEADER_LEN + tmp.len() as u64)
+    }
+
+    /// Reads the bytes of the record at `location` exactly as they are
+    /// stored, after checking that they are intact
+    fn read_raw_record(&mut self, location: Location) -> Result<ByteString> {
+        let record_len = self.record_len_at(location)?;
+        let position = self.segment_position(location.segment)?;
+        let f = &mut self.segments[position].f;
+
+        let mut raw = vec![0u8; record_len as usize];
+        f.seek(SeekFrom::Start(location.offset))?;
+        f.read_exact(&mut raw)?;
+
+        ActionKV::process_record(&mut raw.as_slice(), location, &self.keys)?;
+        Ok(raw)
+    }
+
+    /// Reads the record at `location` and writes it out again, compressed and
+    /// encrypted as the store now would, returning the new bytes
+    fn reencode_record(&mut self, location: Location) -> Result<ByteString> {
+        let kv = self.get_at(location)?;
+        let (value, flags) = match kv.tombstone {
+            true => (Cow::Borrowed(&b""[..]), FLAG_TOMBSTONE),
+            false => self.encode_value(&kv.value)?,
+        };
+
+        let mut raw = ByteString::new();
+        ActionKV::write_record(
+            &mut raw,
+            &kv.key,
+            &value,
+            flags,
+            kv.expires_at,
+            kv.written_at,
+            self.options.encryption_key.as_ref(),
+        )?;
+        Ok(raw)
+    }
+
+    /// Copies the store to a new backup at `path`, checking the copy's
+    /// checksums before it is put in place. The backup is a single file, or
+    /// a directory if the store is kept in one, and can be opened like any
+    /// other store or put back with `restore`.
+    ///
+    /// The store is borrowed until the copy is done, so nothing can be
+    /// written to it in the meantime. `SharedStore::backup_to` only holds
+    /// the store while it works out what to copy, so writers there carry on
+    /// while the files are copied, and the backup holds the store as it was
+    /// when the copy began.
+    pub fn backup_to(&mut self, path: &Path) -> Result<()> {
+        let sources = self.backup_sources()?;
+        backup::copy(sources, &self.keys, self.directory, path)
+    }
+
+    /// Opens each segment file again and notes how long it is, once every
+    /// write so far has reached it
+    pub(crate) fn backup_sources(&mut self) -> Result<Vec<backup::Source>> {
+        self.flush()?;
+
+        let mut sources = Vec::with_capacity(self.segments.len());
+        for segment in &mut self.segments {
+            let len = segment.size()?;
+            sources.push(backup::Source { id: segment.id, f: File::open(&segment.path)?, len });
+        }
+        Ok(sources)
+    }
+
+    /// Rewrites only the live records referenced by the index into fresh
+    /// files, swaps them in place of the current ones and updates the index.
+    ///
+    /// Each segment is compacted in turn, oldest first. Its fresh file is
+    /// written next to the original as `FILE.compact`, synced to disk and
+    /// then renamed over the original. The rename is atomic, so a crash at
+    /// any point leaves either the old file or the new one intact. A stale
+    /// `FILE.compact` left behind by a crash is simply overwritten by the
+    /// next compaction.
+    ///
+    /// The records kept keep their sequence numbers, so `history` numbers
+    /// writes the same way before and after.
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let ids: Vec<u32> = self.segments.iter().map(|s| s.id).collect();
+
+        // Working oldest first means that by the time a segment is reached,
+        // the older segments no longer hold anything a tombstone would have
+        // to hide, so every tombstone can go
+        self.rewrite_segments(&ids, false, false)?;
+
+        self.remove_empty_segments()?;
+        self.refresh_index_files()
+    }
+
+    /// Compacts a single segment, leaving the rest of the store untouched.
+    /// The whole store is still read, to number the writes that are kept.
+    ///
+    /// Tombstones in any segment but the oldest are kept, since there may
+    /// still be values for their keys in older segments that they need
+    /// to hide.
+    pub fn compact_segment(&mut self, id: u32) -> Result<()> {
+        self.check_writable()?;
+        let keep_tombstones = self.segments[0].id != id;
+        self.rewrite_segments(&[id], keep_tombstones, false)?;
+
+        self.remove_empty_segments()?;
+        self.refresh_index_files()
+    }
+
+    /// Compacts the whole store, decrypting every record that is kept and
+    /// encrypting it again under `key`, which the store is written with from
+    /// then on. Records that were written in the clear are encrypted too, so
+    /// this is also how an existing store is encrypted.
+    ///
+    /// Segments are swapped in one at a time, as they are by `compact`. If
+    /// a rekey of a directory store doesn't finish, some segments are left
+    /// under the old key: open it with the old key as
+    /// `previous_encryption_key` and rekey again.
+    ///
+    /// A store kept with a persistent index can't be encrypted, as its
+    /// index file would give the keys away, so rekeying one fails before
+    /// anything is rewritten. Open it without `persistent_index` first.
+    pub fn rekey(&mut self, key: EncryptionKey) -> Result<()> {
+        self.check_writable()?;
+        if self.options.persistent_index {
+            return Err(persistent_index_with_key());
+        }
+
+        // Records not yet rewritten still need the old keys to be read
+        self.keys.insert(0, key.clone());
+        self.options.encryption_key = Some(key);
+
+        let ids: Vec<u32> = self.segments.iter().map(|s| s.id).collect();
+        self.rewrite_segments(&ids, false, true)?;
+
+        self.keys.truncate(1);
+        self.remove_empty_segments()?;
+        self.refresh_index_files()
+    }
+
+    /// Rewrites the segments `ids` in turn. If that throws any records away,
+    /// the end of the store is then marked, as the history the rewrite
+    /// leaves is only whole from there on. Segments swapped in before one
+    /// that fails still count, so the mark is written then too.
+    fn rewrite_segments(&mut self, ids: &[u32], keep_tombstones: bool, reencode: bool) -> Result<()> {
+        let mut numbering = self.number_for_rewrite()?;
+
+        let mut dropped = false;
+        let mut result = Ok(());
+        for &id in ids {
+            match self.rewrite_segment(id, keep_tombstones, reencode, &mut numbering) {
+                Ok(dropped_any) => dropped |= dropped_any,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                },
+            }
+        }
+
+        // A mark the store already ends with will do as well as a new one
+        if dropped && !numbering.marked {
+            let mut mark = ByteString::new();
+            write_mark(&mut mark, numbering.end)?;
+            let active = self.active_segment();
+            active.f.write_all(&mark)?;
+            active.f.sync_data()?;
+        }
+
+        result
+    }
+
+    /// Works out the sequence number of every record that a rewrite of the
+    /// store's segments might keep, of the last write in each segment and
+    /// in the store, and how many records each segment holds.
+    fn number_for_rewrite(&mut self) -> Result<Numbering> {
+        let live: HashSet<Location> = self.index.iter().map(|(_, location)| *location).collect();
+        let mut numbering = Numbering::default();
+
+        self.for_each_entry(|sequence, numbered, at, records| {
+            numbering.ends.insert(at.segment, sequence);
+            numbering.marked = numbered && records.is_empty();
+            *numbering.records.entry(at.segment).or_insert(0) += records.len();
+
+            // Overwritten values are never kept, but any record that is, or
+            // may become, a tombstone might be
+            for (at, kv) in records {
+                if live.contains(&at) || kv.tombstone || kv.expires_at.is_some() {
+                    numbering.sequences.insert(at, sequence);
+                }
+            }
+        })?;
+
+        // A segment with no entries ends where the one before it did
+        let mut end = 0;
+        for segment in &self.segments {
+            end = *numbering.ends.entry(segment.id).or_insert(end);
+        }
+        numbering.end = end;
+
+        Ok(numbering)
+    }
+
+    /// Deletes each sealed segment that a rewrite left with nothing but the
+    /// mark at its end, unless the segment after it counts its writes on
+    /// from that mark
+    fn remove_empty_segments(&mut self) -> Result<()> {
+        // Newest first, so that the segment after each one is still there
+        for position in (0..self.segments.len() - 1).rev() {
+            let empty = self.segments[position].size()? == header::LEN + HEADER_LEN + SEQUENCE_LEN
+                && self.first_entry_flags(position)? == Some(FLAG_BATCH | FLAG_SEQUENCE);
+            let next_numbered = self.first_entry_flags(position + 1)?
+                .is_some_and(|flags| flags & FLAG_SEQUENCE != 0);
+
+            if empty && next_numbered {
+                let segment = self.segments.remove(position);
+                drop(segment.f);
+                fs::remove_file(&segment.path)?;
+                sync_parent_dir(&segment.path)?;
+                self.generation += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The flags of the first entry in the segment at `position`, if it has any
+    fn first_entry_flags(&mut self, position: usize) -> Result<Option<u8>> {
+        let segment = &mut self.segments[position];
+        if segment.size()? < header::LEN + HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        segment.f.seek(SeekFrom::Start(header::LEN))?;
+        segment.f.read_exact(&mut header)?;
+        Ok(Some(header[7]))
+    }
+
+    /// Copies the live records of a segment into a fresh file and swaps it
+    /// in place of the segment, numbering them as `numbering` says and
+    /// ending the file with a mark numbered as the segment's last write, so
+    /// that the segment after it counts on from there. With `reencode`, the
+    /// records are read and written
+    /// again as the store is now set up to write them, rather than copied
+    /// byte for byte.
+    ///
+    /// Returns whether any records were thrown away. A segment that would
+    /// keep every record it has, and already numbers its first entry, is
+    /// left as it is unless it's `reencode`d.
+    fn rewrite_segment(&mut self, id: u32, keep_tombstones: bool, reencode: bool, numbering: &mut Numbering) -> Result<bool> {
+        let position = self.segment_position(id)?;
+
+        // Expired keys are no longer live, so they aren't worth keeping
+        self.drop_expired();
+        let now = self.options.clock.now();
+
+        // The records worth keeping: the live value for each key whose
+        // latest record is in this segment...
+        let mut keep: Vec<(Location, Option<ByteString>)> = self.index
+            .iter()
+            .filter(|(_, location)| location.segment == id)
+            .map(|(key, location)| (*location, Some(key.clone())))
+            .collect();
+
+        // ...and the last tombstone for each key that is still deleted,
+        // which includes expired records
+        if keep_tombstones {
+            let mut tombstones = HashMap::new();
+
+            let mut f = BufReader::new(&mut self.segments[position].f);
+            f.seek(SeekFrom::Start(header::LEN))?;
+            loop {
+                let at = Location { segment: id, offset: f.stream_position()? };
+                let records = match ActionKV::read_entry(&mut f, at, &self.keys)? {
+                    Some(records) => records,
+                    None => break,
+                };
+
+                for (at, kv) in records {
+                    if kv.is_dead_at(now) && !self.index.contains_key(&kv.key) {
+                        tombstones.insert(kv.key, at);
+                    }
+                }
+            }
+
+            keep.extend(tombstones.into_values().map(|location| (location, None)));
+        }
+
+        let dropped = keep.len() < numbering.records.get(&id).copied().unwrap_or(0);
+        let self_numbered = self.first_entry_flags(position)?.is_some_and(|flags| flags & FLAG_SEQUENCE != 0);
+        if !dropped && !reencode && self_numbered {
+            return Ok(false);
+        }
+
+        // Copy records in the order they appear in the file, so the
+        // compacted file preserves the original write order
+        keep.sort_unstable_by_key(|(location, _)| *location);
+
+        let old_path = self.segments[position].path.clone();
+        let file_header = match reencode {
+            true => new_file_header(&self.options),
+            false => self.segments[position].header,
+        };
+        let tmp_path = compaction_path(&old_path);
+
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+
+        let mut moved = Vec::with_capacity(keep.len());
+        {
+            let mut f = BufWriter::new(&mut tmp);
+            file_header.write(&mut f)?;
+            let mut offset = header::LEN;
+
+            // A record that comes straight after the write before it needs no
+            // number of its own, but the first in the file always gets one,
+            // so that the file doesn't count on from the one before
+            let mut last_written: Option<u64> = None;
+            for (old_location, key) in &keep {
+                let raw = match reencode {
+                    true => self.reencode_record(*old_location)?,
+                    false => self.read_raw_record(*old_location)?,
+                };
+
+                let sequence = numbering.sequences[old_location];
+                let raw = match last_written.is_some_and(|last| last + 1 == sequence) {
+                    true => raw,
+                    false => numbered(raw, sequence),
+                };
+                f.write_all(&raw)?;
+
+                if let Some(key) = key {
+                    moved.push((key.clone(), Location { segment: id, offset }));
+                }
+                offset += raw.len() as u64;
+                last_written = Some(sequence);
+            }
+
+            write_mark(&mut f, numbering.ends[&id])?;
+            f.flush()?;
+        }
+
+        // The new file must be fully on disk before it replaces the old one
+        tmp.sync_all()?;
+        drop(tmp);
+
+        // The old hints and index file point into the old file, so they
+        // have to go before the new file takes its place
+        hint::remove(&self.hint_path())?;
+        index_file::remove(&self.index_file_path())?;
+
+        fs::rename(&tmp_path, &old_path)?;
+        sync_parent_dir(&old_path)?;
+        self.segments[position] = Segment::open(id, old_path, file_header)?;
+
+        self.index.extend(moved);
+        self.generation += 1;
+
+        // The last segment now ends with a mark numbered as the store's end
+        if position == self.segments.len() - 1 {
+            numbering.marked = true;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Empties the store, leaving it with a single segment that holds
+    /// nothing but its header
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        self.replace_with(&[])
+    }
+
+    /// Replaces everything in the store with `records`, which have already
+    /// been checked, leaving it with a single segment that holds them.
+    ///
+    /// The new first segment is written next to the old one and renamed
+    /// over it, so snapshots and backups still reading the old files carry
+    /// on seeing them whole. A directory store's later segments have to be
+    /// removed one by one as well, so that is done through
+    /// `begin_replacing`, and opening the store finishes the job if it is
+    /// interrupted.
+    fn replace_with(&mut self, records: &ByteStr) -> Result<()> {
+        self.check_writable()?;
+
+        let first = &self.segments[0];
+        let (id, path, file_header) = (first.id, first.path.clone(), first.header);
+        let staged = staged_path(&path, "clear");
+
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&staged)?;
+        file_header.write(&mut f)?;
+        f.write_all(records)?;
+        f.sync_all()?;
+        drop(f);
+
+        // The hints and index file describe what is being thrown away
+        hint::remove(&self.hint_path())?;
+        index_file::remove(&self.index_file_path())?;
+
+        match self.directory {
+            true => {
+                begin_replacing(&self.path, "clear", &[id])?;
+                finish_replacing(&self.path, "clear", false)?;
+            },
+            false => {
+                fs::rename(&staged, &path)?;
+                sync_parent_dir(&path)?;
+            },
+        }
+        self.segments = vec![Segment::open(id, path, file_header)?];
+
+        self.index.clear();
+        self.expiries.clear();
+        self.generation += 1;
+
+        let start = self.start();
+        self.hinted = Some(start);
+        self.load_from(start)?;
+
+        let end = self.end()?;
+        if let Some(index_file) = &mut self.index_file {
+            index_file.rewrite(&self.index, &self.expiries, end)?;
+        }
+        Ok(())
+    }
+
+    /// Where to start sending the log to a follower that last saw the entry
+    /// at `after`, with the given checksum and sequence number: just past
+    /// that entry, or at the start for a follower that hasn't seen any.
+    ///
+    /// Returns `None` if the entry isn't there any more, as happens once
+    /// compaction has moved it, or if a compaction has thrown away history
+    /// written after it, which the follower would never be sent.
+    pub(crate) fn ship_start(&mut self, after: Option<(Location, u32, u64)>) -> Result<Option<Location>> {
+        let (location, checksum, sequence) = match after {
+            Some(after) => after,
+            None => return Ok(Some(self.start())),
+        };
+
+        if sequence < self.for_each_record(|_, _, _| {})? {
+            return Ok(None);
+        }
+
+        let position = match self.segment_position(location.segment) {
+            Ok(position) => position,
+            Err(_) => return Ok(None),
+        };
+        let size = self.segments[position].size()?;
+        if location.offset < header::LEN || location.offset + HEADER_LEN > size {
+            return Ok(None);
+        }
+
+        let f = &mut self.segments[position].f;
+        let mut header = [0u8; HEADER_LEN as usize];
+        f.seek(SeekFrom::Start(location.offset))?;
+        f.read_exact(&mut header)?;
+
+        let end = location.offset + record_len(&header);
+        match LittleEndian::read_u32(&header) == checksum && end <= size {
+            true => Ok(Some(Location { segment: location.segment, offset: end })),
+            false => Ok(None),
+        }
+    }
+
+    /// Reads the entry at `at` byte for byte, moving on to the start of the
+    /// next segment if `at` is the end of one. Returns where the entry is
+    /// along with it, or `None` if there are no more entries yet.
+    pub(crate) fn next_entry(&mut self, at: Location) -> Result<Option<(Location, ByteString)>> {
+        let mut at = at;
+        loop {
+            let position = self.segment_position(at.segment)?;
+            if at.offset < self.segments[position].size()? {
+                break;
+            }
+
+            match self.segments.get(position + 1) {
+                Some(next) => at = Location { segment: next.id, offset: header::LEN },
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some((at, self.read_raw_record(at)?)))
+    }
+
+    /// How many bytes of entries there are from `at` to the end of the store
+    pub(crate) fn bytes_after(&mut self, at: Location) -> Result<u64> {
+        let mut bytes = 0;
+        for segment in &mut self.segments {
+            let size = segment.size()?;
+            bytes += match segment.id {
+                id if id == at.segment => size.saturating_sub(at.offset),
+                id if id > at.segment => size - header::LEN,
+                _ => 0,
+            };
+        }
+        Ok(bytes)
+    }
+
+    /// Appends an entry copied byte for byte from another store, such as a
+    /// leader's, once it has been checked, and indexes the records in it
+    pub(crate) fn append_entry(&mut self, raw: &ByteStr) -> Result<()> {
+        self.check_writable()?;
+
+        if self.directory {
+            let size = self.active_segment().size()?;
+            if size > header::LEN && size + raw.len() as u64 > self.options.segment_size {
+                self.rotate()?;
+            }
+        }
+
+        let at = self.end()?;
+        let mut reader = raw;
+        let records = match ActionKV::read_entry(&mut reader, at, &self.keys)? {
+            Some(records) if reader.is_empty() => records,
+            _ => return Err(Error::TruncatedRecord { segment: at.segment, offset: at.offset }),
+        };
+
+        self.active_segment().f.write_all(raw)?;
+        let end = Location { segment: at.segment, offset: at.offset + raw.len() as u64 };
+
+        let now = self.options.clock.now();
+        for (location, kv) in records {
+            if kv.is_dead_at(now) {
+                if let Some(index_file) = &mut self.index_file {
+                    index_file.append_delete(&kv.key, end)?;
+                }
+                self.expiries.remove(&kv.key);
+                self.index.remove(&kv.key);
+                continue;
+            }
+
+            if let Some(index_file) = &mut self.index_file {
+                index_file.append_put(&kv.key, location, kv.expires_at, end)?;
+            }
+            self.set_expiry(&kv.key, kv.expires_at);
+            self.index.insert(kv.key, location);
+        }
+
+        self.after_write()
+    }
+
+    /// Copies out the live record for every key, byte for byte and one after
+    /// another in the order they were written, leaving out the tombstones
+    /// and overwritten values that compaction would drop
+    pub(crate) fn live_records(&mut self) -> Result<ByteString> {
+        let mut locations: Vec<Location> = self.index
+            .iter()
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(_, location)| *location)
+            .collect();
+        locations.sort();
+
+        let mut records = ByteString::new();
+        for location in locations {
+            records.extend(self.read_raw_record(location)?);
+        }
+        Ok(records)
+    }
+
+    /// Empties the store and fills it with records copied out by
+    /// `live_records`, such as another store's. Every record is checked
+    /// before anything is changed, and the store goes from the old records
+    /// to the new ones in a single rename, however big they are.
+    pub(crate) fn restore_records(&mut self, records: &ByteStr) -> Result<()> {
+        self.check_writable()?;
+
+        let start = self.start();
+        let mut rest = records;
+        while !rest.is_empty() {
+            let at = Location { segment: start.segment, offset: start.offset + (records.len() - rest.len()) as u64 };
+            if ActionKV::read_entry(&mut rest, at, &self.keys)?.is_none() {
+                return Err(Error::TruncatedRecord { segment: at.segment, offset: at.offset });
+            }
+        }
+
+        self.replace_with(records)
+    }
+
+    /// Rebuilds the hint file or persistent index after records have moved
+    fn refresh_index_files(&mut self) -> Result<()> {
+        let end = self.end()?;
+
+        match &mut self.index_file {
+            Some(index_file) => {
+                index_file.rewrite(&self.index, &self.expiries, end)?;
+                Ok(())
+            },
+            None => self.write_hints(),
+        }
+    }
+
+    #[inline]
+    pub fn update(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr
+    ) -> Result<()> {
+        self.insert(key, value)
+    }
+
+    /// Inserts many keys at once, without the work of inserting them one by
+    /// one. The pairs are sorted by key and written out as a single sorted
+    /// run of records, and the index, along with the hint or index file, is
+    /// brought up to date once rather than a record at a time. A key that
+    /// comes up more than once gets its last value. Returns how many records
+    /// were written.
+    ///
+    /// Pairs are sorted `IMPORT_RUN_LEN` bytes of keys and values at a time,
+    /// so however many pairs there are, only that much is held in memory.
+    /// When there is more than that, each sorted batch is spilled to a file
+    /// beside the store (`FILE.import0`, `FILE.import1`, ...) and the
+    /// batches are merged as the run is written, then the files removed. A
+    /// directory store still starts a new segment whenever one fills up, so
+    /// the run may be spread over several.
+    pub fn import<I: IntoIterator<Item = (ByteString, ByteString)>>(&mut self, pairs: I) -> Result<usize> {
+        self.import_in_runs(pairs, IMPORT_RUN_LEN)
+    }
+
+    fn import_in_runs<I: IntoIterator<Item = (ByteString, ByteString)>>(&mut self, pairs: I, run_len: usize) -> Result<usize> {
+        self.check_writable()?;
+
+        let mut spilled = Vec::new();
+        let imported = self.sorted_batches(pairs, run_len, &mut spilled)
+            .and_then(|batches| self.write_run(MergedBatches::new(batches)?, run_len));
+        for path in spilled {
+            let _ = fs::remove_file(path);
+        }
+        let imported = imported?;
+
+        self.refresh_index_files()?;
+        self.after_write()?;
+        Ok(imported)
+    }
+
+    /// Sorts `pairs` into batches of about `run_len` bytes of keys and
+    /// values. The last batch is kept in memory and the others are spilled,
+    /// with the files they went to pushed onto `spilled`.
+    fn sorted_batches<I: IntoIterator<Item = (ByteString, ByteString)>>(
+        &self,
+        pairs: I,
+        run_len: usize,
+        spilled: &mut Vec<PathBuf>,
+    ) -> Result<Vec<ImportBatch>> {
+        let mut pairs = pairs.into_iter().peekable();
+        let mut batches = Vec::new();
+        loop {
+            let mut sorted = BTreeMap::new();
+            let mut held = 0;
+            for (key, value) in pairs.by_ref() {
+                held += key.len() + value.len();
+                sorted.insert(key, value);
+                if held >= run_len {
+                    break;
+                }
+            }
+
+            if pairs.peek().is_none() {
+                batches.push(ImportBatch::Held(sorted.into_iter()));
+                return Ok(batches);
+            }
+            let path = self.store_file_path(&format!("import{}", batches.len()));
+            spilled.push(path.clone());
+            batches.push(ImportBatch::spill(&path, sorted)?);
+        }
+    }
+
+    /// Writes a run of imported pairs, which come in key order, and points
+    /// the index at them. At most about `run_len` bytes of records are
+    /// buffered before they're written. Returns how many were written.
+    fn write_run<I: Iterator<Item = Result<(ByteString, ByteString)>>>(&mut self, sorted: I, run_len: usize) -> Result<usize> {
+        let now = self.options.clock.now();
+        let cipher = self.options.encryption_key.clone();
+
+        let mut run = ByteString::new();
+        let mut at = self.end()?;
+        let mut locations = Vec::new();
+        for pair in sorted {
+            let (key, value) = pair?;
+            let mut record = ByteString::new();
+            let (stored, flags) = self.encode_value(&value)?;
+            ActionKV::write_record(&mut record, &key, &stored, flags, None, Some(now), cipher.as_ref())?;
+
+            let size = at.offset + run.len() as u64;
+            if self.directory && size > header::LEN && size + record.len() as u64 > self.options.segment_size {
+                self.active_segment().f.write_all(&run)?;
+                run.clear();
+                self.rotate()?;
+                at = self.end()?;
+            } else if run.len() >= run_len {
+                self.active_segment().f.write_all(&run)?;
+                at.offset += run.len() as u64;
+                run.clear();
+            }
+
+            locations.push((key, Location { segment: at.segment, offset: at.offset + run.len() as u64 }));
+            run.extend(record);
+        }
+        self.active_segment().f.write_all(&run)?;
+
+        let written = locations.len();
+        for (key, location) in locations {
+            self.expiries.remove(&key);
+            self.index.insert(key, location);
+        }
+        Ok(written)
+    }
+
+    /// Deletes a key by appending a tombstone record for it
+    /// and dropping it from the index
+    pub fn delete(
+        &mut self,
+        key: &ByteStr
+    ) -> Result<()> {
+        let (_, end) = self.append_record(key, b"", FLAG_TOMBSTONE, None)?;
+
+        if let Some(index_file) = &mut self.index_file {
+            index_file.append_delete(key, end)?;
+        }
+
+        self.expiries.remove(key);
+        self.index.remove(key);
+        self.after_write()
+    }
+
+    /// Writes every put and delete in the batch as a single frame, so that
+    /// either all of them survive a crash or none of them do
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let now = self.options.clock.now();
+        let cipher = self.options.encryption_key.as_ref();
+
+        // Lay the records out one after another, noting where each starts
+        // and when it expires
+        let mut payload = ByteString::new();
+        let mut records = Vec::with_capacity(batch.len());
+        for op in &batch.ops {
+            let expires_at = op.ttl.map(|ttl| now + ttl);
+            records.push((payload.len() as u64, expires_at));
+            match &op.value {
+                Some(value) => {
+                    let (stored, flags) = self.encode_value(value)?;
+                    ActionKV::write_record(&mut payload, &op.key, &stored, flags, expires_at, Some(now), cipher)?
+                },
+                None => ActionKV::write_record(&mut payload, &op.key, b"", FLAG_TOMBSTONE, None, Some(now), cipher)?,
+            };
+        }
+
+        let (frame, end) = self.append_record(b"", &payload, FLAG_BATCH, None)?;
+
+        for (op, (offset, expires_at)) in batch.ops.iter().zip(records) {
+            let location = Location {
+                segment: frame.segment,
+                offset: frame.offset + HEADER_LEN + offset,
+            };
+
+            match op.value {
+                Some(_) => {
+                    if let Some(index_file) = &mut self.index_file {
+                        index_file.append_put(&op.key, location, expires_at, end)?;
+                    }
+                    self.set_expiry(&op.key, expires_at);
+                    self.index.insert(op.key.clone(), location);
+                },
+                None => {
+                    if let Some(index_file) = &mut self.index_file {
+                        index_file.append_delete(&op.key, end)?;
+                    }
+                    self.expiries.remove(&op.key);
+                    self.index.remove(&op.key);
+                },
+            }
+        }
+
+        self.after_write()
+    }
+
+    /// Syncs the store if its sync policy says a sync is due after a write
+    fn after_write(&mut self) -> Result<()> {
+        match self.sync_state.record_write() {
+            true => self.sync(),
+            false => Ok(()),
+        }
+    }
+
+    /// Hands any writes still buffered by the store over to the operating
+    /// system. They survive the process crashing, but not the machine
+    /// losing power until they are synced.
+    pub fn flush(&mut self) -> Result<()> {
+        self.active_segment().f.flush()?;
+        if let Some(index_file) = &mut self.index_file {
+            index_file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every write made so far all the way to disk, whatever the
+    /// sync policy. Sealed segments were synced when they were sealed, so
+    /// only the active segment and the index file need it.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        for f in self.sync_handles()? {
+            f.sync_data()?;
+        }
+
+        self.sync_state.synced();
+        Ok(())
+    }
+
+    /// Separate handles to the files that a sync has to flush, so the sync
+    /// can happen without holding on to the store
+    pub(crate) fn sync_handles(&mut self) -> Result<Vec<File>> {
+        let mut handles = vec![self.active_segment().f.try_clone()?];
+        if let Some(index_file) = &self.index_file {
+            handles.push(index_file.try_clone_file()?);
+        }
+        Ok(handles)
+    }
+}
+
+/// The path of a file that belongs to the whole store rather than to one
+/// segment, e.g. `database.txt.hint` for a store kept in `database.txt`,
+/// or `DIR/store.hint` for a store kept in a directory
+fn store_file_path(path: &Path, directory: bool, extension: &str) -> PathBuf {
+    if directory {
+        return path.join(format!("store.{}", extension));
+    }
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// The header segment files are started with, given how the store is set up
+fn new_file_header(options: &Options) -> FileHeader {
+    let mut file_header = FileHeader::current(options.compression);
+    if options.encryption_key.is_some() {
+        file_header.flags |= header::ENCRYPTED;
+    }
+    file_header
+}
+
+/// Checks that a store's encryption settings make sense for its segments:
+/// an encrypted store can't be opened without a key, and a persistent index
+/// would give away the keys of one
+fn check_encryption(segments: &[Segment], options: &Options) -> Result<()> {
+    if options.encryption_key.is_some() && options.persistent_index {
+        return Err(persistent_index_with_key());
+    }
+
+    if options.encryption_key.is_none() && segments.iter().any(|s| s.header.is_encrypted()) {
+        return Err(Error::KeyRequired);
+    }
+    Ok(())
+}
+
+/// The error for a store that would keep a persistent index while it is
+/// encrypted: the index file names every key in the clear
+fn persistent_index_with_key() -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "an encrypted store can't keep a persistent index",
+    ).into()
+}
+
+/// How many bytes the whole record with the given header takes up
+pub(crate) fn record_len(header: &[u8; HEADER_LEN as usize]) -> u64 {
+    let flags = header[7];
+    let key_len = u32::from_le_bytes([header[4], header[5], header[6], 0]);
+    let val_len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+    HEADER_LEN + data_len(flags, key_len as u64, val_len as u64)
+}
+
+/// How many bytes follow the header of a record with the given flags and
+/// lengths
+fn data_len(flags: u8, key_len: u64, val_len: u64) -> u64 {
+    let sealed = match flags & FLAG_ENCRYPTED {
+        0 => 0,
+        _ => crypto::OVERHEAD,
+    };
+    extras_len(flags) + sealed + key_len + val_len
+}
+
+/// How many bytes of expiry and write times and sequence number a record
+/// with `flags` has between its header and its key
+fn extras_len(flags: u8) -> u64 {
+    let mut len = 0;
+    if flags & FLAG_EXPIRES != 0 {
+        len += EXPIRY_LEN;
+    }
+    if flags & FLAG_TIMESTAMP != 0 {
+        len += TIMESTAMP_LEN;
+    }
+    if flags & FLAG_SEQUENCE != 0 {
+        len += SEQUENCE_LEN;
+    }
+    len
+}
+
+/// Checksums a record's key and value. Records with flags set also cover
+/// the flags byte, while unflagged records keep the original checksum so
+/// that older files still verify.
+fn record_checksum(flags: u8, data: &ByteStr) -> u32 {
+    if flags == 0 {
+        return crc32::checksum_ieee(data);
+    }
+
+    let checksum = crc32::checksum_ieee(&[flags]);
+    crc32::update(checksum, &crc32::IEEE_TABLE, data)
+}
+
+/// Decrypts an encrypted record's key and value, given its header and the
+/// bytes after it, with the first of `keys` it was sealed with. Returns
+/// `None` if it wasn't sealed with any of them, or has been damaged since.
+pub(crate) fn open_record(header: &[u8; HEADER_LEN as usize], data: &ByteStr, keys: &[EncryptionKey]) -> Option<ByteString> {
+    let (extras, sealed) = data.split_at(extras_len(header[7]) as usize);
+    crypto::open(keys, LittleEndian::read_u32(header), sealed, &record_aad(header, extras))
+}
+
+/// What an encrypted record's tag vouches for besides its key and value:
+/// the flags and lengths from its header, and its expiry and write times.
+/// Its sequence number is left out, so that compaction can add one.
+fn record_aad(header: &[u8; HEADER_LEN as usize], extras: &ByteStr) -> ByteString {
+    let mut aad = header[4..].to_vec();
+    aad[3] &= !FLAG_SEQUENCE;
+
+    let times = match header[7] & FLAG_SEQUENCE {
+        0 => extras,
+        _ => &extras[..extras.len() - SEQUENCE_LEN as usize],
+    };
+    aad.extend_from_slice(times);
+    aad
+}
+
+/// Writes a mark: an empty batch frame that carries a sequence number, so
+/// that the entries after it count on from there
+fn write_mark<W: Write>(f: &mut W, sequence: u64) -> io::Result<()> {
+    let flags = FLAG_BATCH | FLAG_SEQUENCE;
+    let data = sequence.to_le_bytes();
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    LittleEndian::write_u32(&mut header[..4], record_checksum(flags, &data));
+    LittleEndian::write_u32(&mut header[4..8], (flags as u32) << 24);
+
+    f.write_all(&header)?;
+    f.write_all(&data)
+}
+
+/// The record `raw`, stored as it is, made to carry its sequence number.
+/// The number goes after its expiry and write times, and its checksum is
+/// worked out again. An encrypted record's tag doesn't cover the number,
+/// so the record is left sealed as it was.
+fn numbered(mut raw: ByteString, sequence: u64) -> ByteString {
+    let flags = raw[7];
+    if flags & FLAG_SEQUENCE != 0 {
+        return raw;
+    }
+
+    let at = (HEADER_LEN + extras_len(flags)) as usize;
+    raw.splice(at..at, sequence.to_le_bytes());
+    raw[7] = flags | FLAG_SEQUENCE;
+
+    if flags & FLAG_ENCRYPTED == 0 {
+        let checksum = record_checksum(raw[7], &raw[HEADER_LEN as usize..]);
+        LittleEndian::write_u32(&mut raw[..4], checksum);
+    }
+    raw
+}
+
+/// The sequence number the entry `raw`, as it is stored, carries itself.
+/// Entries without one are numbered one on from the entry before them.
+pub(crate) fn entry_sequence(raw: &ByteStr) -> Option<u64> {
+    let flags = raw[7];
+    if flags & FLAG_SEQUENCE == 0 {
+        return None;
+    }
+
+    let end = (HEADER_LEN + extras_len(flags)) as usize;
+    Some(LittleEndian::read_u64(&raw[end - SEQUENCE_LEN as usize..end]))
+}
+
+/// The sequence numbers that a rewrite of a store's segments has to keep
+#[derive(Debug, Default)]
+struct Numbering {
+    /// The number of each record that might be kept
+    sequences: HashMap<Location, u64>,
+    /// The number of the last write in each segment
+    ends: HashMap<u32, u64>,
+    /// How many records each segment holds
+    records: HashMap<u32, usize>,
+    /// The number of the last write in the store
+    end: u64,
+    /// Whether the store ends with a mark, numbered `end`
+    marked: bool,
+}
+
+/// One batch of an import, sorted by key and either still in memory or
+/// spilled to a file of `key_len u64 | val_len u64 | key | val` entries
+enum ImportBatch {
+    Held(std::collections::btree_map::IntoIter<ByteString, ByteString>),
+    Spilled(BufReader<File>),
+}
+
+impl ImportBatch {
+    fn spill(path: &Path, sorted: BTreeMap<ByteString, ByteString>) -> Result<ImportBatch> {
+        let f = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let mut f = BufWriter::new(f);
+        for (key, value) in sorted {
+            f.write_u64::<LittleEndian>(key.len() as u64)?;
+            f.write_u64::<LittleEndian>(value.len() as u64)?;
+            f.write_all(&key)?;
+            f.write_all(&value)?;
+        }
+
+        let mut f = f.into_inner().map_err(|err| err.into_error())?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(ImportBatch::Spilled(BufReader::new(f)))
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(ByteString, ByteString)>> {
+        let f = match self {
+            ImportBatch::Held(pairs) => return Ok(pairs.next()),
+            ImportBatch::Spilled(f) => f,
+        };
+
+        let key_len = match f.read_u64::<LittleEndian>() {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let val_len = f.read_u64::<LittleEndian>()?;
+        let mut key = vec![0; key_len as usize];
+        f.read_exact(&mut key)?;
+        let mut value = vec![0; val_len as usize];
+        f.read_exact(&mut value)?;
+        Ok(Some((key, value)))
+    }
+}
+
+/// The pairs of several sorted import batches merged into one sorted run.
+/// A key in more than one batch gets its value from the latest.
+struct MergedBatches {
+    batches: Vec<ImportBatch>,
+    /// The next key of each batch that has any left, smallest first
+    heads: BinaryHeap<Reverse<(ByteString, usize)>>,
+    /// The value that goes with each batch's head
+    values: Vec<Option<ByteString>>,
+}
+
+impl MergedBatches {
+    fn new(batches: Vec<ImportBatch>) -> Result<MergedBatches> {
+        let mut merged = MergedBatches {
+            values: vec![None; batches.len()],
+            batches,
+            heads: BinaryHeap::new(),
+        };
+        for n in 0..merged.batches.len() {
+            merged.advance(n)?;
+        }
+        Ok(merged)
+    }
+
+    /// Moves batch `n` on to its next pair
+    fn advance(&mut self, n: usize) -> Result<()> {
+        self.values[n] = match self.batches[n].next_pair()? {
+            Some((key, value)) => {
+                self.heads.push(Reverse((key, n)));
+                Some(value)
+            },
+            None => None,
+        };
+        Ok(())
+    }
+}
+
+impl Iterator for MergedBatches {
+    type Item = Result<(ByteString, ByteString)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, mut n)) = self.heads.pop()?;
+        // Ties pop in batch order, so the last one popped is the latest
+        while self.heads.peek().is_some_and(|Reverse((next, _))| *next == key) {
+            let Reverse((_, later)) = self.heads.pop()?;
+            if let Err(err) = self.advance(n) {
+                return Some(Err(err));
+            }
+            n = later;
+        }
+
+        let value = self.values[n].take()?;
+        match self.advance(n) {
+            Ok(()) => Some(Ok((key, value))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// The path a compaction writes to before it is swapped in, e.g.
+/// `database.txt` is compacted into `database.txt.compact`
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+/// Where a segment file that is to replace the one at `path` is put
+/// together, e.g. `00000000.akv.clear` as a store is emptied
+pub(crate) fn staged_path(path: &Path, purpose: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(purpose);
+    PathBuf::from(name)
+}
+
+/// Starts replacing the segments of the directory store at `dir` with the
+/// ones staged for `purpose`, which must all be on disk already. The marker
+/// (`store.clear`, say) lists the segments the store is left with, and once
+/// it is written the replacement is carried through by
+/// `finish_replacing`, there and then or the next time the store is opened.
+pub(crate) fn begin_replacing(dir: &Path, purpose: &str, ids: &[u32]) -> io::Result<()> {
+    let marker = store_file_path(dir, true, purpose);
+    let mut f = File::create(&marker)?;
+    for id in ids {
+        f.write_u32::<LittleEndian>(*id)?;
+    }
+    f.sync_all()?;
+    sync_parent_dir(&marker)
+}
+
+/// Finishes replacing the segments of the directory store at `dir` if it
+/// was started for `purpose`: every staged segment is renamed into place,
+/// and every segment the marker doesn't list is removed, along with the
+/// hint and index files. A marker cut short means the replacement never
+/// began, so the store is left as it was.
+pub(crate) fn finish_replacing(dir: &Path, purpose: &str, read_only: bool) -> Result<()> {
+    let marker = store_file_path(dir, true, purpose);
+    let ids: Option<Vec<u32>> = match fs::read(&marker) {
+        Ok(bytes) if !bytes.is_empty() && bytes.len().is_multiple_of(4) => {
+            Some(bytes.chunks(4).map(LittleEndian::read_u32).collect())
+        },
+        Ok(_) => None,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if read_only {
+        return match ids {
+            Some(_) => Err(io::Error::other(format!(
+                "the store was interrupted part way through a {}; open it for writing to finish",
+                purpose,
+            )).into()),
+            None => Ok(()),
+        };
+    }
+
+    if let Some(ids) = ids {
+        for id in &ids {
+            let path = segment::segment_path(dir, *id);
+            let staged = staged_path(&path, purpose);
+            if staged.exists() {
+                fs::rename(&staged, &path)?;
+                sync_parent_dir(&path)?;
+            }
+        }
+
+        for id in segment::list(dir)? {
+            if !ids.contains(&id) {
+                let stale = segment::segment_path(dir, id);
+                fs::remove_file(&stale)?;
+                sync_parent_dir(&stale)?;
+            }
+        }
+
+        hint::remove(&store_file_path(dir, true, "hint"))?;
+        index_file::remove(&store_file_path(dir, true, "idx"))?;
+    }
+
+    fs::remove_file(&marker)?;
+    sync_parent_dir(&marker)?;
+    Ok(())
+}
+
+/// Reads into `buf` until it is full or the reader runs out of data,
+/// returning how many bytes were read
+fn read_up_to<R: Read>(f: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match f.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Flushes the directory entry of a renamed file, so the rename itself
+/// survives a crash
+#[cfg(not(target_os = "windows"))]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Windows doesn't allow directories to be opened as files, and its
+/// renames are flushed along with the file itself
+#[cfg(target_os = "windows")]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_temp(dir: &tempfile::TempDir) -> ActionKV {
+        let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn compact_keeps_only_live_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.update(b"apple", b"3").unwrap();
+        store.update(b"apple", b"4").unwrap();
+
+        let before = fs::metadata(&store.path).unwrap().len();
+        store.compact().unwrap();
+        let after = fs::metadata(&store.path).unwrap().len();
+
+        assert!(after < before);
+        assert_eq!(store.get(b"apple").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert!(!compaction_path(&store.path).exists());
+
+        // Writes after compaction land after the compacted records
+        store.insert(b"cherry", b"5").unwrap();
+        drop(store);
+        let mut reopened = open_temp(&dir);
+        assert_eq!(reopened.get(b"apple").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(reopened.get(b"cherry").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(reopened.index.len(), 3);
+    }
+
+    #[test]
+    fn interrupted_compaction_leaves_original_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+
+        // A crash before the rename leaves a half-written file behind
+        fs::write(compaction_path(&store.path), b"garbage").unwrap();
+        drop(store);
+
+        let mut reopened = open_temp(&dir);
+        assert_eq!(reopened.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        reopened.compact().unwrap();
+        assert_eq!(reopened.get(b"apple").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn history_lists_every_version_of_a_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.update(b"apple", b"3").unwrap();
+        store.delete(b"apple").unwrap();
+
+        let values: Vec<_> = store.history(b"apple").unwrap().into_iter().map(|v| v.value).collect();
+        assert_eq!(values, vec![Some(b"1".to_vec()), Some(b"3".to_vec()), None]);
+        assert!(store.history(b"cherry").unwrap().is_empty());
+    }
+
+    #[test]
+    fn deleted_keys_are_distinct_from_empty_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+
+        store.insert(b"empty", b"").unwrap();
+        store.insert(b"gone", b"1").unwrap();
+        store.delete(b"gone").unwrap();
+
+        assert_eq!(store.get(b"empty").unwrap(), Some(vec![]));
+        assert_eq!(store.get(b"gone").unwrap(), None);
+        assert_eq!(store.find(b"gone").unwrap(), None);
+        drop(store);
+
+        let mut reopened = open_temp(&dir);
+        assert_eq!(reopened.get(b"empty").unwrap(), Some(vec![]));
+        assert_eq!(reopened.get(b"gone").unwrap(), None);
+        assert!(!reopened.index.contains_key(b"gone".as_ref()));
+
+        // Deleting and reinserting brings the key back
+        reopened.insert(b"gone", b"2").unwrap();
+        assert_eq!(reopened.get(b"gone").unwrap(), Some(b"2".to_vec()));
+    }
+
+    /// Writes two good records followed by one whose value has been
+    /// damaged, and returns the offset of the damaged record
+    fn write_corrupt_store(dir: &tempfile::TempDir) -> u64 {
+        let mut store = open_temp(dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        let bad = store.insert_but_ignore_index(b"cherry", b"3").unwrap().offset;
+        store.insert(b"damson", b"4").unwrap();
+
+        let mut bytes = fs::read(&store.path).unwrap();
+        let last_value = bad as usize + 12 + 8 + b"cherry".len();
+        bytes[last_value] ^= 0xff;
+        fs::write(&store.path, bytes).unwrap();
+
+        bad
+    }
+
+    fn open_with_recovery(dir: &tempfile::TempDir, recovery: Recovery) -> Result<ActionKV> {
+        let options = Options { recovery, ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options)?;
+        store.load()?;
+        Ok(store)
+    }
+
+    #[test]
+    fn corrupt_records_are_handled_by_recovery_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let bad = write_corrupt_store(&dir);
+
+        match open_with_recovery(&dir, Recovery::Fail) {
+            Err(Error::ChecksumMismatch { offset, .. }) => assert_eq!(offset, bad),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+
+        let mut skipped = open_with_recovery(&dir, Recovery::Skip).unwrap();
+        assert_eq!(skipped.get(b"cherry").unwrap(), None);
+        assert_eq!(skipped.get(b"damson").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(skipped.verify().unwrap().len(), 1);
+        drop(skipped);
+
+        let mut truncated = open_with_recovery(&dir, Recovery::Truncate).unwrap();
+        assert_eq!(truncated.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(truncated.get(b"damson").unwrap(), None);
+        assert_eq!(fs::metadata(&truncated.path).unwrap().len(), bad);
+        assert!(truncated.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn torn_writes_are_reported_as_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        let torn = store.insert_but_ignore_index(b"banana", b"2").unwrap().offset;
+
+        let len = fs::metadata(&store.path).unwrap().len();
+        store.active_segment().f.set_len(len - 1).unwrap();
+
+        let errors = store.verify().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], Error::TruncatedRecord { offset, .. } if offset == torn));
+        drop(store);
+
+        // Skipping cuts off the torn record, so later appends stay readable
+        let mut skipped = open_with_recovery(&dir, Recovery::Skip).unwrap();
+        skipped.insert(b"cherry", b"3").unwrap();
+        drop(skipped);
+        let mut reopened = open_temp(&dir);
+        assert_eq!(reopened.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(reopened.get(b"cherry").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn load_uses_hints_and_scans_the_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.close().unwrap();
+
+        // Damage a value the hints cover. Loading from the hints doesn't
+        // read values, so it doesn't notice
+        let path = dir.path().join("store.akv");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[16 + 12 + 8 + b"apple".len()] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = open_temp(&dir);
+        assert!(store.index.contains_key(b"apple".as_ref()));
+        store.insert(b"cherry", b"3").unwrap();
+        store.delete(b"banana").unwrap();
+        drop(store);
+
+        // Records written after the hints are picked up from the file
+        let store = open_temp(&dir);
+        assert!(store.index.contains_key(b"cherry".as_ref()));
+        assert!(!store.index.contains_key(b"banana".as_ref()));
+
+        // Without hints the damaged value is found by a full scan
+        fs::remove_file(store.hint_path()).unwrap();
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        assert!(matches!(store.load(), Err(Error::ChecksumMismatch { offset: 16, .. })));
+    }
+
+    #[test]
+    fn stale_hints_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.write_hints().unwrap();
+
+        // The data file is now shorter than the hints say it is
+        store.active_segment().f.set_len(header::LEN).unwrap();
+        store.insert(b"cherry", b"3").unwrap();
+        drop(store);
+
+        let store = open_temp(&dir);
+        assert_eq!(store.index.len(), 1);
+        assert!(store.index.contains_key(b"cherry".as_ref()));
+        assert!(!store.hint_path().exists());
+    }
+
+    #[test]
+    fn persistent_index_is_kept_in_its_own_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"+index").unwrap(), None);
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"+index", b"2").unwrap();
+        store.insert(b"banana", b"3").unwrap();
+        store.delete(b"banana").unwrap();
+        drop(store);
+
+        // Damage a value. Loading from the index file doesn't read values,
+        // so it can only succeed if the index came from the index file
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[16 + 12 + 8 + b"apple".len()] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"+index").unwrap(), Some(b"2".to_vec()));
+        assert!(!store.hint_path().exists());
+    }
+
+    #[test]
+    fn persistent_index_is_rebuilt_when_it_does_not_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+
+        drop(store);
+
+        // Writes that bypass the index file are found by scanning the tail
+        let mut plain = open_temp(&dir);
+        plain.insert(b"cherry", b"3").unwrap();
+        drop(plain);
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"cherry").unwrap(), Some(b"3".to_vec()));
+
+        // An index file describing more data than there is gets thrown away
+        store.active_segment().f.set_len(header::LEN).unwrap();
+        store.insert(b"damson", b"4").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 1);
+        assert_eq!(store.get(b"damson").unwrap(), Some(b"4".to_vec()));
+    }
+
+    fn open_segmented(dir: &tempfile::TempDir) -> ActionKV {
+        let options = Options { segment_size: 88, ..Options::default() };
+        let mut store = ActionKV::open_dir(&dir.path().join("store"), options).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn segments_rotate_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_segmented(&dir);
+
+        // Each record is 12 + 8 + 5 + 20 = 45 bytes, so only one fits in a
+        // segment along with its 16 byte header
+        for (i, key) in [b"apple", b"berry", b"apple", b"peach"].iter().enumerate() {
+            store.insert(*key, &[b'0' + i as u8; 20]).unwrap();
+        }
+
+        assert_eq!(segment::list(&store.path).unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(store.index[b"apple".as_ref()], Location { segment: 2, offset: 16 });
+        store.close().unwrap();
+
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(b"apple").unwrap(), Some(vec![b'2'; 20]));
+        assert_eq!(store.get(b"berry").unwrap(), Some(vec![b'1'; 20]));
+        assert_eq!(store.find(b"peach").unwrap().unwrap().0.segment, 3);
+    }
+
+    #[test]
+    fn compacting_a_segment_keeps_tombstones_that_hide_older_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_segmented(&dir);
+
+        store.insert(b"apple", &[b'1'; 20]).unwrap();
+        store.insert(b"berry", &[b'2'; 20]).unwrap();
+        store.delete(b"apple").unwrap();
+        store.insert(b"peach", &[b'3'; 20]).unwrap();
+        assert_eq!(segment::list(&store.path).unwrap(), vec![0, 1, 2]);
+
+        // Segment 1 holds the tombstone hiding the value in segment 0
+        store.compact_segment(1).unwrap();
+        store.compact_segment(0).unwrap();
+        assert_eq!(segment::list(&store.path).unwrap(), vec![1, 2]);
+        drop(store);
+
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        assert_eq!(store.get(b"berry").unwrap(), Some(vec![b'2'; 20]));
+
+        // A full compaction can drop the tombstone too
+        store.compact().unwrap();
+        drop(store);
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        assert_eq!(store.get(b"peach").unwrap(), Some(vec![b'3'; 20]));
+        assert!(store.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_batches_are_applied_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"banana", b"2").put(b"cherry", b"3").delete(b"apple");
+        store.write_batch(&batch).unwrap();
+
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.find(b"cherry").unwrap().unwrap().1, b"3".to_vec());
+        drop(store);
+
+        let mut reopened = open_temp(&dir);
+        assert_eq!(reopened.get(b"apple").unwrap(), None);
+        assert_eq!(reopened.get(b"cherry").unwrap(), Some(b"3".to_vec()));
+
+        // Compacting copies the batched records out of their frame
+        reopened.compact().unwrap();
+        assert_eq!(reopened.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert!(reopened.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn torn_write_batches_are_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"apple", b"2").put(b"banana", b"3");
+        store.write_batch(&batch).unwrap();
+
+        // Lose the end of the frame, as if the write was cut short
+        let len = fs::metadata(&store.path).unwrap().len();
+        store.active_segment().f.set_len(len - 1).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_with(
+            &dir.path().join("store.akv"),
+            Options { recovery: Recovery::Skip, ..Options::default() },
+        ).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"banana").unwrap(), None);
+    }
+
+    #[test]
+    fn scans_return_keys_in_order() {
+        for ordered_index in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let options = Options { ordered_index, ..Options::default() };
+            let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.index.is_ordered(), ordered_index);
+
+            for key in [b"b2".as_ref(), b"a", b"b1", b"b\xff", b"c", b"b3"] {
+                store.insert(key, key).unwrap();
+            }
+            store.delete(b"b3").unwrap();
+
+            let keys = |scan: Scan| -> Vec<ByteString> {
+                scan.map(|kv| kv.unwrap().0).collect()
+            };
+
+            assert_eq!(
+                keys(store.scan_prefix(b"b")),
+                vec![b"b1".to_vec(), b"b2".to_vec(), b"b\xff".to_vec()],
+            );
+            assert_eq!(
+                keys(store.scan(b"a".to_vec()..b"b2".to_vec())),
+                vec![b"a".to_vec(), b"b1".to_vec()],
+            );
+            assert_eq!(keys(store.scan(..)).len(), 5);
+            assert!(keys(store.scan(b"c".to_vec()..b"a".to_vec())).is_empty());
+
+            let (key, value) = store.scan_prefix(b"c").next().unwrap().unwrap();
+            assert_eq!((key, value), (b"c".to_vec(), b"c".to_vec()));
+        }
+    }
+
+    fn open_with_clock(path: &Path, clock: &Arc<ManualClock>, persistent_index: bool) -> ActionKV {
+        let options = Options { clock: clock.clone(), persistent_index, ..Options::default() };
+        let mut store = ActionKV::open_with(path, options).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn expired_keys_are_treated_as_absent() {
+        for persistent_index in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("store.akv");
+            let clock = Arc::new(ManualClock::new(SystemTime::now()));
+
+            let mut store = open_with_clock(&path, &clock, persistent_index);
+            store.insert_with_ttl(b"apple", b"1", Duration::from_secs(10)).unwrap();
+            store.insert_with_ttl(b"banana", b"2", Duration::from_secs(60)).unwrap();
+            store.insert(b"cherry", b"3").unwrap();
+            store.close().unwrap();
+
+            let mut store = open_with_clock(&path, &clock, persistent_index);
+            assert_eq!(store.get(b"apple").unwrap(), Some(b"1".to_vec()));
+
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(store.get(b"apple").unwrap(), None);
+            assert_eq!(store.find(b"apple").unwrap(), None);
+            assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(store.scan(..).count(), 2);
+
+            // Loading again, from hints or the index file, drops the key
+            drop(store);
+            let mut store = open_with_clock(&path, &clock, persistent_index);
+            assert!(!store.index.contains_key(b"apple".as_ref()));
+            assert_eq!(store.index.len(), 2);
+
+            // Writing the key without a time to live makes it permanent
+            store.insert(b"banana", b"4").unwrap();
+            clock.advance(Duration::from_secs(60));
+            assert_eq!(store.get(b"banana").unwrap(), Some(b"4".to_vec()));
+        }
+    }
+
+    #[test]
+    fn expired_records_hide_older_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let options = Options { segment_size: 64, clock: clock.clone(), ..Options::default() };
+
+        let mut store = ActionKV::open_dir(&dir.path().join("store"), options.clone()).unwrap();
+        store.load().unwrap();
+        store.insert(b"apple", &[b'1'; 40]).unwrap();
+        store.insert(b"peach", &[b'2'; 40]).unwrap();
+        store.insert_with_ttl(b"apple", b"3", Duration::from_secs(5)).unwrap();
+        let newest = store.segments.last().unwrap().id;
+        assert!(newest > 0);
+
+        clock.advance(Duration::from_secs(5));
+        store.compact_segment(newest).unwrap();
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        drop(store);
+
+        // The expired record is kept, so the older value stays hidden
+        hint::remove(&dir.path().join("store").join("store.hint")).unwrap();
+        let mut store = ActionKV::open_dir(&dir.path().join("store"), options).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        assert_eq!(store.get(b"peach").unwrap(), Some(vec![b'2'; 40]));
+        assert!(store.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshots_see_the_store_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clock = Arc::new(ManualClock::new(start));
+        let mut store = open_with_clock(&dir.path().join("store.akv"), &clock, false);
+
+        store.insert(b"apple", b"1").unwrap();
+        clock.advance(Duration::from_secs(1));
+        store.insert_with_ttl(b"banana", b"2", Duration::from_secs(5)).unwrap();
+        clock.advance(Duration::from_secs(10));
+        let mut batch = WriteBatch::new();
+        batch.put(b"apple", b"3");
+        batch.delete(b"cherry");
+        store.write_batch(&batch).unwrap();
+        store.delete(b"apple").unwrap();
+
+        let history = store.history(b"apple").unwrap();
+        let times: Vec<_> = history.iter().map(|v| (v.sequence, v.written_at)).collect();
+        assert_eq!(times, vec![
+            (1, Some(start)),
+            (3, Some(start + Duration::from_secs(11))),
+            (4, Some(start + Duration::from_secs(11))),
+        ]);
+
+        let mut snapshot = store.snapshot_at(2).unwrap();
+        assert_eq!(snapshot.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"banana").unwrap(), Some(b"2".to_vec()));
+
+        // By the time of the batch, banana's time to live had run out
+        let mut snapshot = store.snapshot_at(3).unwrap();
+        assert_eq!(snapshot.get(b"apple").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(snapshot.keys(), vec![b"apple".to_vec()]);
+
+        assert!(store.snapshot_at(4).unwrap().keys().is_empty());
+        assert!(store.snapshot_at(0).unwrap().keys().is_empty());
+    }
+
+    #[test]
+    fn sequence_numbers_survive_compaction() {
+        for open in [open_temp, open_segmented] {
+            check_sequences_survive_compaction(open);
+        }
+    }
+
+    #[test]
+    fn compacting_a_compacted_store_changes_nothing() {
+        for open in [open_temp, open_segmented] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut store = open(&dir);
+            for i in 0..6 {
+                store.insert(format!("key{}", i).as_bytes(), b"value").unwrap();
+            }
+            store.update(b"key0", b"again").unwrap();
+            store.delete(b"key1").unwrap();
+            store.compact().unwrap();
+
+            let size = |store: &mut ActionKV| store.segments.iter_mut().map(|s| s.size().unwrap()).sum::<u64>();
+            let compacted = size(&mut store);
+            let whole_from = store.for_each_record(|_, _, _| {}).unwrap();
+
+            for _ in 0..3 {
+                store.compact().unwrap();
+                let ids: Vec<u32> = store.segments.iter().map(|s| s.id).collect();
+                for id in ids {
+                    store.compact_segment(id).unwrap();
+                }
+            }
+            assert_eq!(size(&mut store), compacted);
+            assert_eq!(store.for_each_record(|_, _, _| {}).unwrap(), whole_from);
+        }
+    }
+
+    fn check_sequences_survive_compaction(open: fn(&tempfile::TempDir) -> ActionKV) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open(&dir);
+
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.update(b"apple", b"3").unwrap();
+        store.update(b"apple", b"4").unwrap();
+        store.compact().unwrap();
+
+        let sequences = |store: &mut ActionKV, key: &ByteStr| -> Vec<u64> {
+            store.history(key).unwrap().into_iter().map(|v| v.sequence).collect()
+        };
+        assert_eq!(sequences(&mut store, b"apple"), vec![4]);
+        assert_eq!(sequences(&mut store, b"banana"), vec![2]);
+
+        // The versions compaction threw away can't be looked back on
+        let err = store.snapshot_at(3).map(|_| ()).unwrap_err();
+        assert!(matches!(err, Error::HistoryCompacted { sequence: 3, whole_from: 4 }), "{:?}", err);
+        let mut snapshot = store.snapshot_at(4).unwrap();
+        assert_eq!(snapshot.get(b"apple").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(snapshot.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        drop(snapshot);
+
+        store.insert(b"cherry", b"5").unwrap();
+        drop(store);
+
+        let mut reopened = open(&dir);
+        assert_eq!(sequences(&mut reopened, b"apple"), vec![4]);
+        assert_eq!(sequences(&mut reopened, b"cherry"), vec![5]);
+        assert!(reopened.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_read_while_the_store_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        for i in 0..50u8 {
+            store.insert(&[i], &[i]).unwrap();
+        }
+
+        let snapshot = store.snapshot().unwrap();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        for i in 0..50u8 {
+                            assert_eq!(snapshot.get(&[i]).unwrap(), Some(vec![i]));
+                        }
+                        assert_eq!(snapshot.get(&[200]).unwrap(), None);
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..50u8 {
+            store.insert(&[i], &[i + 100]).unwrap();
+            store.delete(&[i]).unwrap();
+            store.insert(&[200], b"new").unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(snapshot.len(), 50);
+        assert_eq!(store.snapshot().unwrap().keys(), vec![vec![200]]);
+    }
+
+    #[test]
+    fn snapshots_outlive_the_store_being_emptied() {
+        let dir = tempfile::tempdir().unwrap();
+        for mut store in [open_temp(&dir), open_segmented(&dir)] {
+            for i in 0..20u8 {
+                store.insert(&[i], b"apple").unwrap();
+            }
+
+            let snapshot = store.snapshot().unwrap();
+            store.clear().unwrap();
+            store.insert(b"banana", b"2").unwrap();
+
+            for i in 0..20u8 {
+                assert_eq!(snapshot.get(&[i]).unwrap(), Some(b"apple".to_vec()));
+            }
+            assert_eq!(store.snapshot().unwrap().keys(), vec![b"banana".to_vec()]);
+            assert_eq!(store.segments.len(), 1);
+        }
+    }
+
+    #[test]
+    fn restoring_records_replaces_the_store_in_one_go() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = ActionKV::open(&dir.path().join("source.akv")).unwrap();
+        source.load().unwrap();
+        source.insert(b"apple", b"1").unwrap();
+        source.insert(b"banana", b"2").unwrap();
+        source.delete(b"apple").unwrap();
+        let records = source.live_records().unwrap();
+
+        let mut store = open_segmented(&dir);
+        for i in 0..20u8 {
+            store.insert(&[i], b"cherry").unwrap();
+        }
+        let snapshot = store.snapshot().unwrap();
+
+        // Records cut short are turned away before anything changes
+        let err = store.restore_records(&records[..records.len() - 1]).unwrap_err();
+        assert!(matches!(err, Error::TruncatedRecord { .. }), "{:?}", err);
+        assert_eq!(store.get(&[0]).unwrap(), Some(b"cherry".to_vec()));
+
+        store.restore_records(&records).unwrap();
+        assert_eq!(store.snapshot().unwrap().keys(), vec![b"banana".to_vec()]);
+        assert_eq!(store.segments.len(), 1);
+        assert_eq!(snapshot.get(&[19]).unwrap(), Some(b"cherry".to_vec()));
+        drop(store);
+
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(&[0]).unwrap(), None);
+    }
+
+    #[test]
+    fn an_interrupted_clear_is_finished_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store");
+        let mut store = open_segmented(&dir);
+        for i in 0..20u8 {
+            store.insert(&[i], b"apple").unwrap();
+        }
+        assert!(store.segments.len() > 1);
+        drop(store);
+
+        // As if the store stopped once the new first segment and the marker
+        // were written, but before either was put into place
+        let first = segment::segment_path(&path, 0);
+        let mut f = File::create(staged_path(&first, "clear")).unwrap();
+        FileHeader::current(Compression::None).write(&mut f).unwrap();
+        fs::write(path.join("store.clear"), 0u32.to_le_bytes()).unwrap();
+
+        let options = Options { read_only: true, ..Options::default() };
+        assert!(ActionKV::open_dir(&path, options).is_err());
+
+        let mut store = open_segmented(&dir);
+        assert!(store.snapshot().unwrap().keys().is_empty());
+        assert_eq!(segment::list(&path).unwrap(), vec![0]);
+        assert!(!path.join("store.clear").exists());
+        store.insert(b"banana", b"2").unwrap();
+        drop(store);
+
+        // A marker cut short means the store was never emptied
+        fs::write(path.join("store.clear"), [0u8, 0]).unwrap();
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.snapshot().unwrap().keys(), vec![b"banana".to_vec()]);
+    }
+
+    #[test]
+    fn stores_are_locked_against_other_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut writer = open_temp(&dir);
+        writer.insert(b"apple", b"1").unwrap();
+        assert!(matches!(ActionKV::open(&path), Err(Error::Locked { .. })));
+        assert!(matches!(ActionKV::open_read_only(&path), Err(Error::Locked { .. })));
+        drop(writer);
+
+        // Any number of readers can share the store, but nobody can write
+        let mut reader = ActionKV::open_read_only(&path).unwrap();
+        reader.load().unwrap();
+        let mut other = ActionKV::open_read_only(&path).unwrap();
+        other.load().unwrap();
+        assert_eq!(reader.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        assert!(matches!(ActionKV::open(&path), Err(Error::Locked { .. })));
+        assert!(matches!(reader.insert(b"banana", b"2"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(Error::ReadOnly)));
+
+        // Closing a reader doesn't leave hints behind
+        let hint_path = reader.hint_path();
+        reader.close().unwrap();
+        drop(other);
+        assert!(!hint_path.exists());
+
+        let mut writer = open_temp(&dir);
+        writer.insert(b"banana", b"2").unwrap();
+        drop(writer);
+
+        // A reader that finds no lock file makes one, so writers are still
+        // kept out
+        let lock_path = store_file_path(&path, false, "lock");
+        fs::remove_file(&lock_path).unwrap();
+        let mut reader = ActionKV::open_read_only(&path).unwrap();
+        reader.load().unwrap();
+        assert_eq!(reader.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        assert!(lock_path.exists());
+        assert!(matches!(ActionKV::open(&path), Err(Error::Locked { .. })));
+    }
+
+    #[test]
+    fn files_without_a_header_have_to_be_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        // A store from before headers is the same records with no header
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.delete(b"apple").unwrap();
+        store.insert(b"banana", b"2").unwrap();
+        store.close().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[header::LEN as usize..]).unwrap();
+
+        assert!(matches!(ActionKV::open(&path), Err(Error::MissingHeader { segment: 0 })));
+        assert_eq!(upgrade(&path).unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        assert_eq!(upgrade(&path).unwrap(), 0);
+
+        // The stale hints went with the upgrade
+        let mut store = open_temp(&dir);
+        assert_eq!(store.get(b"apple").unwrap(), None);
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        drop(store);
+
+        // Anything else is left alone
+        let poem = dir.path().join("poem.txt");
+        fs::write(&poem, "Jabberwocky\n'Twas brillig, and the slithy toves\n").unwrap();
+        assert!(matches!(ActionKV::open(&poem), Err(Error::MissingHeader { .. })));
+        assert!(upgrade(&poem).is_err());
+        assert!(fs::read_to_string(&poem).unwrap().starts_with("Jabberwocky"));
+    }
+
+    #[test]
+    fn files_cut_short_in_their_header_are_started_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut header = Vec::new();
+        FileHeader::current(Compression::None).write(&mut header).unwrap();
+
+        for len in [1, 4, header::LEN as usize - 1] {
+            fs::write(&path, &header[..len]).unwrap();
+            assert_eq!(upgrade(&path).unwrap(), 0);
+
+            let mut reader = ActionKV::open_read_only(&path).unwrap();
+            reader.load().unwrap();
+            assert_eq!(reader.get(b"apple").unwrap(), None);
+            drop(reader);
+
+            let mut store = open_temp(&dir);
+            store.insert(b"apple", b"1").unwrap();
+            drop(store);
+            let mut store = open_temp(&dir);
+            assert_eq!(store.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        }
+
+        // The active segment of a directory store can be cut short too
+        let mut store = open_segmented(&dir);
+        for i in 0..10u8 {
+            store.insert(&[i], b"apple").unwrap();
+        }
+        let next = store.segments.last().unwrap().id + 1;
+        drop(store);
+        fs::write(segment::segment_path(&dir.path().join("store"), next), &header[..7]).unwrap();
+
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(&[9]).unwrap(), Some(b"apple".to_vec()));
+        store.insert(b"banana", b"2").unwrap();
+        assert!(store.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn large_values_are_compressed_transparently() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let blob = br#"{"name": "apple", "colour": "red"}, "#.repeat(100);
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let options = Options { compression, compression_threshold: 64, ..Options::default() };
+            let mut store = ActionKV::open_with(&path, options).unwrap();
+            store.load().unwrap();
+            store.insert(b"blob", &blob).unwrap();
+            store.insert(b"small", b"not worth compressing").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"batched", &blob);
+            store.write_batch(&batch).unwrap();
+
+            let location = store.index[b"blob".as_ref()];
+            assert!(store.record_len_at(location).unwrap() < blob.len() as u32);
+            let stats = store.stats().unwrap();
+            assert_eq!(stats.value_bytes, 2 * blob.len() as u64 + 21);
+            assert!(stats.compression_ratio > 4.0);
+            store.close().unwrap();
+        }
+
+        // Compressed and plain records read back whatever the setting is now
+        let mut store = open_temp(&dir);
+        assert_eq!(store.get(b"blob").unwrap(), Some(blob.clone()));
+        assert_eq!(store.get(b"small").unwrap(), Some(b"not worth compressing".to_vec()));
+        assert_eq!(store.snapshot().unwrap().get(b"batched").unwrap(), Some(blob.clone()));
+        store.compact().unwrap();
+        assert_eq!(store.get(b"batched").unwrap(), Some(blob));
+        assert!(store.verify().unwrap().is_empty());
+    }
+
+    fn open_encrypted(path: &Path, key: &EncryptionKey) -> Result<ActionKV> {
+        let options = Options { encryption_key: Some(key.clone()), ..Options::default() };
+        let mut store = ActionKV::open_with(path, options)?;
+        store.load()?;
+        Ok(store)
+    }
+
+    #[test]
+    fn encrypted_records_need_the_key_to_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        // Written in the clear first, then with a key
+        let mut store = open_temp(&dir);
+        store.insert(b"plain", b"in the clear").unwrap();
+        drop(store);
+
+        let key = EncryptionKey::generate();
+        let mut store = open_encrypted(&path, &key).unwrap();
+        store.insert(b"password", b"hunter2").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"token", b"s3cret");
+        batch.delete(b"plain");
+        store.write_batch(&batch).unwrap();
+        store.close().unwrap();
+
+        let mut store = open_encrypted(&path, &key).unwrap();
+        assert_eq!(store.get(b"password").unwrap(), Some(b"hunter2".to_vec()));
+        assert_eq!(store.snapshot().unwrap().get(b"token").unwrap(), Some(b"s3cret".to_vec()));
+        assert_eq!(store.get(b"plain").unwrap(), None);
+        assert!(!store_file_path(&path, false, "hint").exists());
+        drop(store);
+
+        // Neither keys nor values are written out in the clear
+        let bytes = fs::read(&path).unwrap();
+        for secret in [b"password".as_ref(), b"hunter2", b"token", b"s3cret"] {
+            assert!(!bytes.windows(secret.len()).any(|w| w == secret));
+        }
+
+        // The first record isn't encrypted, so the segment only fails when
+        // an encrypted one is reached
+        let err = open_with_recovery(&dir, Recovery::Truncate).unwrap_err();
+        assert!(matches!(err, Error::Decryption { segment: 0, .. }), "{:?}", err);
+        let err = open_encrypted(&path, &EncryptionKey::generate()).unwrap_err();
+        assert!(matches!(err, Error::Decryption { .. }), "{:?}", err);
+
+        // Rekeying encrypts everything, and the old key no longer works
+        let new_key = EncryptionKey::generate();
+        let mut store = open_encrypted(&path, &key).unwrap();
+        store.insert(b"plain", b"in the clear").unwrap();
+        store.rekey(new_key.clone()).unwrap();
+        assert_eq!(store.get(b"plain").unwrap(), Some(b"in the clear".to_vec()));
+        drop(store);
+
+        assert!(matches!(ActionKV::open(&path), Err(Error::KeyRequired)));
+        assert!(matches!(open_encrypted(&path, &key), Err(Error::Decryption { .. })));
+        let mut store = open_encrypted(&path, &new_key).unwrap();
+        assert_eq!(store.get(b"password").unwrap(), Some(b"hunter2".to_vec()));
+        assert_eq!(store.get(b"plain").unwrap(), Some(b"in the clear".to_vec()));
+        assert!(store.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn encrypted_records_are_checked_by_their_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let key = EncryptionKey::generate();
+
+        let mut store = open_encrypted(&path, &key).unwrap();
+        store.insert(b"password", b"hunter2").unwrap();
+        let at = store.index[b"password".as_ref()].offset as usize;
+        drop(store);
+
+        // The checksum field holds part of the tag rather than a CRC32
+        let mut bytes = fs::read(&path).unwrap();
+        let header: [u8; HEADER_LEN as usize] = bytes[at..at + HEADER_LEN as usize].try_into().unwrap();
+        let data = &bytes[at + HEADER_LEN as usize..];
+        assert_eq!(record_len(&header), (bytes.len() - at) as u64);
+        assert_ne!(LittleEndian::read_u32(&header), record_checksum(header[7], data));
+
+        // Damage can't be told from the wrong key, so it is never recovered from
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(open_encrypted(&path, &key), Err(Error::Decryption { .. })));
+
+        let options = Options { encryption_key: Some(key), ..Options::default() };
+        let mut store = ActionKV::open_with(&path, options).unwrap();
+        assert!(matches!(store.verify().unwrap()[..], [Error::Decryption { offset, .. }] if offset == at as u64));
+        let backup = store.backup_to(&dir.path().join("backup"));
+        assert!(matches!(backup, Err(Error::Decryption { .. })));
+    }
+
+    #[test]
+    fn stores_with_a_persistent_index_are_not_rekeyed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"password", b"hunter2").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let err = store.rekey(EncryptionKey::generate()).unwrap_err();
+        assert!(matches!(err, Error::Io(ref err) if err.kind() == io::ErrorKind::InvalidInput), "{:?}", err);
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert_eq!(store.get(b"password").unwrap(), Some(b"hunter2".to_vec()));
+        drop(store);
+
+        let mut store = ActionKV::open_with_persistent_index(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"password").unwrap(), Some(b"hunter2".to_vec()));
+    }
+
+    #[test]
+    fn sync_policy_decides_when_writes_are_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options { sync: SyncPolicy::EveryN(3), ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+        store.load().unwrap();
+
+        store.insert(b"apple", b"1").unwrap();
+        store.delete(b"apple").unwrap();
+        assert_eq!(store.sync_state.unsynced(), 2);
+        store.insert(b"banana", b"2").unwrap();
+        assert_eq!(store.sync_state.unsynced(), 0);
+
+        store.insert(b"cherry", b"3").unwrap();
+        store.sync().unwrap();
+        assert_eq!(store.sync_state.unsynced(), 0);
+        drop(store);
+
+        let options = Options { sync: SyncPolicy::Never, ..Options::default() };
+        let mut store = ActionKV::open_with(&dir.path().join("store.akv"), options).unwrap();
+        store.load().unwrap();
+        store.insert(b"damson", b"4").unwrap();
+        assert_eq!(store.sync_state.unsynced(), 1);
+        assert_eq!(store.get(b"cherry").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn shared_store_takes_writes_from_many_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options { sync: SyncPolicy::EveryWrite, ..Options::default() };
+        let mut store = ActionKV::open_with(&path, options).unwrap();
+        store.load().unwrap();
+        let shared = SharedStore::new(store);
+
+        let writers: Vec<_> = (0..4u8)
+            .map(|thread| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25u8 {
+                        shared.insert(&[thread, i], &[i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(shared.get(&[3, 24]).unwrap(), Some(vec![24]));
+        shared.sync().unwrap();
+        drop(shared);
+
+        let reopened = open_temp(&dir);
+        assert_eq!(reopened.index.len(), 100);
+    }
+
+    /// Waits for `done` to hold, failing the test if it takes too long
+    fn wait_until<F: FnMut() -> bool>(mut done: F) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn followers_keep_up_with_their_leader() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader = SharedStore::new(open_segmented(&leader_dir));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shipping = leader.clone();
+        std::thread::spawn(move || serve_log(listener, shipping));
+
+        leader.insert(b"apple", b"1").unwrap();
+        leader.insert(b"banana", b"2").unwrap();
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let follower_path = follower_dir.path().join("follower.akv");
+        let start_following = || {
+            let mut store = ActionKV::open(&follower_path).unwrap();
+            store.load().unwrap();
+            let store = SharedStore::new(store);
+            let follower = Arc::new(Follower::new(store.clone(), &address));
+            let running = follower.clone();
+            (follower, store, std::thread::spawn(move || running.run()))
+        };
+
+        let (follower, _, running) = start_following();
+        let mut batch = WriteBatch::new();
+        batch.put(b"cherry", b"3");
+        batch.delete(b"apple");
+        leader.write_batch(&batch).unwrap();
+        wait_until(|| follower.status().caught_up() && follower.status().applied == 3);
+        assert!(follower.status().position.is_some());
+
+        // A follower that stops carries on from where it got to
+        follower.stop();
+        running.join().unwrap().unwrap();
+        drop(follower);
+        leader.insert(b"damson", b"4").unwrap();
+
+        let (follower, copy, running) = start_following();
+        wait_until(|| follower.status().applied == 1 && follower.status().caught_up());
+        assert_eq!(copy.get(b"damson").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(copy.get(b"apple").unwrap(), None);
+
+        // Once compaction has moved the records, the follower starts again
+        leader.with(|store| store.compact()).unwrap();
+        leader.insert(b"elderberry", b"5").unwrap();
+        wait_until(|| copy.get(b"elderberry").unwrap().is_some() && follower.status().caught_up());
+        for key in [b"banana".as_ref(), b"cherry", b"damson"] {
+            assert_eq!(copy.get(key).unwrap(), leader.get(key).unwrap());
+        }
+        assert_eq!(copy.with(|store| store.index.len()), 4);
+
+        follower.stop();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn followers_start_again_once_compaction_drops_entries_they_missed() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader = SharedStore::new(open_segmented(&leader_dir));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shipping = leader.clone();
+        std::thread::spawn(move || serve_log(listener, shipping));
+
+        // A segment each, numbered so that compaction leaves banana's be
+        leader.insert(b"apple", &[b'1'; 40]).unwrap();
+        leader.insert(b"banana", &[b'2'; 40]).unwrap();
+        leader.with(|store| store.compact()).unwrap();
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let copy = SharedStore::new(open_temp(&follower_dir));
+        let start_following = || {
+            let follower = Arc::new(Follower::new(copy.clone(), &address));
+            let running = follower.clone();
+            (follower.clone(), std::thread::spawn(move || running.run()))
+        };
+
+        let (follower, running) = start_following();
+        wait_until(|| follower.status().caught_up() && copy.get(b"banana").unwrap().is_some());
+        follower.stop();
+        running.join().unwrap().unwrap();
+
+        // The tombstone is thrown away before the follower is sent it, while
+        // the entry the follower got to last stays where it was
+        leader.delete(b"apple").unwrap();
+        leader.with(|store| store.compact()).unwrap();
+        assert_eq!(copy.get(b"apple").unwrap(), Some(vec![b'1'; 40]));
+
+        let (follower, running) = start_following();
+        wait_until(|| follower.status().caught_up() && copy.get(b"apple").unwrap().is_none());
+        assert_eq!(copy.get(b"banana").unwrap(), Some(vec![b'2'; 40]));
+        follower.stop();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn followers_only_empty_stores_they_are_told_to() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader = SharedStore::new(open_segmented(&leader_dir));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shipping = leader.clone();
+        std::thread::spawn(move || serve_log(listener, shipping));
+        leader.insert(b"apple", b"1").unwrap();
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&follower_dir);
+        store.insert(b"banana", b"mine").unwrap();
+        let copy = SharedStore::new(store);
+
+        // A store with records of its own is left alone...
+        let follower = Follower::new(copy.clone(), &address);
+        assert!(matches!(follower.run(), Err(Error::NotAReplica)));
+        assert_eq!(copy.get(b"banana").unwrap(), Some(b"mine".to_vec()));
+
+        // ...unless the follower is told to reset it
+        let follower = Arc::new(Follower::new(copy.clone(), &address).resetting());
+        let running = follower.clone();
+        let running = std::thread::spawn(move || running.run());
+        wait_until(|| follower.status().applied == 1 && follower.status().caught_up());
+        assert_eq!(copy.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(copy.get(b"banana").unwrap(), None);
+        follower.stop();
+        running.join().unwrap().unwrap();
+
+        // Once it has emptied the store it is the follower's to empty again,
+        // even if it stopped before it saved a position
+        let replica = copy.with(|store| store.store_file_path("replica"));
+        fs::write(&replica, b"").unwrap();
+        let follower = Arc::new(Follower::new(copy.clone(), &address));
+        let running = follower.clone();
+        let running = std::thread::spawn(move || running.run());
+        wait_until(|| follower.status().applied == 1 && follower.status().caught_up());
+        assert_eq!(copy.get(b"apple").unwrap(), Some(b"1".to_vec()));
+        follower.stop();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn stats_count_the_dead_records_compaction_would_free() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.insert(b"apple", b"2").unwrap();
+        store.insert(b"apple", &[b'3'; 100]).unwrap();
+        store.insert(b"banana", b"yellow").unwrap();
+        store.delete(b"banana").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"cherry", b"red");
+        store.write_batch(&batch).unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.keys, stats.records, stats.dead_records), (2, 6, 4));
+        assert_eq!(stats.live_bytes + stats.dead_bytes + stats.overhead_bytes, stats.disk_bytes);
+        assert_eq!(stats.overhead_bytes, header::LEN + HEADER_LEN);
+        assert!(stats.fragmentation > 0.0 && stats.fragmentation < 1.0);
+        assert_eq!((stats.largest_key, stats.largest_value), (6, 100));
+        assert_eq!(stats.value_sizes, vec![
+            SizeBucket { up_to: 16, values: 1 },
+            SizeBucket { up_to: 64, values: 0 },
+            SizeBucket { up_to: 256, values: 1 },
+        ]);
+
+        store.compact().unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.records, stats.dead_records, stats.dead_bytes), (2, 0, 0));
+        assert_eq!(stats.fragmentation, 0.0);
+    }
+
+    #[test]
+    fn stats_count_the_frames_of_live_batches_as_overhead() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+        let mut batch = WriteBatch::new();
+        batch.put(b"apple", b"1").put(b"banana", b"2");
+        store.write_batch(&batch).unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.records, stats.dead_records, stats.dead_bytes), (2, 0, 0));
+        assert_eq!(stats.overhead_bytes, header::LEN + HEADER_LEN);
+        assert_eq!(stats.fragmentation, 0.0);
+
+        // Once nothing in the frame is live, its header can go too
+        let mut batch = WriteBatch::new();
+        batch.put(b"apple", b"3").put(b"banana", b"4");
+        store.write_batch(&batch).unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.dead_records, 2);
+        assert_eq!(stats.live_bytes, stats.dead_bytes - HEADER_LEN);
+    }
+
+    #[test]
+    fn imports_write_one_sorted_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_segmented(&dir);
+        store.insert(b"apple", b"old").unwrap();
+
+        let pairs = [("cherry", "1"), ("apple", "2"), ("banana", "3"), ("cherry", "4")]
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        assert_eq!(store.import(pairs).unwrap(), 3);
+
+        // The records follow one another in key order, after what was there
+        let locations: Vec<Location> = ["apple", "banana", "cherry"]
+            .iter()
+            .map(|key| store.index[key.as_bytes()])
+            .collect();
+        assert!(locations.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(store.segments.len() > 1);
+
+        drop(store);
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(b"apple").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"banana").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"cherry").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn large_imports_are_merged_into_one_sorted_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_temp(&dir);
+
+        // Each batch holds at most 4 bytes of keys and values: two pairs
+        let pairs = [("d", "1"), ("c", "2"), ("b", "3"), ("a", "4"), ("c", "5")]
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        assert_eq!(store.import_in_runs(pairs, 4).unwrap(), 4);
+
+        let order = |store: &ActionKV, keys: [&str; 2]| store.index[keys[0].as_bytes()] < store.index[keys[1].as_bytes()];
+        assert!(order(&store, ["a", "b"]) && order(&store, ["b", "c"]) && order(&store, ["c", "d"]));
+        assert!(!store.store_file_path("import0").exists());
+        drop(store);
+
+        let mut store = open_temp(&dir);
+        let values: Vec<_> = ["a", "b", "c", "d"].iter().map(|key| store.get(key.as_bytes()).unwrap().unwrap()).collect();
+        assert_eq!(values, [b"4", b"3", b"5", b"1"]);
+    }
+
+    #[test]
+    fn backups_hold_every_write_made_before_they_began() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SharedStore::new(open_segmented(&dir));
+        for i in 0..20 {
+            store.insert(format!("key{:03}", i).as_bytes(), b"before").unwrap();
+        }
+
+        // Keep writing while the backups are taken
+        let writing = store.clone();
+        let writer = std::thread::spawn(move || {
+            for i in 20..400 {
+                writing.insert(format!("key{:03}", i).as_bytes(), b"during").unwrap();
+            }
+        });
+        for n in 0..5 {
+            store.backup_to(&dir.path().join(format!("backup{}", n))).unwrap();
+        }
+        writer.join().unwrap();
+
+        for n in 0..5 {
+            let restored = dir.path().join(format!("restored{}", n));
+            restore(&dir.path().join(format!("backup{}", n)), &restored).unwrap();
+            let mut copy = ActionKV::open_dir(&restored, Options::default()).unwrap();
+            copy.load().unwrap();
+
+            let count = copy.index.len();
+            assert!(count >= 20);
+            for i in 0..count {
+                assert!(copy.get(format!("key{:03}", i).as_bytes()).unwrap().is_some());
+            }
+        }
+
+        // A backup isn't written over, and a damaged one isn't restored
+        let backup = dir.path().join("backup0");
+        assert!(store.backup_to(&backup).is_err());
+        let first = segment::segment_path(&backup, 0);
+        let mut bytes = fs::read(&first).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&first, bytes).unwrap();
+        let damaged = restore(&backup, &dir.path().join("restored5"));
+        assert!(matches!(damaged, Err(Error::ChecksumMismatch { segment: 0, .. })));
+    }
+
+    #[test]
+    fn restores_replace_the_whole_store_or_none_of_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_segmented(&dir);
+        store.insert(b"apple", b"1").unwrap();
+        store.backup_to(&dir.path().join("backup")).unwrap();
+        for i in 0..20u8 {
+            store.insert(&[i], b"after").unwrap();
+        }
+        drop(store);
+
+        let path = dir.path().join("store");
+        let backup = dir.path().join("backup");
+        let check = |path: &Path| {
+            let mut store = ActionKV::open_dir(path, Options::default()).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.snapshot().unwrap().keys(), vec![b"apple".to_vec()]);
+        };
+
+        // Stopped once every segment was staged, before the marker
+        for id in segment::list(&backup).unwrap() {
+            let staged = staged_path(&segment::segment_path(&path, id), "restore");
+            fs::copy(segment::segment_path(&backup, id), staged).unwrap();
+        }
+        let mut store = open_segmented(&dir);
+        assert_eq!(store.get(&[19]).unwrap(), Some(b"after".to_vec()));
+        drop(store);
+
+        // Stopped once the marker was written
+        begin_replacing(&path, "restore", &segment::list(&backup).unwrap()).unwrap();
+        let options = Options { read_only: true, ..Options::default() };
+        assert!(ActionKV::open_dir(&path, options).is_err());
+        check(&path);
+        assert!(!path.join("store.restore").exists());
+
+        // All the way through, to a single file as well
+        restore(&backup, &path).unwrap();
+        check(&path);
+        let file = dir.path().join("store.akv");
+        let mut store = open_temp(&dir);
+        store.insert(b"banana", b"2").unwrap();
+        store.backup_to(&dir.path().join("backup.akv")).unwrap();
+        store.insert(b"cherry", b"3").unwrap();
+        drop(store);
+        restore(&dir.path().join("backup.akv"), &file).unwrap();
+        assert!(!staged_path(&file, "restore").exists());
+        let mut store = open_temp(&dir);
+        assert_eq!(store.snapshot().unwrap().keys(), vec![b"banana".to_vec()]);
+    }
+
+    fn put(key: &[u8], value: &[u8]) -> Command {
+        Command::Put { key: key.to_vec(), value: value.to_vec() }
+    }
+
+    #[test]
+    fn raft_clusters_survive_partitions_and_crashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RaftConfig { snapshot_threshold: 20, seed: 7, ..RaftConfig::default() };
+        let mut net = Network::new(dir.path(), 3, config).unwrap();
+        assert!(net.run_until(500, |net| net.leader().is_some()).unwrap());
+
+        let index = net.propose(put(b"apple", b"1")).unwrap();
+        assert!(net.run_until(500, |net| net.nodes().all(|node| node.last_applied() >= index)).unwrap());
+        for id in 1..=3 {
+            assert_eq!(net.node_mut(id).unwrap().get(b"apple").unwrap(), Some(b"1".to_vec()));
+        }
+
+        // A leader cut off from the rest can't commit, and they elect another
+        let old = net.leader().unwrap();
+        let rest: Vec<NodeId> = (1..=3).filter(|id| *id != old).collect();
+        net.partition(&[&[old], &rest]);
+        let lost = net.node_mut(old).unwrap().propose(put(b"banana", b"lost")).unwrap();
+        assert!(net.run_until(500, |net| net.leader().is_some_and(|leader| leader != old)).unwrap());
+
+        let index = net.propose(put(b"banana", b"2")).unwrap();
+        let applied = |net: &Network, ids: &[NodeId], index| {
+            ids.iter().all(|id| net.node(*id).is_some_and(|node| node.last_applied() >= index))
+        };
+        assert!(net.run_until(500, |net| applied(net, &rest, index)).unwrap());
+        assert!(net.node(old).unwrap().commit_index() < lost);
+
+        // Once the partition heals, the old leader's entry is replaced
+        net.heal();
+        assert!(net.run_until(500, |net| applied(net, &[1, 2, 3], index)).unwrap());
+        net.run(20).unwrap();
+        let leader = net.leader().unwrap();
+        let log = net.node(leader).unwrap().entries().to_vec();
+        for id in 1..=3 {
+            assert_eq!(net.node(id).unwrap().entries(), log.as_slice());
+            assert_eq!(net.node_mut(id).unwrap().get(b"banana").unwrap(), Some(b"2".to_vec()));
+        }
+
+        // A follower that misses more than a snapshot's worth of entries is
+        // sent the snapshot when it comes back
+        let follower = rest.into_iter().find(|id| *id != leader).unwrap_or(old);
+        let missed_from = net.node(follower).unwrap().last_index();
+        net.crash(follower);
+        for i in 0..30 {
+            net.propose(put(format!("key{}", i).as_bytes(), b"x")).unwrap();
+        }
+        net.propose(Command::Delete { key: b"apple".to_vec() }).unwrap();
+        let last = net.node(leader).unwrap().last_index();
+        assert!(net.run_until(500, |net| net.node(leader).unwrap().last_applied() == last).unwrap());
+        assert!(net.node(leader).unwrap().snapshot_index() > missed_from);
+
+        net.restart(follower).unwrap();
+        assert!(net.run_until(500, |net| applied(net, &[follower], last)).unwrap());
+        let node = net.node_mut(follower).unwrap();
+        assert!(node.snapshot_index() > missed_from);
+        assert_eq!(node.get(b"apple").unwrap(), None);
+        assert_eq!(node.get(b"banana").unwrap(), Some(b"2".to_vec()));
+        for i in 0..30 {
+            assert_eq!(node.get(format!("key{}", i).as_bytes()).unwrap(), Some(b"x".to_vec()));
+        }
+    }
+
+    #[test]
+    fn raft_runs_repeat_for_the_same_seed() {
+        let run = || {
+            let dir = tempfile::tempdir().unwrap();
+            let config = RaftConfig { seed: 42, ..RaftConfig::default() };
+            let mut net = Network::new(dir.path(), 3, config).unwrap();
+            net.run_until(500, |net| net.leader().is_some()).unwrap();
+            net.propose(put(b"apple", b"1")).unwrap();
+            net.crash(net.leader().unwrap());
+            net.run(100).unwrap();
+
+            let nodes: Vec<_> = net.nodes().map(|node| (node.id(), node.term(), node.role(), node.last_index())).collect();
+            (net.now(), nodes)
+        };
+
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,57 @@
+//! Advisory locks that stop two processes from writing to the same store.
+//!
+//! A store is locked through its own lock file (`FILE.lock`, or
+//! `DIR/store.lock`) rather than its data files, since compaction replaces
+//! those with new files that wouldn't carry the lock over. A writer holds
+//! an exclusive lock for as long as the store is open, and read-only opens
+//! hold a shared one, so any number of readers can open the store together
+//! but never alongside a writer.
+//!
+//! The locks are advisory: they only keep out other processes that take
+//! them too.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use crate::{Error, Result};
+
+/// Takes the lock for the store whose lock file is at `path`, without
+/// waiting. The lock is held until the returned file is dropped.
+///
+/// A store is never opened without its lock: a reader that finds no lock
+/// file creates one to lock, and if it can't, the open fails rather than
+/// going on unlocked.
+pub(crate) fn acquire(path: &Path, shared: bool) -> Result<File> {
+    let f = open(path, shared)?;
+
+    let locked = match shared {
+        true => f.try_lock_shared(),
+        false => f.try_lock(),
+    };
+
+    match locked {
+        Ok(()) => Ok(f),
+        Err(std::fs::TryLockError::WouldBlock) => Err(Error::Locked { path: path.to_path_buf() }),
+        Err(std::fs::TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Opens the lock file, creating it if it isn't there yet. Readers open an
+/// existing one for reading only, so that they can still lock a store on
+/// a read-only filesystem as long as a writer has opened it before.
+fn open(path: &Path, shared: bool) -> io::Result<File> {
+    if shared {
+        match File::open(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            opened => return opened,
+        }
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
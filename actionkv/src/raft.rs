@@ -0,0 +1,701 @@
+//! Keeping three (or more) copies of a store in step with Raft consensus.
+//!
+//! Each `RaftNode` keeps a log of commands. Writes are proposed to the node
+//! that is leader, which appends them to its log and sends them on to the
+//! others. Once a majority of nodes have an entry in their logs, it is
+//! committed: it can't be lost while a majority survive, and every node
+//! applies it, in log order, to its own `ActionKV` store. So a write that
+//! has been committed survives the loss of any one node of three.
+//!
+//! A node that hasn't heard from a leader for a while stands for election.
+//! It becomes leader once a majority vote for it, which they only do if its
+//! log is at least as up to date as theirs. A leader brings its followers'
+//! logs into line with its own, walking back from the end of their log to
+//! the last entry the two agree on and replacing everything after it.
+//!
+//! Once enough entries have been applied, a node snapshots its store by
+//! copying out the live records, and drops those entries from its log. A
+//! follower that has fallen so far behind that the entries it needs have
+//! been dropped is sent the snapshot instead, and replaces its store with
+//! the records in it.
+//!
+//! Nodes don't do any I/O with each other themselves. Time passes when
+//! `tick` is called, messages come in through `step`, and the messages a
+//! node wants to send are collected with `take_messages`. So they can be
+//! wired up to any transport, such as the in-process `Network` used for
+//! testing.
+
+mod network;
+mod storage;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::{ActionKV, Error, Options, Result};
+use storage::Storage;
+
+pub use network::Network;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Identifies a node within its cluster. Ids start at 1.
+pub type NodeId = u64;
+
+/// The most entries a leader sends a follower in one message
+const MAX_ENTRIES: usize = 64;
+
+/// A change to the store, as ordered by the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Changes nothing. A new leader appends one to commit the entries it
+    /// inherited from earlier terms.
+    Noop,
+    Put { key: ByteString, value: ByteString },
+    Delete { key: ByteString },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// The term of the leader that appended the entry
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+/// A message from one node to another. Every message carries the sender's
+/// term, and a node that sees a newer term than its own moves up to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A candidate asking for a vote
+    RequestVote { term: u64, last_log_index: u64, last_log_term: u64 },
+    Vote { term: u64, granted: bool },
+    /// A leader sending the entries after `prev_log_index`, or none just to
+    /// keep its followers from standing for election
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// Whether the entries were appended. If so, `match_index` is the last
+    /// entry the follower now has in common with the leader. If not, it is
+    /// where the leader should try again from.
+    AppendResponse { term: u64, success: bool, match_index: u64 },
+    /// A leader sending its snapshot to a follower that is missing entries
+    /// it no longer has
+    InstallSnapshot {
+        term: u64,
+        last_included_index: u64,
+        last_included_term: u64,
+        data: ByteString,
+    },
+    SnapshotResponse { term: u64, last_included_index: u64 },
+}
+
+impl Message {
+    pub fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::SnapshotResponse { term, .. } => *term,
+        }
+    }
+}
+
+/// A message on its way from one node to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// How many ticks a follower waits to hear from a leader before it
+    /// stands for election. Each wait is drawn at random from between this
+    /// and twice this, so that nodes seldom stand at the same time.
+    pub election_ticks: u32,
+    /// How many ticks a leader lets pass between messages to its followers
+    pub heartbeat_ticks: u32,
+    /// How many entries are applied between snapshots
+    pub snapshot_threshold: u64,
+    /// Seeds the election timeouts, so that a run can be repeated
+    pub seed: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            snapshot_threshold: 1000,
+            seed: 0,
+        }
+    }
+}
+
+/// The store as of some entry in the log: its live records, one after
+/// another as `ActionKV::live_records` copies them out
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RaftSnapshot {
+    /// The last entry applied to the store
+    pub index: u64,
+    pub term: u64,
+    pub data: ByteString,
+}
+
+/// One member of a Raft cluster, along with the store it applies committed
+/// entries to.
+///
+/// A node keeps everything in its own directory: its term, vote, log and
+/// snapshot in `raft.*` files, and its store in `data`. The store can
+/// always be rebuilt from the rest, so its writes aren't synced, and it is
+/// reset to the snapshot whenever the node is opened.
+#[derive(Debug)]
+pub struct RaftNode {
+    id: NodeId,
+    /// The other nodes in the cluster
+    peers: Vec<NodeId>,
+    config: RaftConfig,
+    storage: Storage,
+    store: ActionKV,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    /// The entries after the snapshot
+    log: Vec<LogEntry>,
+    snapshot: RaftSnapshot,
+    commit_index: u64,
+    last_applied: u64,
+
+    /// Ticks since the node last heard from a leader or stood for election
+    election_elapsed: u32,
+    election_timeout: u32,
+    /// Ticks since a leader last sent its followers anything
+    heartbeat_elapsed: u32,
+    /// The nodes that have voted for a candidate
+    votes: HashSet<NodeId>,
+    /// The next entry a leader will send each follower
+    next_index: HashMap<NodeId, u64>,
+    /// The last entry a leader knows each follower has in common with it
+    match_index: HashMap<NodeId, u64>,
+
+    outbox: Vec<Envelope>,
+    rng: u64,
+}
+
+impl RaftNode {
+    /// Opens (or creates) the node `id` in `dir`, as a member of a cluster
+    /// with the nodes in `peers`. The node starts out as a follower, with
+    /// its store as of its last snapshot.
+    pub fn open(id: NodeId, peers: &[NodeId], dir: &Path, config: RaftConfig) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let (storage, saved) = Storage::open(dir)?;
+
+        let mut store = ActionKV::open_dir(&dir.join("data"), Options::default())?;
+        store.restore_records(&saved.snapshot.data)?;
+
+        let mut node = RaftNode {
+            id,
+            peers: peers.iter().copied().filter(|peer| *peer != id).collect(),
+            storage,
+            store,
+            role: Role::Follower,
+            term: saved.term,
+            voted_for: saved.voted_for,
+            leader: None,
+            log: saved.log,
+            commit_index: saved.snapshot.index,
+            last_applied: saved.snapshot.index,
+            snapshot: saved.snapshot,
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            outbox: Vec::new(),
+            // xorshift needs a state that isn't 0
+            rng: (config.seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+            config,
+        };
+        node.reset_election_timer();
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// The node this one believes is leader, if it knows of one
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// The last entry known to be committed
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// The last entry applied to the store
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// The last entry in the log
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.index + self.log.len() as u64
+    }
+
+    /// The last entry covered by the snapshot
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot.index
+    }
+
+    /// The entries still in the log, after the snapshot
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.log
+    }
+
+    /// Reads a key from the node's own store. Only committed writes are
+    /// applied to it, but a follower may not have heard of the latest yet.
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.store.get(key)
+    }
+
+    /// Takes the messages the node has to send
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Appends a command to the leader's log, returning its index. It is
+    /// applied once `commit_index` reaches that index, unless the node stops
+    /// being leader first, in which case it may never be.
+    ///
+    /// Fails with `Error::NotLeader` if the node isn't leader.
+    pub fn propose(&mut self, command: Command) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader { leader: self.leader });
+        }
+
+        let index = self.append(command)?;
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+        self.advance_commit()?;
+        Ok(index)
+    }
+
+    /// Moves the node's clock on by one tick
+    pub fn tick(&mut self) -> Result<()> {
+        match self.role {
+            Role::Leader => {
+                self.heartbeat_elapsed += 1;
+                if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+                    self.heartbeat_elapsed = 0;
+                    for peer in self.peers.clone() {
+                        self.send_append(peer);
+                    }
+                }
+                Ok(())
+            },
+            Role::Follower | Role::Candidate => {
+                self.election_elapsed += 1;
+                match self.election_elapsed >= self.election_timeout {
+                    true => self.campaign(),
+                    false => Ok(()),
+                }
+            },
+        }
+    }
+
+    /// Handles a message from another node
+    pub fn step(&mut self, from: NodeId, message: Message) -> Result<()> {
+        if message.term() > self.term {
+            self.become_follower(message.term(), None)?;
+        }
+
+        match message {
+            Message::RequestVote { term, last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|voted_for| voted_for == from)
+                    && up_to_date;
+
+                if granted {
+                    self.voted_for = Some(from);
+                    self.storage.save_state(self.term, self.voted_for)?;
+                    self.reset_election_timer();
+                }
+                self.send(from, Message::Vote { term: self.term, granted });
+            },
+
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.has_majority(self.votes.len()) {
+                        self.become_leader()?;
+                    }
+                }
+            },
+
+            Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit } => {
+                if term < self.term {
+                    let response = Message::AppendResponse { term: self.term, success: false, match_index: 0 };
+                    self.send(from, response);
+                    return Ok(());
+                }
+
+                self.follow(from)?;
+                let (success, match_index) = self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?;
+                self.send(from, Message::AppendResponse { term: self.term, success, match_index });
+            },
+
+            Message::AppendResponse { term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(match_index);
+                    let next = *matched + 1;
+                    self.next_index.insert(from, next);
+                    self.advance_commit()?;
+                    if next <= self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    // Go straight back to where the follower said, rather
+                    // than one entry at a time
+                    let next = self.next_index.entry(from).or_insert(1);
+                    *next = (*next - 1).min(match_index + 1).max(1);
+                    self.send_append(from);
+                }
+            },
+
+            Message::InstallSnapshot { term, last_included_index, last_included_term, data } => {
+                if term == self.term {
+                    self.follow(from)?;
+                    self.install_snapshot(last_included_index, last_included_term, data)?;
+                }
+                self.send(from, Message::SnapshotResponse { term: self.term, last_included_index });
+            },
+
+            Message::SnapshotResponse { term, last_included_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+
+                let matched = self.match_index.entry(from).or_default();
+                *matched = (*matched).max(last_included_index);
+                let next = *matched + 1;
+                self.next_index.insert(from, next);
+                self.advance_commit()?;
+                if next <= self.last_index() {
+                    self.send_append(from);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope { from: self.id, to, message });
+    }
+
+    /// Draws a fresh election timeout and starts waiting for it again
+    fn reset_election_timer(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        let ticks = self.config.election_ticks.max(1);
+        self.election_elapsed = 0;
+        self.election_timeout = ticks + (self.rng % ticks as u64) as u32;
+    }
+
+    fn has_majority(&self, count: usize) -> bool {
+        count * 2 > self.peers.len() + 1
+    }
+
+    /// The term of the entry at `index`, if the node still has it
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.snapshot.index + 1) {
+            Some(position) => self.log.get(position as usize).map(|entry| entry.term),
+            None if index == self.snapshot.index => Some(self.snapshot.term),
+            None => None,
+        }
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// Moves up to `term` if it is newer, and stops leading or standing for
+    /// election
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.storage.save_state(self.term, self.voted_for)?;
+        }
+
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    /// Takes `leader` as the leader of the current term, having heard from it
+    fn follow(&mut self, leader: NodeId) -> Result<()> {
+        match self.role {
+            Role::Follower => {
+                self.leader = Some(leader);
+                self.reset_election_timer();
+                Ok(())
+            },
+            Role::Candidate | Role::Leader => self.become_follower(self.term, Some(leader)),
+        }
+    }
+
+    /// Stands for election in the next term, voting for itself
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.storage.save_state(self.term, self.voted_for)?;
+        self.reset_election_timer();
+
+        self.votes.clear();
+        self.votes.insert(self.id);
+        if self.has_majority(self.votes.len()) {
+            return self.become_leader();
+        }
+
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, message.clone());
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (*peer, next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+
+        // Entries from earlier terms can only be committed along with one
+        // from this term
+        self.propose(Command::Noop)?;
+        Ok(())
+    }
+
+    /// Adds a command to the end of the log in the current term
+    fn append(&mut self, command: Command) -> Result<u64> {
+        let entry = LogEntry { term: self.term, index: self.last_index() + 1, command };
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        Ok(self.last_index())
+    }
+
+    /// Sends a follower the entries it is missing, or the snapshot if the
+    /// leader has dropped some of them
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+
+        let message = match self.term_at(next - 1) {
+            Some(prev_log_term) => {
+                let start = (next - 1 - self.snapshot.index) as usize;
+                let end = self.log.len().min(start + MAX_ENTRIES);
+                Message::AppendEntries {
+                    term: self.term,
+                    prev_log_index: next - 1,
+                    prev_log_term,
+                    entries: self.log[start..end].to_vec(),
+                    leader_commit: self.commit_index,
+                }
+            },
+            None => Message::InstallSnapshot {
+                term: self.term,
+                last_included_index: self.snapshot.index,
+                last_included_term: self.snapshot.term,
+                data: self.snapshot.data.clone(),
+            },
+        };
+        self.send(peer, message);
+    }
+
+    /// Commits the last entry of the current term that a majority have
+    fn advance_commit(&mut self) -> Result<()> {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+
+            let copies = 1 + self.match_index.values().filter(|matched| **matched >= index).count();
+            if self.has_majority(copies) {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        self.apply()
+    }
+
+    /// Appends the entries a leader sent, if the entry before them matches
+    /// the leader's, replacing any that conflict with them. Returns whether
+    /// they were appended, and the `match_index` to reply with.
+    fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> Result<(bool, u64)> {
+        let last_sent = prev_log_index + entries.len() as u64;
+
+        // Entries up to the snapshot are committed, so they match already
+        let (prev_log_index, prev_log_term, entries) = match prev_log_index < self.snapshot.index {
+            true => {
+                let entries = entries.into_iter().filter(|entry| entry.index > self.snapshot.index).collect();
+                (self.snapshot.index, self.snapshot.term, entries)
+            },
+            false => (prev_log_index, prev_log_term, entries),
+        };
+
+        match self.term_at(prev_log_index) {
+            Some(term) if term == prev_log_term => {},
+            Some(term) => {
+                // Skip back over the rest of the conflicting term, as none
+                // of its entries will match either
+                let first = self.log
+                    .iter()
+                    .find(|entry| entry.term == term)
+                    .map_or(prev_log_index, |entry| entry.index);
+                return Ok((false, (first - 1).max(self.commit_index)));
+            },
+            None => return Ok((false, self.last_index())),
+        }
+
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    debug_assert!(entry.index > self.commit_index, "a committed entry conflicts");
+                    self.log.truncate((entry.index - self.snapshot.index - 1) as usize);
+                    self.storage.rewrite_log(self.snapshot.index, &self.log)?;
+                },
+                None => {},
+            }
+            new_entries.push(entry);
+        }
+
+        if !new_entries.is_empty() {
+            self.storage.append(&new_entries)?;
+            self.log.extend(new_entries);
+        }
+
+        let match_index = last_sent.max(self.snapshot.index);
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(match_index);
+            self.apply()?;
+        }
+        Ok((true, match_index))
+    }
+
+    /// Applies the committed entries that haven't been yet to the store,
+    /// then takes a snapshot if enough have been since the last one
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let position = (self.last_applied - self.snapshot.index) as usize;
+            match &self.log[position].command {
+                Command::Noop => {},
+                Command::Put { key, value } => self.store.insert(key, value)?,
+                Command::Delete { key } => self.store.delete(key)?,
+            }
+            self.last_applied += 1;
+        }
+
+        match self.last_applied - self.snapshot.index >= self.config.snapshot_threshold.max(1) {
+            true => self.take_snapshot(),
+            false => Ok(()),
+        }
+    }
+
+    /// Copies the store's live records into a new snapshot, then drops the
+    /// entries it covers from the log and compacts the store
+    fn take_snapshot(&mut self) -> Result<()> {
+        let index = self.last_applied;
+        let term = self.term_at(index).expect("applied entries are in the log");
+        self.store.flush()?;
+        let data = self.store.live_records()?;
+
+        let snapshot = RaftSnapshot { index, term, data };
+        self.storage.save_snapshot(&snapshot)?;
+        self.log.drain(..(index - self.snapshot.index) as usize);
+        self.snapshot = snapshot;
+        self.storage.rewrite_log(self.snapshot.index, &self.log)?;
+
+        self.store.compact()
+    }
+
+    /// Replaces the store with a leader's snapshot. Entries after it are
+    /// kept if the log agrees with the snapshot, and dropped if not.
+    fn install_snapshot(&mut self, index: u64, term: u64, data: ByteString) -> Result<()> {
+        if index <= self.commit_index {
+            return Ok(());
+        }
+
+        match self.term_at(index) == Some(term) {
+            true => { self.log.drain(..(index - self.snapshot.index) as usize); },
+            false => self.log.clear(),
+        }
+
+        self.snapshot = RaftSnapshot { index, term, data };
+        self.storage.save_snapshot(&self.snapshot)?;
+        self.storage.rewrite_log(self.snapshot.index, &self.log)?;
+
+        self.store.restore_records(&self.snapshot.data)?;
+        self.commit_index = index;
+        self.last_applied = index;
+        Ok(())
+    }
+}
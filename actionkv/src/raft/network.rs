@@ -0,0 +1,203 @@
+//! An in-process network for testing a cluster of `RaftNode`s.
+//!
+//! Every node runs in the same thread, and time only passes when the
+//! network is ticked. Each message takes between one and three ticks to
+//! arrive, drawn from a random number generator with a fixed seed, so a run
+//! with the same seed and the same faults plays out the same way every
+//! time. Links between nodes can be cut to partition the cluster, and nodes
+//! can be crashed and restarted from what they saved to disk.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+use super::{Command, Envelope, NodeId, RaftConfig, RaftNode, Role};
+
+/// The most ticks a message can take to arrive
+const MAX_DELAY: u64 = 3;
+
+#[derive(Debug)]
+pub struct Network {
+    root: PathBuf,
+    config: RaftConfig,
+    ids: Vec<NodeId>,
+    /// Every node, or `None` for one that has crashed
+    nodes: BTreeMap<NodeId, Option<RaftNode>>,
+    /// Messages on their way, along with the tick they arrive on, in the
+    /// order they were sent
+    in_flight: Vec<(u64, Envelope)>,
+    /// The links that messages can't cross, both ways round
+    cut: HashSet<(NodeId, NodeId)>,
+    now: u64,
+    rng: u64,
+}
+
+impl Network {
+    /// Starts a cluster of `size` nodes, numbered from 1, each kept in its
+    /// own directory under `root`
+    pub fn new(root: &Path, size: u64, config: RaftConfig) -> Result<Self> {
+        let ids: Vec<NodeId> = (1..=size).collect();
+
+        let mut nodes = BTreeMap::new();
+        for id in &ids {
+            let node = RaftNode::open(*id, &ids, &node_dir(root, *id), config.clone())?;
+            nodes.insert(*id, Some(node));
+        }
+
+        Ok(Network {
+            root: root.to_path_buf(),
+            rng: config.seed | 1,
+            config,
+            ids,
+            nodes,
+            in_flight: Vec::new(),
+            cut: HashSet::new(),
+            now: 0,
+        })
+    }
+
+    /// How many ticks have passed
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// The node `id`, unless it has crashed
+    pub fn node(&self, id: NodeId) -> Option<&RaftNode> {
+        self.nodes.get(&id)?.as_ref()
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut RaftNode> {
+        self.nodes.get_mut(&id)?.as_mut()
+    }
+
+    /// The nodes that haven't crashed
+    pub fn nodes(&self) -> impl Iterator<Item = &RaftNode> {
+        self.nodes.values().flatten()
+    }
+
+    /// The leader with the newest term. A leader cut off from the rest of
+    /// the cluster carries on believing it leads, but any leader elected
+    /// since has a newer term.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes()
+            .filter(|node| node.role() == Role::Leader)
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Proposes a command to the current leader, returning its index
+    pub fn propose(&mut self, command: Command) -> Result<u64> {
+        let leader = self.leader().ok_or(Error::NotLeader { leader: None })?;
+        self.node_mut(leader).expect("the leader is running").propose(command)
+    }
+
+    /// Delivers the messages due this tick, then ticks every node
+    pub fn tick(&mut self) -> Result<()> {
+        self.now += 1;
+
+        let (due, later) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|(at, _)| *at <= self.now);
+        self.in_flight = later;
+
+        for (_, envelope) in due {
+            if self.is_cut(envelope.from, envelope.to) {
+                continue;
+            }
+            if let Some(Some(node)) = self.nodes.get_mut(&envelope.to) {
+                node.step(envelope.from, envelope.message)?;
+            }
+        }
+
+        for node in self.nodes.values_mut().flatten() {
+            node.tick()?;
+        }
+
+        self.collect();
+        Ok(())
+    }
+
+    pub fn run(&mut self, ticks: u64) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Ticks until `done` holds, or `max_ticks` have passed. Returns whether
+    /// `done` held.
+    pub fn run_until<F: FnMut(&Network) -> bool>(&mut self, max_ticks: u64, mut done: F) -> Result<bool> {
+        for _ in 0..max_ticks {
+            if done(self) {
+                return Ok(true);
+            }
+            self.tick()?;
+        }
+        Ok(done(self))
+    }
+
+    /// Splits the cluster so that nodes can only reach the others in their
+    /// own group. Nodes not in any group can't reach anyone.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        let group_of = |id: &NodeId| groups.iter().position(|group| group.contains(id));
+
+        self.cut.clear();
+        for a in &self.ids {
+            for b in &self.ids {
+                if a != b && (group_of(a).is_none() || group_of(a) != group_of(b)) {
+                    self.cut.insert((*a, *b));
+                }
+            }
+        }
+    }
+
+    /// Joins the cluster back up after a partition
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// Stops the node `id` as if its machine had failed. Messages on their
+    /// way to it are lost, but what it saved to disk survives.
+    pub fn crash(&mut self, id: NodeId) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            *node = None;
+        }
+    }
+
+    /// Starts a crashed node again from what it saved to disk
+    pub fn restart(&mut self, id: NodeId) -> Result<()> {
+        let node = RaftNode::open(id, &self.ids, &node_dir(&self.root, id), self.config.clone())?;
+        self.nodes.insert(id, Some(node));
+        Ok(())
+    }
+
+    fn is_cut(&self, from: NodeId, to: NodeId) -> bool {
+        self.cut.contains(&(from, to))
+    }
+
+    /// Sends off the messages every node has waiting
+    fn collect(&mut self) {
+        let mut sent = Vec::new();
+        for node in self.nodes.values_mut().flatten() {
+            sent.extend(node.take_messages());
+        }
+
+        for envelope in sent {
+            if self.is_cut(envelope.from, envelope.to) {
+                continue;
+            }
+
+            // xorshift64
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+
+            let delay = 1 + self.rng % MAX_DELAY;
+            self.in_flight.push((self.now + delay, envelope));
+        }
+    }
+}
+
+fn node_dir(root: &Path, id: NodeId) -> PathBuf {
+    root.join(format!("node-{}", id))
+}
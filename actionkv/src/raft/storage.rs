@@ -0,0 +1,294 @@
+//! What a Raft node must not forget across a crash: its current term and
+//! vote, its log, and its latest snapshot. Each is kept in its own file in
+//! the node's directory, made up of little-endian numbers:
+//!
+//! ```text
+//! raft.state     term:u64 voted_for:u64 crc:u32
+//! raft.snapshot  index:u64 term:u64 len:u64 records crc:u32
+//! raft.log       "AKVL" base:u64 entry...
+//! entry          len:u32 crc:u32 term:u64 op:u8 [key_len:u32 key [value]]
+//! ```
+//!
+//! `voted_for` is 0 if the node hasn't voted this term. The log's first
+//! entry is at index `base + 1`, and the one before it is the last entry
+//! the snapshot covers. An entry's `len` is the length of everything after
+//! its `crc`, which covers the same bytes. `op` is 0 for a no-op, which has
+//! no key, 1 for a put and 2 for a delete, which has no value.
+//!
+//! The state and snapshot are replaced whole, by renaming a new file into
+//! place. The log is appended to, and rewritten whenever its start or end
+//! has to be cut off.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use super::{Command, LogEntry, NodeId, RaftSnapshot};
+
+type ByteString = Vec<u8>;
+
+const LOG_MAGIC: &[u8; 4] = b"AKVL";
+const LOG_HEADER_LEN: u64 = 12;
+
+const OP_NOOP: u8 = 0;
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// Everything a node had saved when it was last running
+#[derive(Debug)]
+pub(crate) struct Saved {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub snapshot: RaftSnapshot,
+    /// The entries after the snapshot
+    pub log: Vec<LogEntry>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: File,
+}
+
+impl Storage {
+    /// Opens the files in `dir`, creating any that aren't there yet, and
+    /// reads back what was saved in them
+    pub fn open(dir: &Path) -> io::Result<(Storage, Saved)> {
+        let (term, voted_for) = read_state(&dir.join("raft.state"))?;
+        let snapshot = read_snapshot(&dir.join("raft.snapshot"))?;
+
+        let log_path = dir.join("raft.log");
+        let mut log = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+
+        let (base, mut entries, good_len) = read_log(&mut log)?;
+
+        // A torn write at the end was never acknowledged, so it can go
+        if good_len < log.metadata()?.len() {
+            log.set_len(good_len)?;
+            log.sync_all()?;
+        }
+
+        // The snapshot is saved before the log is cut back to it, so the
+        // log may still hold entries the snapshot covers
+        let mut storage = Storage { dir: dir.to_path_buf(), log };
+        if base != snapshot.index || good_len == 0 {
+            let matches = base < snapshot.index
+                && entries.iter().any(|e| e.index == snapshot.index && e.term == snapshot.term);
+            entries = match matches {
+                true => entries.into_iter().filter(|e| e.index > snapshot.index).collect(),
+                false => Vec::new(),
+            };
+            storage.rewrite_log(snapshot.index, &entries)?;
+        }
+
+        let saved = Saved { term, voted_for, snapshot, log: entries };
+        Ok((storage, saved))
+    }
+
+    /// Saves the current term and vote
+    pub fn save_state(&self, term: u64, voted_for: Option<NodeId>) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(20);
+        buf.write_u64::<LittleEndian>(term)?;
+        buf.write_u64::<LittleEndian>(voted_for.unwrap_or(0))?;
+        buf.write_u32::<LittleEndian>(crc32::checksum_ieee(&buf))?;
+
+        replace(&self.dir.join("raft.state"), &buf)
+    }
+
+    /// Saves a snapshot in place of the last one
+    pub fn save_snapshot(&self, snapshot: &RaftSnapshot) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(28 + snapshot.data.len());
+        buf.write_u64::<LittleEndian>(snapshot.index)?;
+        buf.write_u64::<LittleEndian>(snapshot.term)?;
+        buf.write_u64::<LittleEndian>(snapshot.data.len() as u64)?;
+        buf.extend_from_slice(&snapshot.data);
+        buf.write_u32::<LittleEndian>(crc32::checksum_ieee(&buf))?;
+
+        replace(&self.dir.join("raft.snapshot"), &buf)
+    }
+
+    /// Adds entries to the end of the log
+    pub fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut f = BufWriter::new(&mut self.log);
+        for entry in entries {
+            write_entry(&mut f, entry)?;
+        }
+        f.flush()?;
+        drop(f);
+
+        self.log.sync_data()
+    }
+
+    /// Replaces the whole log with `entries`, which start just after `base`
+    pub fn rewrite_log(&mut self, base: u64, entries: &[LogEntry]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(LOG_MAGIC);
+        buf.write_u64::<LittleEndian>(base)?;
+        for entry in entries {
+            write_entry(&mut buf, entry)?;
+        }
+
+        let path = self.dir.join("raft.log");
+        replace(&path, &buf)?;
+        self.log = OpenOptions::new().read(true).append(true).open(&path)?;
+        Ok(())
+    }
+}
+
+/// Writes `buf` to a temporary file and renames it over `path`, so that a
+/// crash leaves either the old contents or the new
+fn replace(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    tmp.write_all(buf)?;
+    tmp.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+    crate::sync_parent_dir(path)
+}
+
+/// Reads a whole file, less its trailing CRC32, or nothing if it isn't
+/// there. Files are only renamed into place once they are on disk, so one
+/// whose checksum doesn't match has been damaged since.
+fn read_checked(path: &Path) -> io::Result<Option<ByteString>> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    if buf.len() < 4 {
+        return Err(corrupt(path));
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32::checksum_ieee(body) != LittleEndian::read_u32(crc) {
+        return Err(corrupt(path));
+    }
+
+    buf.truncate(buf.len() - 4);
+    Ok(Some(buf))
+}
+
+fn read_state(path: &Path) -> io::Result<(u64, Option<NodeId>)> {
+    let buf = match read_checked(path)? {
+        Some(buf) => buf,
+        None => return Ok((0, None)),
+    };
+
+    let mut fields = buf.as_slice();
+    let term = fields.read_u64::<LittleEndian>()?;
+    let voted_for = fields.read_u64::<LittleEndian>()?;
+    Ok((term, (voted_for != 0).then_some(voted_for)))
+}
+
+fn read_snapshot(path: &Path) -> io::Result<RaftSnapshot> {
+    let buf = match read_checked(path)? {
+        Some(buf) => buf,
+        None => return Ok(RaftSnapshot::default()),
+    };
+
+    let mut fields = buf.as_slice();
+    let index = fields.read_u64::<LittleEndian>()?;
+    let term = fields.read_u64::<LittleEndian>()?;
+    let len = fields.read_u64::<LittleEndian>()?;
+    if fields.len() as u64 != len {
+        return Err(corrupt(path));
+    }
+
+    Ok(RaftSnapshot { index, term, data: fields.to_vec() })
+}
+
+/// Reads the log, returning the index before its first entry, the entries,
+/// and how much of the file holds whole entries
+fn read_log(f: &mut File) -> io::Result<(u64, Vec<LogEntry>, u64)> {
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+
+    if buf.len() < LOG_HEADER_LEN as usize || &buf[..4] != LOG_MAGIC {
+        return Ok((0, Vec::new(), 0));
+    }
+    let base = LittleEndian::read_u64(&buf[4..12]);
+
+    let mut entries = Vec::new();
+    let mut rest = &buf[LOG_HEADER_LEN as usize..];
+    while let Some((entry, after)) = read_entry(rest, base + entries.len() as u64 + 1) {
+        entries.push(entry);
+        rest = after;
+    }
+
+    Ok((base, entries, (buf.len() - rest.len()) as u64))
+}
+
+fn write_entry<W: Write>(f: &mut W, entry: &LogEntry) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.write_u64::<LittleEndian>(entry.term)?;
+    match &entry.command {
+        Command::Noop => body.write_u8(OP_NOOP)?,
+        Command::Put { key, value } => {
+            body.write_u8(OP_PUT)?;
+            body.write_u32::<LittleEndian>(key.len() as u32)?;
+            body.extend_from_slice(key);
+            body.extend_from_slice(value);
+        },
+        Command::Delete { key } => {
+            body.write_u8(OP_DELETE)?;
+            body.write_u32::<LittleEndian>(key.len() as u32)?;
+            body.extend_from_slice(key);
+        },
+    }
+
+    f.write_u32::<LittleEndian>(body.len() as u32)?;
+    f.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+    f.write_all(&body)
+}
+
+/// Reads the entry at the start of `buf`, which is at `index` in the log.
+/// Returns `None` if `buf` doesn't start with a whole, intact entry.
+fn read_entry(buf: &[u8], index: u64) -> Option<(LogEntry, &[u8])> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let len = LittleEndian::read_u32(buf) as usize;
+    let crc = LittleEndian::read_u32(&buf[4..]);
+    let body = buf.get(8..8 + len)?;
+    if crc32::checksum_ieee(body) != crc || body.len() < 9 {
+        return None;
+    }
+
+    let term = LittleEndian::read_u64(body);
+    let mut fields = &body[9..];
+    let command = match body[8] {
+        OP_NOOP => Command::Noop,
+        op @ (OP_PUT | OP_DELETE) => {
+            let key_len = fields.read_u32::<LittleEndian>().ok()? as usize;
+            let key = fields.get(..key_len)?.to_vec();
+            match op {
+                OP_PUT => Command::Put { key, value: fields[key_len..].to_vec() },
+                _ => Command::Delete { key },
+            }
+        },
+        _ => return None,
+    };
+
+    Some((LogEntry { term, index, command }, &buf[8 + len..]))
+}
+
+fn corrupt(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupt", path.display()))
+}
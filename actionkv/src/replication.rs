@@ -0,0 +1,454 @@
+//! Keeping a standby copy of a store up to date by shipping its log.
+//!
+//! A leader serves its log over TCP with `serve_log`. A `Follower` connects
+//! to it, says which entry it applied last, and is sent every entry after
+//! that one, byte for byte, followed by each new entry as it is written. The
+//! follower appends the entries to its own store, so both stores hold the
+//! same entries, though a directory store may split them into segments
+//! differently.
+//!
+//! Positions are locations in the leader's log, along with the sequence
+//! number of the entry there. Compacting the leader moves its records and
+//! throws away the ones it no longer needs, so a follower is told to start
+//! again from an empty store if its last entry isn't where it was any more,
+//! or if a compaction has since thrown away entries written after it.
+//!
+//! Messages are made up of little-endian numbers:
+//!
+//! ```text
+//! hello      follower  "AKVR" version:u8 has_position:u8 segment:u32 offset:u64 checksum:u32 sequence:u64
+//! entry      leader    1 segment:u32 offset:u64 sequence:u64 behind:u64 len:u32 entry
+//! heartbeat  leader    2
+//! reset      leader    3
+//! ```
+//!
+//! `behind` is how many bytes of the leader's log come after the entry.
+//! Heartbeats are only sent once the follower has been sent everything.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::{ActionKV, Error, Location, Result, SharedStore};
+
+type ByteString = Vec<u8>;
+
+const MAGIC: &[u8; 4] = b"AKVR";
+const VERSION: u8 = 2;
+
+const ENTRY: u8 = 1;
+const HEARTBEAT: u8 = 2;
+const RESET: u8 = 3;
+
+/// How often a leader lets a follower that has caught up know it is still
+/// there
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a leader looks for new entries once a follower has caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a follower waits to hear from its leader before it gives up on
+/// the connection and makes a new one
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a follower waits between attempts to connect
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// The last entry a follower applied: where it is in the leader's log, its
+/// checksum, which tells it apart from whatever is there after compaction,
+/// and its sequence number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    location: Location,
+    checksum: u32,
+    sequence: u64,
+}
+
+impl Position {
+    fn of(location: Location, entry: &[u8], sequence: u64) -> Self {
+        Position { location, checksum: LittleEndian::read_u32(entry), sequence }
+    }
+}
+
+/// Serves the log of `store` to followers until the listener fails, each
+/// on its own thread
+pub fn serve_log(listener: TcpListener, store: SharedStore) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+
+        // A follower that goes away connects again when it is ready
+        std::thread::spawn(move || ship(stream, &store));
+    }
+
+    Ok(())
+}
+
+/// What a leader sends a follower next
+enum Step {
+    Entry { location: Location, sequence: u64, entry: ByteString, behind: u64 },
+    Reset,
+    /// The follower has been sent everything
+    Idle,
+}
+
+/// Sends a follower every entry after the last one it applied, and then
+/// each new entry as it is written, until the connection is lost
+fn ship(stream: TcpStream, store: &SharedStore) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut last = read_hello(&mut reader)?;
+    let mut cursor = None;
+    let mut last_sent = Instant::now();
+
+    loop {
+        match store.with(|store| next_step(store, &mut last, &mut cursor))? {
+            Step::Entry { location, sequence, entry, behind } => {
+                writer.write_u8(ENTRY)?;
+                write_location(&mut writer, location)?;
+                writer.write_u64::<LittleEndian>(sequence)?;
+                writer.write_u64::<LittleEndian>(behind)?;
+                writer.write_u32::<LittleEndian>(entry.len() as u32)?;
+                writer.write_all(&entry)?;
+            },
+            Step::Reset => writer.write_u8(RESET)?,
+            Step::Idle => {
+                if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                    writer.write_u8(HEARTBEAT)?;
+                    writer.flush()?;
+                    last_sent = Instant::now();
+                }
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            },
+        }
+
+        // Entries that are ready together go out together
+        writer.flush()?;
+        last_sent = Instant::now();
+    }
+}
+
+/// Works out what to send a follower next, with the store locked. `cursor`
+/// is where the next entry starts, as of a generation of the store.
+fn next_step(store: &mut ActionKV, last: &mut Option<Position>, cursor: &mut Option<(u64, Location)>) -> Result<Step> {
+    let from = match *cursor {
+        Some((generation, from)) if generation == store.generation => from,
+        // Starting out, or the records have been moved since
+        _ => match store.ship_start(last.map(|p| (p.location, p.checksum, p.sequence)))? {
+            Some(from) => from,
+            None => {
+                *last = None;
+                *cursor = None;
+                return Ok(Step::Reset);
+            },
+        },
+    };
+
+    match store.next_entry(from)? {
+        Some((location, entry)) => {
+            let next = Location { segment: location.segment, offset: location.offset + entry.len() as u64 };
+            let sequence = crate::entry_sequence(&entry).unwrap_or(last.map_or(0, |p| p.sequence) + 1);
+            *last = Some(Position::of(location, &entry, sequence));
+            *cursor = Some((store.generation, next));
+            let behind = store.bytes_after(next)?;
+            Ok(Step::Entry { location, sequence, entry, behind })
+        },
+        None => {
+            *cursor = Some((store.generation, from));
+            Ok(Step::Idle)
+        },
+    }
+}
+
+/// How a follower is getting on with keeping up with its leader
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    /// Whether the follower is connected to its leader right now
+    pub connected: bool,
+    /// Where the last entry the follower applied is in the leader's log
+    pub position: Option<Location>,
+    /// How many bytes of the leader's log the follower still had to be sent
+    /// when it last heard from the leader
+    pub behind_bytes: u64,
+    /// When the follower last heard from the leader
+    pub last_contact: Option<SystemTime>,
+    /// How many entries the follower has applied since it started
+    pub applied: u64,
+}
+
+impl ReplicationStatus {
+    /// Whether the follower has applied everything the leader had, as of
+    /// when it last heard from it
+    pub fn caught_up(&self) -> bool {
+        self.connected && self.behind_bytes == 0
+    }
+}
+
+/// Keeps a store up to date with a leader serving its log with `serve_log`.
+///
+/// The follower remembers the last entry it applied in the store's own
+/// `FILE.replica` (or `DIR/store.replica`), saved once it has caught up
+/// with each run of entries, so it carries on from there after a restart.
+/// A follower that stops before saving is sent those entries again, which
+/// leaves every key with the same value. Nothing else should write to the
+/// store while it is following.
+///
+/// A follower with no saved position starts from an empty store. It won't
+/// empty one that already holds records unless it was made `resetting`, in
+/// case it was pointed at the wrong store. Once it has emptied a store it
+/// leaves an empty `FILE.replica` behind, which marks the store as its own
+/// from then on.
+#[derive(Debug)]
+pub struct Follower {
+    store: SharedStore,
+    leader: String,
+    position_path: PathBuf,
+    reset: bool,
+    status: Mutex<ReplicationStatus>,
+    stopped: AtomicBool,
+}
+
+impl Follower {
+    /// A follower for `store`, which has been loaded, of the leader serving
+    /// its log at `leader`, e.g. `127.0.0.1:7379`
+    pub fn new(store: SharedStore, leader: &str) -> Self {
+        let position_path = store.with(|store| store.store_file_path("replica"));
+        Follower {
+            store,
+            leader: leader.to_string(),
+            position_path,
+            reset: false,
+            status: Mutex::new(ReplicationStatus::default()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Lets the follower empty a store that holds records it didn't get
+    /// from the leader, rather than refusing to start with
+    /// `Error::NotAReplica`
+    pub fn resetting(mut self) -> Self {
+        self.reset = true;
+        self
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        self.lock_status().clone()
+    }
+
+    /// Follows the leader until `stop` is called, connecting again whenever
+    /// the connection is lost or can't be made. Returns early with any error
+    /// that trying again wouldn't fix, such as an entry that can't be read.
+    pub fn run(&self) -> Result<()> {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let result = self.follow();
+            self.lock_status().connected = false;
+
+            match result {
+                Ok(()) | Err(Error::Io(_)) => {},
+                Err(err) => return Err(err),
+            }
+
+            if !self.stopped.load(Ordering::SeqCst) {
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `run` return once it has finished with the entries it has
+    /// been sent, which may take as long as it takes to hear from the leader
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn lock_status(&self) -> std::sync::MutexGuard<'_, ReplicationStatus> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies the entries the leader sends over a single connection,
+    /// until it is lost or the follower is stopped
+    fn follow(&self) -> Result<()> {
+        let mut saved = read_position(&self.position_path)?;
+        if saved.is_none() {
+            let ours = self.reset || self.position_path.exists();
+            self.store.with(|store| match ours || store.end()? == store.start() {
+                true => store.clear(),
+                false => Err(Error::NotAReplica),
+            })?;
+            claim(&self.position_path)?;
+        }
+
+        let stream = TcpStream::connect(&self.leader)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        write_hello(&mut &stream, saved)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut position = saved;
+        {
+            let mut status = self.lock_status();
+            status.connected = true;
+            status.position = position.map(|p| p.location);
+        }
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            let kind = reader.read_u8()?;
+            let mut status = ReplicationStatus { last_contact: Some(SystemTime::now()), ..self.status() };
+
+            match kind {
+                ENTRY => {
+                    let location = read_location(&mut reader)?;
+                    let sequence = reader.read_u64::<LittleEndian>()?;
+                    let behind = reader.read_u64::<LittleEndian>()?;
+                    let len = reader.read_u32::<LittleEndian>()? as u64;
+
+                    let mut entry = ByteString::new();
+                    reader.by_ref().take(len).read_to_end(&mut entry)?;
+                    if (entry.len() as u64) < len {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+
+                    self.store.append_entry(&entry)?;
+                    position = Some(Position::of(location, &entry, sequence));
+                    status.position = Some(location);
+                    status.behind_bytes = behind;
+                    status.applied += 1;
+                },
+                HEARTBEAT => status.behind_bytes = 0,
+                RESET => {
+                    self.store.with(|store| store.clear())?;
+                    claim(&self.position_path)?;
+                    position = None;
+                    saved = None;
+                    status.position = None;
+                },
+                other => return Err(protocol_error(format!("unknown message {}", other)).into()),
+            }
+            *self.lock_status() = status;
+
+            // Once everything sent so far has been applied, it is made to
+            // last before the follower claims to have it
+            if reader.buffer().is_empty() && position != saved {
+                self.store.sync()?;
+                if let Some(position) = position {
+                    write_position(&self.position_path, position)?;
+                }
+                saved = position;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_hello<W: Write>(f: &mut W, position: Option<Position>) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(30);
+    buf.extend_from_slice(MAGIC);
+    buf.write_u8(VERSION)?;
+    buf.write_u8(position.is_some() as u8)?;
+
+    let position = position.unwrap_or(Position { location: Location::default(), checksum: 0, sequence: 0 });
+    write_location(&mut buf, position.location)?;
+    buf.write_u32::<LittleEndian>(position.checksum)?;
+    buf.write_u64::<LittleEndian>(position.sequence)?;
+
+    f.write_all(&buf)?;
+    f.flush()
+}
+
+fn read_hello<R: Read>(f: &mut R) -> io::Result<Option<Position>> {
+    let mut magic = [0u8; 4];
+    f.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(protocol_error("not an ActionKV follower".to_string()));
+    }
+
+    let version = f.read_u8()?;
+    if version != VERSION {
+        return Err(protocol_error(format!("replication version {} is not supported", version)));
+    }
+
+    let has_position = f.read_u8()? != 0;
+    let location = read_location(f)?;
+    let checksum = f.read_u32::<LittleEndian>()?;
+    let sequence = f.read_u64::<LittleEndian>()?;
+    Ok(has_position.then_some(Position { location, checksum, sequence }))
+}
+
+fn write_location<W: Write>(f: &mut W, location: Location) -> io::Result<()> {
+    f.write_u32::<LittleEndian>(location.segment)?;
+    f.write_u64::<LittleEndian>(location.offset)
+}
+
+fn read_location<R: Read>(f: &mut R) -> io::Result<Location> {
+    let segment = f.read_u32::<LittleEndian>()?;
+    let offset = f.read_u64::<LittleEndian>()?;
+    Ok(Location { segment, offset })
+}
+
+/// Saves a follower's position, along with a CRC32 of it, by writing it to
+/// a temporary file and renaming that into place
+fn write_position(path: &Path, position: Position) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(28);
+    write_location(&mut buf, position.location)?;
+    buf.write_u32::<LittleEndian>(position.checksum)?;
+    buf.write_u64::<LittleEndian>(position.sequence)?;
+    buf.write_u32::<LittleEndian>(crc32::checksum_ieee(&buf))?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    tmp.write_all(&buf)?;
+    tmp.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Reads a follower's saved position. A missing, empty or damaged file
+/// means it has to start again from the beginning.
+fn read_position(path: &Path) -> io::Result<Option<Position>> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    if buf.len() != 28 || crc32::checksum_ieee(&buf[..24]) != LittleEndian::read_u32(&buf[24..]) {
+        return Ok(None);
+    }
+
+    let mut fields = buf.as_slice();
+    let location = read_location(&mut fields)?;
+    let checksum = fields.read_u32::<LittleEndian>()?;
+    let sequence = fields.read_u64::<LittleEndian>()?;
+    Ok(Some(Position { location, checksum, sequence }))
+}
+
+/// Leaves an empty position file, which marks the store as a follower's
+/// copy that it is free to empty, but gives no position to carry on from
+fn claim(path: &Path) -> io::Result<()> {
+    File::create(path)?.sync_all()?;
+    crate::sync_parent_dir(path)
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
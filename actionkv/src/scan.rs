@@ -0,0 +1,58 @@
+//! Iterating over the keys in a range, in order
+
+use std::ops::Bound;
+
+use crate::{ActionKV, Location, Result};
+
+type ByteString = Vec<u8>;
+
+/// The keys within a range and their values, in key order. Created by
+/// `ActionKV::scan` and `ActionKV::scan_prefix`.
+///
+/// Values are only read from disk as the iterator reaches them. With an
+/// ordered index the keys are found one at a time too, whereas a hashed
+/// index has to gather and sort the matching keys up front.
+pub struct Scan<'a> {
+    store: &'a mut ActionKV,
+    lower: Bound<ByteString>,
+    upper: Bound<ByteString>,
+    /// The matching keys, when they had to be sorted up front
+    sorted: Option<std::vec::IntoIter<(ByteString, Location)>>,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(
+        store: &'a mut ActionKV,
+        lower: Bound<ByteString>,
+        upper: Bound<ByteString>,
+    ) -> Self {
+        let sorted = match store.index.is_ordered() {
+            true => None,
+            false => Some(store.index.sorted_in(&lower, &upper).into_iter()),
+        };
+
+        Scan { store, lower, upper, sorted }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(ByteString, ByteString)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, location) = match &mut self.sorted {
+                Some(keys) => keys.next()?,
+                None => {
+                    let (key, location) = self.store.index.first_in(&self.lower, &self.upper)?;
+                    self.lower = Bound::Excluded(key.clone());
+                    (key, location)
+                },
+            };
+
+            // Keys that have expired since the index was loaded are skipped
+            if !self.store.is_expired(&key) {
+                return Some(self.store.get_at(location).map(|kv| (key, kv.value)));
+            }
+        }
+    }
+}
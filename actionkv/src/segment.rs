@@ -0,0 +1,106 @@
+//! Segments are the files a store's records are written to.
+//!
+//! A store kept in a single file has exactly one segment: the file itself.
+//! A store kept in a directory is made up of numbered segment files
+//! (`00000000.akv`, `00000001.akv`, ...). Records are only ever appended to
+//! the highest numbered one, the active segment. Once that reaches the
+//! configured size it is sealed, never to be written to again, and a new
+//! active segment is started after it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use crate::compression::Compression;
+use crate::header::{self, FileHeader};
+use crate::Result;
+
+const EXTENSION: &str = "akv";
+
+#[derive(Debug)]
+pub struct Segment {
+    pub id: u32,
+    pub path: PathBuf,
+    pub f: File,
+    pub header: FileHeader,
+}
+
+impl Segment {
+    /// Opens (or creates) a segment file in append mode. A new file is
+    /// started with `new_header`, and an existing one has its header checked.
+    /// A file that stopped part way through its header never got as far as
+    /// holding records, so it is started again as if it were new.
+    pub fn open(id: u32, path: PathBuf, new_header: FileHeader) -> Result<Segment> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&path)?;
+
+        let header = match f.metadata()?.len() {
+            0 => {
+                new_header.write(&mut f)?;
+                new_header
+            },
+            _ if header::is_torn(&mut f)? => {
+                f.set_len(0)?;
+                new_header.write(&mut f)?;
+                f.sync_all()?;
+                new_header
+            },
+            _ => FileHeader::read(&mut f, id)?,
+        };
+
+        Ok(Segment { id, path, f, header })
+    }
+
+    /// Opens an existing segment file for reading only. An empty file, or
+    /// one whose header was cut short, is read as an empty segment, as there
+    /// is no header to check.
+    pub fn open_read_only(id: u32, path: PathBuf) -> Result<Segment> {
+        let mut f = File::open(&path)?;
+
+        let header = match f.metadata()?.len() {
+            0 => FileHeader::current(Compression::None),
+            _ if header::is_torn(&mut f)? => FileHeader::current(Compression::None),
+            _ => FileHeader::read(&mut f, id)?,
+        };
+
+        Ok(Segment { id, path, f, header })
+    }
+
+    /// How many bytes have been written to the segment
+    pub fn size(&mut self) -> io::Result<u64> {
+        self.f.seek(SeekFrom::End(0))
+    }
+}
+
+/// The path of a numbered segment file inside a store directory
+pub fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, EXTENSION))
+}
+
+/// Lists the ids of the segment files in a store directory, oldest first.
+/// Anything that isn't named like a segment file is ignored.
+pub fn list(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+
+        let id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u32>().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+
+    ids.sort_unstable();
+    Ok(ids)
+}
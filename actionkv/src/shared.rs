@@ -0,0 +1,210 @@
+//! A store that can be written to from several threads at once
+
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::backup;
+use crate::sync::{SyncPolicy, SyncState};
+use crate::{ActionKV, Result, Snapshot, Stats, WriteBatch};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// A handle to a store shared between threads. Cloning the handle is cheap,
+/// and every clone refers to the same store.
+///
+/// Writes follow the store's sync policy, but with group commit: writers
+/// that are due a sync at the same time share a single `fsync`. While one
+/// writer is syncing, the others queue up behind it, and the next sync
+/// covers all of their writes at once.
+#[derive(Debug, Clone)]
+pub struct SharedStore {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    inner: Mutex<Inner>,
+    commit: Mutex<Commit>,
+    /// Signalled whenever a sync finishes
+    synced: Condvar,
+}
+
+#[derive(Debug)]
+struct Inner {
+    store: ActionKV,
+    sync_state: SyncState,
+    /// How many writes have been made through the handle
+    written: u64,
+}
+
+#[derive(Debug, Default)]
+struct Commit {
+    /// How many of the writes are known to be on disk
+    synced: u64,
+    /// Whether some writer is syncing right now
+    syncing: bool,
+}
+
+impl SharedStore {
+    /// Shares a store that has already been loaded. The store's sync policy
+    /// is taken over by the handle, which syncs for all of its writers.
+    pub fn new(mut store: ActionKV) -> Self {
+        let policy = std::mem::replace(&mut store.options.sync, SyncPolicy::Never);
+        store.sync_state = SyncState::new(SyncPolicy::Never);
+
+        let inner = Inner { store, sync_state: SyncState::new(policy), written: 0 };
+        SharedStore {
+            shared: Arc::new(Shared {
+                inner: Mutex::new(inner),
+                commit: Mutex::new(Commit::default()),
+                synced: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.shared.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_commit(&self) -> MutexGuard<'_, Commit> {
+        self.shared.commit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.lock().store.get(key)
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.lock().store.contains_key(key)
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        self.lock().store.stats()
+    }
+
+    /// Takes a snapshot that can be read from without waiting on the lock
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.lock().store.snapshot()
+    }
+
+    /// Backs the store up to `path`, as `ActionKV::backup_to` does. The
+    /// store is only locked while the files to copy are worked out, so
+    /// writers aren't held up by the copying.
+    pub fn backup_to(&self, path: &Path) -> Result<()> {
+        let (sources, keys, directory) = {
+            let mut inner = self.lock();
+            (inner.store.backup_sources()?, inner.store.keys.clone(), inner.store.directory)
+        };
+        backup::copy(sources, &keys, directory, path)
+    }
+
+    /// Runs `f` with the store locked, e.g. to scan it. Writes made through
+    /// `f` don't count towards the sync policy.
+    pub fn with<T, F: FnOnce(&mut ActionKV) -> T>(&self, f: F) -> T {
+        f(&mut self.lock().store)
+    }
+
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write(|store| store.insert(key, value))
+    }
+
+    pub fn insert_with_ttl(&self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+        self.write(|store| store.insert_with_ttl(key, value, ttl))
+    }
+
+    /// Deletes the key, returning whether it was there. Only one of any
+    /// writers deleting the same key at once finds it.
+    pub fn delete(&self, key: &ByteStr) -> Result<bool> {
+        self.write(|store| match store.contains_key(key) {
+            true => store.delete(key).map(|()| true),
+            false => Ok(false),
+        })
+    }
+
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.write(|store| store.write_batch(batch))
+    }
+
+    /// Appends an entry shipped from a leader, as a follower does
+    pub(crate) fn append_entry(&self, raw: &ByteStr) -> Result<()> {
+        self.write(|store| store.append_entry(raw))
+    }
+
+    /// Flushes every write made through the handle so far to disk, sharing
+    /// a sync with any other writers that are waiting for one
+    pub fn sync(&self) -> Result<()> {
+        let written = self.lock().written;
+        self.wait_for_sync(written)
+    }
+
+    /// Makes a write, then waits for it to reach disk if the sync policy
+    /// says it is time to sync
+    fn write<T, F: FnOnce(&mut ActionKV) -> Result<T>>(&self, op: F) -> Result<T> {
+        let (result, due) = {
+            let mut inner = self.lock();
+            let result = op(&mut inner.store)?;
+            inner.written += 1;
+            (result, inner.sync_state.record_write().then_some(inner.written))
+        };
+
+        if let Some(written) = due {
+            self.wait_for_sync(written)?;
+        }
+        Ok(result)
+    }
+
+    /// Waits until at least the first `written` writes are on disk. If no
+    /// other writer is already syncing, this one does, on behalf of every
+    /// write made up to that point.
+    fn wait_for_sync(&self, written: u64) -> Result<()> {
+        let mut commit = self.lock_commit();
+
+        loop {
+            if commit.synced >= written {
+                return Ok(());
+            }
+
+            if commit.syncing {
+                commit = self.shared.synced.wait(commit).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            commit.syncing = true;
+            drop(commit);
+
+            let result = self.sync_now();
+
+            commit = self.lock_commit();
+            commit.syncing = false;
+            if let Ok(synced) = result {
+                commit.synced = commit.synced.max(synced);
+            }
+            self.shared.synced.notify_all();
+
+            // If the sync failed, the writers waiting on it try again
+            result?;
+        }
+    }
+
+    /// Syncs the store's files, returning how many writes that covers. The
+    /// store is only locked while the files are looked up, so other writers
+    /// can carry on while the sync is under way.
+    fn sync_now(&self) -> Result<u64> {
+        let (handles, written, unsynced) = {
+            let mut inner = self.lock();
+            inner.store.flush()?;
+            (inner.store.sync_handles()?, inner.written, inner.sync_state.unsynced())
+        };
+
+        for f in handles {
+            f.sync_data()?;
+        }
+
+        // Only once every file is on disk, as a failed sync leaves the
+        // writes due another
+        self.lock().sync_state.synced_some(unsynced);
+        Ok(written)
+    }
+}
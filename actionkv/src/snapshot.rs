@@ -0,0 +1,172 @@
+//! Read-only views of a store that other threads can read from while the
+//! store itself carries on taking writes
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{ActionKV, Clock, EncryptionKey, Error, KeyIndex, Location, Result, HEADER_LEN};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// The store as it was when `ActionKV::snapshot` was called. Cloning a
+/// snapshot is cheap, and the clones can be sent to other threads.
+///
+/// A snapshot holds its own handles to the segment files and remembers how
+/// long each one was, so it never reads anything written after it was
+/// taken, let alone a record that is only partly written. Reads go straight
+/// to a position in the file rather than through a shared cursor, so any
+/// number of threads can read at once while the store appends.
+///
+/// On Unix the handles keep the files they were opened on alive, so a
+/// snapshot still reads the old records after the store is compacted or
+/// emptied.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    index: KeyIndex,
+    expiries: HashMap<ByteString, SystemTime>,
+    clock: Arc<dyn Clock>,
+    keys: Vec<EncryptionKey>,
+    /// Each segment's id, file and length when the snapshot was taken
+    segments: Vec<(u32, File, u64)>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: &mut ActionKV) -> Result<Self> {
+        // Anything still buffered has to reach the file for the snapshot
+        // to be able to read it
+        store.flush()?;
+
+        let mut segments = Vec::with_capacity(store.segments.len());
+        for segment in &mut store.segments {
+            let len = segment.size()?;
+            segments.push((segment.id, segment.f.try_clone()?, len));
+        }
+
+        Ok(Snapshot {
+            inner: Arc::new(Inner {
+                index: store.index.clone(),
+                expiries: store.expiries.clone(),
+                clock: store.options.clock.clone(),
+                keys: store.keys.clone(),
+                segments,
+            }),
+        })
+    }
+
+    /// Gets the value `key` had when the snapshot was taken. Keys whose time
+    /// to live has run out since are treated as absent, as they are by the
+    /// store.
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        match self.inner.index.get(key) {
+            Some(location) if !self.is_expired(key) => Ok(Some(self.read_value(*location)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether `key` had a live value when the snapshot was taken
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.inner.index.contains_key(key) && !self.is_expired(key)
+    }
+
+    /// How many keys the snapshot holds, including any that have expired
+    /// since it was taken
+    pub fn len(&self) -> usize {
+        self.inner.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The live keys in the snapshot, in key order
+    pub fn keys(&self) -> Vec<ByteString> {
+        let mut keys: Vec<ByteString> = self.inner.index
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| !self.is_expired(key))
+            .cloned()
+            .collect();
+
+        // An ordered index already gives them in order
+        if !self.inner.index.is_ordered() {
+            keys.sort_unstable();
+        }
+        keys
+    }
+
+    fn is_expired(&self, key: &ByteStr) -> bool {
+        self.inner.expiries
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= self.inner.clock.now())
+    }
+
+    /// Reads the value of the record at `location` with positional reads
+    fn read_value(&self, location: Location) -> Result<ByteString> {
+        let Location { segment, offset } = location;
+        let (_, f, len) = self.inner.segments
+            .iter()
+            .find(|(id, _, _)| *id == segment)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("segment {} does not exist", segment),
+            ))?;
+
+        let truncated = Error::TruncatedRecord { segment, offset };
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        if offset + HEADER_LEN > *len {
+            return Err(truncated);
+        }
+        read_exact_at(f, &mut header, offset)?;
+
+        let record_len = crate::record_len(&header);
+        if offset + record_len > *len {
+            return Err(truncated);
+        }
+
+        let mut raw = vec![0u8; record_len as usize];
+        read_exact_at(f, &mut raw, offset)?;
+
+        match ActionKV::process_record(&mut raw.as_slice(), location, &self.inner.keys)? {
+            Some(kv) => Ok(kv.value),
+            None => Err(truncated),
+        }
+    }
+}
+
+/// Fills `buf` from `f` starting `offset` bytes in, without moving the
+/// file's cursor
+#[cfg(not(target_os = "windows"))]
+fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    f.read_exact_at(buf, offset)
+}
+
+/// Windows has no positional reads that leave the cursor alone, but every
+/// read says where it starts, so the shared cursor doesn't matter
+#[cfg(target_os = "windows")]
+fn read_exact_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match f.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Figures describing the state of a store
+
+use serde::Serialize;
+
+/// A summary of what a store holds, from `ActionKV::stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// How many keys have a live value
+    pub keys: usize,
+    /// How many segment files the store is made of
+    pub segments: usize,
+    /// The total size of the segment files, in bytes
+    pub disk_bytes: u64,
+    /// The total size of the live values, in bytes
+    pub value_bytes: u64,
+    /// How many bytes the live values take up as they are stored, after
+    /// any compression
+    pub stored_value_bytes: u64,
+    /// How many times smaller compression has made the live values, e.g.
+    /// 4.0 if they take up a quarter of the space. 1.0 when nothing is
+    /// compressed.
+    pub compression_ratio: f64,
+    /// How many records the segment files hold, counting each of the
+    /// records in a batch
+    pub records: u64,
+    /// How many of the records aren't a key's live value: values that have
+    /// since been overwritten, deleted or expired, and tombstones
+    pub dead_records: u64,
+    /// How many bytes the live records take up, headers and all
+    pub live_bytes: u64,
+    /// How many bytes are taken up by dead records, and anything else that
+    /// isn't overhead. Compaction would free them.
+    pub dead_bytes: u64,
+    /// How many bytes hold no keys or values, but can't be freed while the
+    /// records they go with are live: the segment file headers, the headers
+    /// of batch frames still holding a live record, and the marks that
+    /// compaction leaves to keep writes numbered
+    pub overhead_bytes: u64,
+    /// How much of the live and dead bytes are dead, from 0.0 to 1.0
+    pub fragmentation: f64,
+    /// The length of the longest live key, in bytes
+    pub largest_key: u64,
+    /// The length of the longest live value, before any compression
+    pub largest_value: u64,
+    /// How many live values there are of each size, before any compression,
+    /// from the smallest size up to the bucket the largest value is in
+    pub value_sizes: Vec<SizeBucket>,
+}
+
+/// The live values whose size is greater than the bucket before's
+/// `up_to`, and at most this one's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SizeBucket {
+    pub up_to: u64,
+    pub values: usize,
+}
+
+/// The upper bound of the smallest value size bucket. Each bucket after it
+/// is four times as big as the one before.
+const SMALLEST_BUCKET: u64 = 16;
+
+/// Sorts value sizes into buckets
+pub(crate) fn histogram<I: IntoIterator<Item = u64>>(sizes: I) -> Vec<SizeBucket> {
+    let mut buckets: Vec<SizeBucket> = Vec::new();
+
+    for size in sizes {
+        let mut i = 0;
+        while SMALLEST_BUCKET.saturating_mul(1 << (2 * i)) < size {
+            i += 1;
+        }
+
+        while buckets.len() <= i {
+            let up_to = SMALLEST_BUCKET.saturating_mul(1 << (2 * buckets.len()));
+            buckets.push(SizeBucket { up_to, values: 0 });
+        }
+        buckets[i].values += 1;
+    }
+
+    buckets
+}
@@ -0,0 +1,75 @@
+//! When writes are flushed all the way to disk
+
+use std::time::{Duration, Instant};
+
+/// How often a store calls `fsync` on its files as it is written to.
+///
+/// Until a write has been synced it can be lost if the machine loses power,
+/// even though it has reached the operating system. Syncing more often loses
+/// less on a crash, but every sync makes writes wait for the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never sync on its own, leaving it to the operating system or to
+    /// explicit calls to `sync`
+    #[default]
+    Never,
+    /// Sync after every write, so each write is on disk once it returns
+    EveryWrite,
+    /// Sync after every `n` writes
+    EveryN(u64),
+    /// Sync on the first write after at least this long has passed since
+    /// the last sync. There is no timer behind this: the last writes before
+    /// the store goes quiet stay unsynced until the next write, an explicit
+    /// `sync`, or `close`.
+    Interval(Duration),
+}
+
+/// Keeps track of the writes made since the last sync, to decide when the
+/// next one is due
+#[derive(Debug)]
+pub(crate) struct SyncState {
+    policy: SyncPolicy,
+    unsynced: u64,
+    last_sync: Instant,
+}
+
+impl SyncState {
+    pub fn new(policy: SyncPolicy) -> Self {
+        SyncState { policy, unsynced: 0, last_sync: Instant::now() }
+    }
+
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Notes that a write has been made, returning whether the policy says
+    /// it is time to sync
+    pub fn record_write(&mut self) -> bool {
+        self.unsynced += 1;
+
+        match self.policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        }
+    }
+
+    /// Notes that everything written so far has been synced
+    pub fn synced(&mut self) {
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+    }
+
+    /// Notes that the first `writes` of those made since the last sync have
+    /// been synced. Any made while the sync was under way still need one.
+    pub fn synced_some(&mut self, writes: u64) {
+        self.unsynced = self.unsynced.saturating_sub(writes);
+        self.last_sync = Instant::now();
+    }
+
+    /// How many writes have been made since the last sync
+    pub fn unsynced(&self) -> u64 {
+        self.unsynced
+    }
+}